@@ -2,11 +2,28 @@ if exists('b:current_syntax')
   finish
 endif
 
-syn keyword px2Keywords dup drop over swap rot println skipwhite
+syn keyword px2Keywords dup drop over swap rot println eprintln flush var array as module pub struct enum comptime when else extern data end shl shr sizeof alloc free realloc length concat substring compare eq format random sleep abs min max pow divmod getenv exec syscall0 syscall1 syscall2 syscall3 syscall4 syscall5 syscall6 skipwhite
 syn keyword px2Booleans true false skipwhite
+syn keyword px2Types int uint big bool ptr str skipwhite
 
-syn match px2Number "\v<\d+>"
+syn match px2Number "\v[-+]?<\d+[un]?>"
+syn match px2Access "\v[@!&]\h(\w|::)*"
+syn match px2Directive "\v\?stack>"
+syn match px2Attribute "\v#\h(\w|-)*"
+syn match px2Conversions "\v<(int-\>ptr|ptr-\>int|bool-\>int|int-\>str|str-\>int|int-\>uint|uint-\>int|int-\>big|big-\>int|char-at|random-range|now-ms|clock-ns|tcp-(connect|listen|accept|read|write|close))"
+syn region px2String start=/"/ skip=/\\./ end=/"/ oneline
+syn region px2String start=/\<r"/ end=/"/ oneline
+syn region px2String start=/"""/ skip=/\\./ end=/"""/
+syn region px2String start=/\<r"""/ end=/"""/
+syn region px2Data matchgroup=px2Keywords start=/\v<data\s+\h\w*\s*$/ end=/\v^\s*end\s*$/
 
 hi def link px2Keywords   Keyword
 hi def link px2Booleans   Boolean
 hi def link px2Number     Number
+hi def link px2Types      Type
+hi def link px2Access     Identifier
+hi def link px2Conversions Keyword
+hi def link px2Directive  PreProc
+hi def link px2Attribute  PreProc
+hi def link px2String     String
+hi def link px2Data       String
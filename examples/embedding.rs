@@ -0,0 +1,63 @@
+// Runs a px2 file with a 'hash' word provided by the host.
+// cargo run --example embedding -- examples/embedding.px2
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use px2::compiler::{self, Options};
+use px2::log::Verbosity;
+use px2::vm::{DataType, Permissions, VM};
+
+fn main() {
+    let file_path = match std::env::args().nth(1) {
+        Some(file_path) => file_path,
+        None => {
+            println!("Usage: embedding <file_path>");
+            return;
+        }
+    };
+
+    let mut vm = VM::new();
+    vm.register_builtin("hash", &[DataType::Str], &[DataType::Int], |call| {
+        let mut hasher = DefaultHasher::new();
+        call.str(0)?.hash(&mut hasher);
+        // keep it positive so it prints nicely
+        call.push_int((hasher.finish() >> 1) as i64);
+        Ok(())
+    });
+    vm.register_builtin("shout", &[DataType::Str, DataType::Int], &[DataType::Str], |call| {
+        let times = call.int(1)?;
+        if times < 0 {
+            return Err(format!("cannot shout {} times", times));
+        }
+        let shouted = call.str(0)?.to_uppercase().repeat(times as usize);
+        call.push_str(shouted);
+        Ok(())
+    });
+
+    let options = Options {
+        verbosity: Verbosity::Quiet,
+        heap_size: px2::DEFAULT_HEAP_SIZE,
+        seed: None,
+        allow_unsafe: false,
+        max_ops: None,
+        timeout: None,
+        permissions: Permissions::default(),
+        include_paths: Vec::new(),
+        buffer_output: true,
+        fuse_ops: true,
+        fold_program: false,
+        jobs: 1,
+        coverage: false,
+        lcov: None,
+        stats: false,
+        record: None,
+        replay: None,
+        snapshot: None,
+        resume: None,
+        dump_ir: None,
+        stop_after: None,
+        defines: Vec::new(),
+    };
+    std::process::exit(compiler::compile_with(&file_path, &options, vm));
+}
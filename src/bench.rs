@@ -0,0 +1,127 @@
+use std::fs;
+use std::time::Duration;
+
+use crate::compiler::{self, Options};
+
+pub struct Settings {
+    pub runs: usize,
+    // runs before the timed ones, so caches and the allocator have settled
+    pub warmup: usize,
+    // a summary saved by an earlier bench to compare against
+    pub baseline: Option<String>,
+    // where to save this bench's summary, to use as a baseline later
+    pub save: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            runs: 10,
+            warmup: 2,
+            baseline: None,
+            save: None,
+        }
+    }
+}
+
+// Execution times of the timed runs, not including compilation.
+pub struct Summary {
+    pub runs: usize,
+    pub min: Duration,
+    pub median: Duration,
+    pub mean: Duration,
+    pub stddev: Duration,
+}
+
+impl Summary {
+    pub fn from_times(times: &[Duration]) -> Self {
+        let mut sorted = times.to_vec();
+        sorted.sort();
+        let middle = sorted.len() / 2;
+        let median = if sorted.len().is_multiple_of(2) {
+            (sorted[middle - 1] + sorted[middle]) / 2
+        } else {
+            sorted[middle]
+        };
+
+        let mean = sorted.iter().map(Duration::as_secs_f64).sum::<f64>() / sorted.len() as f64;
+        let variance = sorted.iter().map(|time| (time.as_secs_f64() - mean).powi(2)).sum::<f64>() / sorted.len() as f64;
+
+        Summary {
+            runs: sorted.len(),
+            min: sorted[0],
+            median,
+            mean: Duration::from_secs_f64(mean),
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+        }
+    }
+
+    // Saved as JSON with the times in nanoseconds, so other tools can read baselines too.
+    pub fn save(&self, file_path: &str, path: &str) -> Result<(), String> {
+        let escaped = file_path.replace('\\', "\\\\").replace('"', "\\\"");
+        let json = format!(
+            "{{\n  \"file\": \"{}\",\n  \"runs\": {},\n  \"min_ns\": {},\n  \"median_ns\": {},\n  \"mean_ns\": {},\n  \"stddev_ns\": {}\n}}\n",
+            escaped, self.runs, self.min.as_nanos(), self.median.as_nanos(), self.mean.as_nanos(), self.stddev.as_nanos(),
+        );
+        fs::write(path, json).map_err(|error| format!("cannot write baseline '{}': {}", path, error))
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let json = fs::read_to_string(path).map_err(|error| format!("cannot read baseline '{}': {}", path, error))?;
+        let field = |key: &str| json_number(&json, key).ok_or_else(|| format!("baseline '{}' has no number '{}'", path, key));
+        Ok(Summary {
+            runs: field("runs")? as usize,
+            min: Duration::from_nanos(field("min_ns")?),
+            median: Duration::from_nanos(field("median_ns")?),
+            mean: Duration::from_nanos(field("mean_ns")?),
+            stddev: Duration::from_nanos(field("stddev_ns")?),
+        })
+    }
+}
+
+// Benchmarks the program and prints a summary, returning whether it compiled and ran.
+pub fn run(file_path: &str, options: &Options, settings: &Settings) -> bool {
+    // read first so a bad path is reported before waiting for the runs
+    let baseline = match settings.baseline.as_deref().map(Summary::load).transpose() {
+        Ok(baseline) => baseline,
+        Err(message) => {
+            eprintln!("Error: {}", message);
+            return false;
+        }
+    };
+
+    let times = match compiler::bench(file_path, options, settings.warmup, settings.runs) {
+        Some(times) => times,
+        None => return false,
+    };
+    let summary = Summary::from_times(&times);
+
+    println!("Benchmarked {}: {} runs after {} warmup runs", file_path, settings.runs, settings.warmup);
+    println!("    min     {:?}", summary.min);
+    println!("    median  {:?}", summary.median);
+    println!("    mean    {:?}", summary.mean);
+    println!("    stddev  {:?}", summary.stddev);
+
+    if let Some(baseline) = baseline {
+        let change = (summary.median.as_secs_f64() / baseline.median.as_secs_f64() - 1.0) * 100.0;
+        let direction = if change > 0.0 { "slower" } else { "faster" };
+        println!("    median is {:.1}% {} than the baseline's {:?}", change.abs(), direction, baseline.median);
+    }
+
+    if let Some(path) = &settings.save {
+        if let Err(message) = summary.save(file_path, path) {
+            eprintln!("Error: {}", message);
+            return false;
+        }
+    }
+
+    true
+}
+
+// Reads the whole number after "key": in a flat JSON object.
+fn json_number(json: &str, key: &str) -> Option<u64> {
+    let quoted = format!("\"{}\"", key);
+    let rest = json[json.find(&quoted)? + quoted.len()..].trim_start().strip_prefix(':')?.trim_start();
+    let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    rest[..digits].parse().ok()
+}
@@ -0,0 +1,461 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Mul, Sub};
+
+use crate::vm::Value;
+
+const BASE: u64 = 1_000_000_000;
+const BASE_DIGITS: usize = 9;
+
+// The table only collects once it has grown past this many values.
+const MIN_COLLECT_AT: usize = 1024;
+
+// An integer of any size. The limbs are base 10^9 so that printing one, which programs
+// do far more often than multiplying or dividing, takes linear time.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct BigInt {
+    negative: bool,
+    // least significant first, with no zero limbs at the end, so zero has none
+    limbs: Vec<u32>,
+}
+
+impl BigInt {
+    // Digits with an optional sign, e.g. "-123".
+    pub fn parse(text: &str) -> Option<BigInt> {
+        let (negative, digits) = match text.as_bytes().first() {
+            Some(b'-') => (true, &text[1..]),
+            Some(b'+') => (false, &text[1..]),
+            _ => (false, text),
+        };
+        if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+            return None;
+        }
+
+        let limbs = digits.as_bytes().rchunks(BASE_DIGITS).map(|chunk| {
+            chunk.iter().fold(0, |limb, digit| limb * 10 + (digit - b'0') as u32)
+        }).collect();
+        Some(BigInt::new(negative, limbs))
+    }
+
+    pub fn from_i64(value: i64) -> BigInt {
+        let mut magnitude = value.unsigned_abs();
+        let mut limbs = Vec::new();
+        while magnitude > 0 {
+            limbs.push((magnitude % BASE) as u32);
+            magnitude /= BASE;
+        }
+        BigInt::new(value < 0, limbs)
+    }
+
+    // None if it does not fit in an i64.
+    pub fn to_i64(&self) -> Option<i64> {
+        let mut magnitude: i128 = 0;
+        for limb in self.limbs.iter().rev() {
+            magnitude = magnitude * BASE as i128 + *limb as i128;
+            if magnitude > i64::MAX as i128 + 1 {
+                return None;
+            }
+        }
+        i64::try_from(if self.negative { -magnitude } else { magnitude }).ok()
+    }
+
+    fn new(negative: bool, mut limbs: Vec<u32>) -> BigInt {
+        trim(&mut limbs);
+        // there is no negative zero
+        BigInt { negative: negative && !limbs.is_empty(), limbs }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.limbs.is_empty()
+    }
+
+    pub fn abs(&self) -> BigInt {
+        BigInt::new(false, self.limbs.clone())
+    }
+
+    // Truncates towards zero like Int division, None when dividing by zero.
+    pub fn checked_div(&self, other: &BigInt) -> Option<BigInt> {
+        if other.is_zero() {
+            return None;
+        }
+
+        let mut quotient = vec![0; self.limbs.len()];
+        let mut remainder = Vec::new();
+        for index in (0..self.limbs.len()).rev() {
+            remainder.insert(0, self.limbs[index]);
+            trim(&mut remainder);
+
+            // the largest limb the divisor can be multiplied by without passing the remainder
+            let (mut low, mut high) = (0, BASE as u32 - 1);
+            while low < high {
+                let middle = low + (high - low).div_ceil(2);
+                if compare_magnitudes(&multiply_limb(&other.limbs, middle), &remainder) == Ordering::Greater {
+                    high = middle - 1;
+                } else {
+                    low = middle;
+                }
+            }
+            remainder = subtract_magnitudes(&remainder, &multiply_limb(&other.limbs, low));
+            quotient[index] = low;
+        }
+
+        Some(BigInt::new(self.negative != other.negative, quotient))
+    }
+
+    pub fn pow(&self, mut exponent: u32) -> BigInt {
+        let mut result = BigInt::from_i64(1);
+        let mut base = self.clone();
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                result = &result * &base;
+            }
+            exponent >>= 1;
+            if exponent > 0 {
+                base = &base * &base;
+            }
+        }
+        result
+    }
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, other: Self) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::new(self.negative, add_magnitudes(&self.limbs, &other.limbs));
+        }
+
+        // the signs differ, so the larger magnitude decides the sign
+        match compare_magnitudes(&self.limbs, &other.limbs) {
+            Ordering::Less => BigInt::new(other.negative, subtract_magnitudes(&other.limbs, &self.limbs)),
+            _ => BigInt::new(self.negative, subtract_magnitudes(&self.limbs, &other.limbs)),
+        }
+    }
+}
+
+impl Sub for &BigInt {
+    type Output = BigInt;
+
+    fn sub(self, other: Self) -> BigInt {
+        self + &BigInt::new(!other.negative, other.limbs.clone())
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+
+    fn mul(self, other: Self) -> BigInt {
+        let mut product = vec![0u64; self.limbs.len() + other.limbs.len()];
+        for (i, a) in self.limbs.iter().enumerate() {
+            // every sum stays below BASE * BASE, so the carry stays below BASE
+            let mut carry = 0;
+            for (j, b) in other.limbs.iter().enumerate() {
+                let sum = product[i + j] + *a as u64 * *b as u64 + carry;
+                product[i + j] = sum % BASE;
+                carry = sum / BASE;
+            }
+            product[i + other.limbs.len()] = carry;
+        }
+
+        BigInt::new(self.negative != other.negative, product.into_iter().map(|limb| limb as u32).collect())
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare_magnitudes(&self.limbs, &other.limbs),
+            (true, true) => compare_magnitudes(&other.limbs, &self.limbs),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.negative {
+            write!(f, "-")?;
+        }
+        match self.limbs.split_last() {
+            None => write!(f, "0"),
+            Some((most_significant, rest)) => {
+                write!(f, "{}", most_significant)?;
+                for limb in rest.iter().rev() {
+                    write!(f, "{:09}", limb)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// The VM's bigs, which Value::Big indexes so that a big is Copy like every other value.
+// The first slots hold the program's constants and are never freed. The rest hold the
+// results of ops, and once enough of those have been added the VM collects between two
+// ops, passing every value still on the stack or in memory. Slots none of them refers
+// to are freed and handed out again.
+pub struct BigTable {
+    values: Vec<BigInt>,
+    constant_count: usize,
+    // freed slots, the lowest index last so it is reused first
+    free: Vec<usize>,
+    // collect once the table has grown to this many slots with none free
+    collect_at: usize,
+}
+
+impl BigTable {
+    pub fn new(values: Vec<BigInt>, constant_count: usize) -> Self {
+        let collect_at = (values.len() * 2).max(MIN_COLLECT_AT);
+        BigTable { values, constant_count, free: Vec::new(), collect_at }
+    }
+
+    pub fn get(&self, index: usize) -> &BigInt {
+        &self.values[index]
+    }
+
+    // Every slot, freed ones holding 0, so the indices values refer to stay the same.
+    pub fn values(&self) -> &[BigInt] {
+        &self.values
+    }
+
+    pub fn add(&mut self, value: BigInt) -> usize {
+        match self.free.pop() {
+            Some(index) => {
+                self.values[index] = value;
+                index
+            }
+            None => {
+                self.values.push(value);
+                self.values.len() - 1
+            }
+        }
+    }
+
+    // Whether the free slots have run out and the table has grown to twice what was
+    // live after the last collection.
+    pub fn wants_collection(&self) -> bool {
+        self.free.is_empty() && self.values.len() >= self.collect_at
+    }
+
+    // Frees every slot after the constants that none of the live values refers to.
+    pub fn collect(&mut self, live: impl IntoIterator<Item = Value>) {
+        let mut marked = vec![false; self.values.len()];
+        for value in live {
+            if let Value::Big(index) = value {
+                marked[index] = true;
+            }
+        }
+
+        // unused slots at the end are dropped, the ones in between are kept for reuse
+        let mut length = self.values.len();
+        while length > self.constant_count && !marked[length - 1] {
+            length -= 1;
+        }
+        self.values.truncate(length);
+        self.free.clear();
+        for index in (self.constant_count..length).rev().filter(|index| !marked[*index]) {
+            self.values[index] = BigInt::from_i64(0);
+            self.free.push(index);
+        }
+        self.collect_at = ((length - self.free.len()) * 2).max(MIN_COLLECT_AT);
+    }
+}
+
+fn trim(limbs: &mut Vec<u32>) {
+    while limbs.last() == Some(&0) {
+        limbs.pop();
+    }
+}
+
+// Both trimmed, so the longer one is larger.
+fn compare_magnitudes(a: &[u32], b: &[u32]) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut sum = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0;
+    for index in 0..a.len().max(b.len()) {
+        let limb = *a.get(index).unwrap_or(&0) as u64 + *b.get(index).unwrap_or(&0) as u64 + carry;
+        sum.push((limb % BASE) as u32);
+        carry = limb / BASE;
+    }
+    sum.push(carry as u32);
+    trim(&mut sum);
+    sum
+}
+
+// a must be at least as large as b.
+fn subtract_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut difference = Vec::with_capacity(a.len());
+    let mut borrow = 0;
+    for (index, limb) in a.iter().enumerate() {
+        let subtrahend = *b.get(index).unwrap_or(&0) as i64 + borrow;
+        let mut limb = *limb as i64 - subtrahend;
+        borrow = 0;
+        if limb < 0 {
+            limb += BASE as i64;
+            borrow = 1;
+        }
+        difference.push(limb as u32);
+    }
+    trim(&mut difference);
+    difference
+}
+
+fn multiply_limb(a: &[u32], factor: u32) -> Vec<u32> {
+    let mut product = Vec::with_capacity(a.len() + 1);
+    let mut carry = 0;
+    for limb in a {
+        let limb = *limb as u64 * factor as u64 + carry;
+        product.push((limb % BASE) as u32);
+        carry = limb / BASE;
+    }
+    product.push(carry as u32);
+    trim(&mut product);
+    product
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BigInt, BigTable, MIN_COLLECT_AT};
+    use crate::vm::Value;
+
+    fn big(text: &str) -> BigInt {
+        BigInt::parse(text).unwrap()
+    }
+
+    #[test]
+    fn parses_and_prints() {
+        assert_eq!(big("0").to_string(), "0");
+        assert_eq!(big("-0").to_string(), "0");
+        assert_eq!(big("+42").to_string(), "42");
+        assert_eq!(big("000123").to_string(), "123");
+        // a limb of zeros in the middle is padded back out to nine digits
+        assert_eq!(big("1000000000000000001").to_string(), "1000000000000000001");
+        assert_eq!(big("-123456789012345678901234567890").to_string(), "-123456789012345678901234567890");
+        for text in ["", "-", "+", "12a", "1 2", "--1"] {
+            assert_eq!(BigInt::parse(text), None, "parsed {:?}", text);
+        }
+    }
+
+    #[test]
+    fn converts_to_and_from_i64() {
+        for value in [0, 1, -1, 999_999_999, 1_000_000_000, -1_000_000_000, i64::MAX, i64::MIN] {
+            assert_eq!(BigInt::from_i64(value).to_string(), value.to_string());
+            assert_eq!(BigInt::from_i64(value).to_i64(), Some(value));
+        }
+        assert_eq!(big("9223372036854775808").to_i64(), None);
+        assert_eq!(big("-9223372036854775809").to_i64(), None);
+    }
+
+    #[test]
+    fn carries_and_borrows_across_limbs() {
+        assert_eq!(&big("999999999") + &big("1"), big("1000000000"));
+        assert_eq!(&big("999999999999999999") + &big("1"), big("1000000000000000000"));
+        assert_eq!(&big("1000000000") - &big("1"), big("999999999"));
+        assert_eq!(&big("1000000000000000000") - &big("1"), big("999999999999999999"));
+        assert_eq!(&big("999999999") * &big("999999999"), big("999999998000000001"));
+        assert_eq!(&big("999999999999999999") * &big("999999999999999999"), big("999999999999999998000000000000000001"));
+    }
+
+    #[test]
+    fn follows_signs() {
+        assert_eq!(&big("5") + &big("-7"), big("-2"));
+        assert_eq!(&big("-5") + &big("7"), big("2"));
+        assert_eq!(&big("-5") + &big("-7"), big("-12"));
+        assert_eq!(&big("5") - &big("7"), big("-2"));
+        assert_eq!(&big("-5") - &big("-5"), big("0"));
+        assert_eq!(&big("-3") * &big("4"), big("-12"));
+        assert_eq!(&big("-3") * &big("-4"), big("12"));
+        assert_eq!(big("-12").abs(), big("12"));
+        assert!(big("-1000000000000") < big("-1"));
+        assert!(big("-1") < big("0"));
+        assert!(big("1000000000") > big("999999999"));
+    }
+
+    #[test]
+    fn divides_towards_zero() {
+        let cases = [("7", "2", "3"), ("-7", "2", "-3"), ("7", "-2", "-3"), ("-7", "-2", "3"), ("1", "3", "0"), ("-1", "3", "0")];
+        for (a, b, quotient) in cases {
+            assert_eq!(big(a).checked_div(&big(b)), Some(big(quotient)), "{} / {}", a, b);
+            // divmod's remainder, which takes the sign of the dividend
+            let remainder = &big(a) - &(&big(quotient) * &big(b));
+            assert_eq!(remainder.to_i64(), Some(a.parse::<i64>().unwrap() % b.parse::<i64>().unwrap()), "{} mod {}", a, b);
+        }
+
+        let large = big("-123456789012345678901234567890");
+        assert_eq!(large.checked_div(&big("1000000000")), Some(big("-123456789012345678901")));
+        assert_eq!(large.checked_div(&big("-999999999999")), Some(big("123456789012469135")));
+        assert_eq!(large.checked_div(&large), Some(big("1")));
+    }
+
+    #[test]
+    fn handles_zero() {
+        let zero = big("0");
+        assert!(zero.is_zero());
+        assert_eq!(zero, BigInt::from_i64(0));
+        assert_eq!(big("5").checked_div(&zero), None);
+        assert_eq!(zero.checked_div(&big("-5")), Some(zero.clone()));
+        assert_eq!(&big("-5") * &zero, zero);
+        assert!(!(&big("-5") * &zero).to_string().starts_with('-'));
+        assert_eq!(big("-5").pow(0), big("1"));
+        assert_eq!(zero.pow(3), zero);
+        assert_eq!(big("-2").pow(3), big("-8"));
+        assert_eq!(big("2").pow(64).to_string(), "18446744073709551616");
+    }
+
+    #[test]
+    fn reuses_slots_no_value_refers_to() {
+        let mut table = BigTable::new(vec![BigInt::from_i64(0), big("7")], 2);
+        let first = table.add(big("1"));
+        let second = table.add(big("2"));
+        let third = table.add(big("3"));
+        assert_eq!((first, second, third), (2, 3, 4));
+
+        // the constants stay whether or not anything refers to them
+        table.collect([Value::Big(third), Value::Int(second as i64)]);
+        assert_eq!(table.get(1), &big("7"));
+        assert_eq!(table.get(third), &big("3"));
+        assert_eq!(table.add(big("4")), first);
+        assert_eq!(table.add(big("5")), second);
+        assert_eq!(table.add(big("6")), 5);
+
+        // slots at the end nothing refers to are dropped
+        table.collect([Value::Big(first)]);
+        assert_eq!(table.values().len(), 3);
+        assert_eq!(table.add(big("8")), 3);
+    }
+
+    #[test]
+    fn wants_collection_once_it_has_grown() {
+        let mut table = BigTable::new(vec![BigInt::from_i64(0)], 1);
+        while table.values().len() < MIN_COLLECT_AT {
+            assert!(!table.wants_collection());
+            table.add(big("1"));
+        }
+        assert!(table.wants_collection());
+
+        // every other value is kept, so the freed slots are filled first and then the
+        // table grows to twice the 513 values that were live
+        let live: Vec<Value> = (1..MIN_COLLECT_AT).step_by(2).map(Value::Big).collect();
+        table.collect(live);
+        for _ in 0..MIN_COLLECT_AT / 2 - 1 {
+            assert!(!table.wants_collection());
+            table.add(big("1"));
+        }
+        assert_eq!(table.values().len(), MIN_COLLECT_AT);
+        table.add(big("1"));
+        assert!(!table.wants_collection());
+        table.add(big("1"));
+        assert!(table.wants_collection());
+    }
+}
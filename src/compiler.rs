@@ -1,285 +1,2580 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::io::{self, Write};
 use std::path::Path;
+use std::sync::mpsc;
+use std::thread::{self, Scope};
+use std::time::Duration;
 use std::num::IntErrorKind;
+use std::ops::{Index, Range};
 
 use colored::*;
 
-use crate::scanner::{Scanner, TokenType, Token};
-use crate::vm::{DataType, Op, Value, VM};
+use crate::bigint::BigInt;
+use crate::condition;
+use crate::coverage;
+use crate::diagnostics::Diagnostic;
+use crate::dump::{self, Node};
+use crate::error_code::ErrorCode;
+use crate::ffi::{self, ExternFunction};
+use crate::heap::DEFAULT_HEAP_SIZE;
+use crate::include::load_includes;
+use crate::log::Verbosity;
+use crate::scanner::{self, Scanner, TokenType, Token};
+use crate::source_map::SourceMap;
+use crate::snapshot::Snapshot;
+use crate::span::{FileId, Span};
+use crate::trace::Trace;
+use crate::vm::{self, ArrayRef, DataType, ErrorKind, Op, Permissions, RuntimeError, Stats, Value, VM};
+
+#[derive(Clone, Copy)]
+enum Symbol {
+    Variable(usize),
+    Array(ArrayRef),
+    // index into CompilerContext::structs
+    Struct(usize),
+    // index into CompilerContext::enums
+    Enum(usize),
+    // index into the VM's extern table
+    Extern(usize),
+    // the string table index of its text and the cells holding its bytes
+    Data(usize, ArrayRef),
+}
+
+struct Definition<'a> {
+    symbol: Symbol,
+    token: Token<'a>,
+    // items inside a module are private to it unless marked 'pub'
+    public: bool,
+    attributes: Attributes,
+    // whether the name is used anywhere, names that never are get a warning
+    used: bool,
+}
+
+// What the attributes written before a declaration, like '#deprecated', say about it.
+#[derive(Clone, Default)]
+struct Attributes {
+    // the reason given, which may be empty
+    deprecated: Option<String>,
+    // no warning if it is never used, for items kept on purpose
+    allow_unused: bool,
+}
+
+enum Lookup {
+    // path of the item found
+    Found(String),
+    // path of an item that exists but is private to a module the name is used outside of
+    Private(String),
+    Missing,
+}
+
+struct StructDef<'a> {
+    fields: Vec<(&'a str, DataType)>,
+}
+
+struct EnumDef<'a> {
+    variants: Vec<&'a str>,
+}
+
+// Upper bound on the ops a comptime block may execute.
+const COMPTIME_FUEL: usize = 100_000;
+
+// Upper bound on the ops a whole program may execute to be folded, see VM::fold_program.
+const FOLD_FUEL: usize = 1_000_000;
+
+struct Comptime<'a> {
+    token: Token<'a>,
+    // index of the block's first op
+    start: usize,
+    // the stack from before the block, which the block cannot see
+    outer_stack: TypeStack,
+}
+
+struct When<'a> {
+    token: Token<'a>,
+    // how many modules were open when the block was, so an 'end' closes the block only
+    // if no module was opened inside it
+    modules: usize,
+    // whether the branch being compiled is the else branch
+    in_else: bool,
+}
+
+// The types the program will have on the stack at the current op, along with the span
+// of the word that pushed each one so type errors can point back at it.
+#[derive(Default)]
+struct TypeStack {
+    types: Vec<DataType>,
+    origins: Vec<Span>,
+    // the most values the stack has held, which is how much the VM has to allocate
+    max_depth: usize,
+}
+
+impl TypeStack {
+    fn len(&self) -> usize {
+        self.types.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.types.is_empty()
+    }
+
+    fn last(&self) -> Option<&DataType> {
+        self.types.last()
+    }
+
+    fn types(&self) -> &[DataType] {
+        &self.types
+    }
+
+    // depth counts down from the top of the stack, 0 is the top
+    fn origin(&self, depth: usize) -> Span {
+        self.origins[self.origins.len() - 1 - depth]
+    }
+
+    fn push(&mut self, data_type: DataType, origin: Span) {
+        self.types.push(data_type);
+        self.origins.push(origin);
+        self.max_depth = self.max_depth.max(self.types.len());
+    }
+
+    fn pop(&mut self) {
+        self.pop_n(1);
+    }
+
+    fn pop_n(&mut self, count: usize) {
+        self.types.truncate(self.types.len() - count);
+        self.origins.truncate(self.origins.len() - count);
+    }
+
+    // moves the value at depth to the top, keeping where it came from
+    fn move_to_top(&mut self, depth: usize) {
+        let index = self.types.len() - 1 - depth;
+        let data_type = self.types.remove(index);
+        let origin = self.origins.remove(index);
+        self.push(data_type, origin);
+    }
+
+    // pushes a copy of the value at depth, which points at the same word as the original
+    fn copy_to_top(&mut self, depth: usize) {
+        self.push(self.types[self.types.len() - 1 - depth], self.origin(depth));
+    }
+}
+
+impl Index<usize> for TypeStack {
+    type Output = DataType;
+
+    fn index(&self, index: usize) -> &DataType {
+        &self.types[index]
+    }
+}
 
 struct CompilerContext<'a> {
-    file_path: String,
-    code_string: &'a String,
-    stack: Vec<DataType>,
-    vm: VM,
+    sources: &'a SourceMap,
+    stack: TypeStack,
+    // the span of the word being compiled, which pushes onto the stack are attributed to
+    origin: Option<Span>,
+    vm: &'a mut VM,
+    // keyed by the name qualified with its module path, e.g. 'math::counter'
+    symbols: HashMap<String, Definition<'a>>,
+    // the modules currently open, outermost first
+    modules: Vec<Token<'a>>,
+    module_paths: HashSet<String>,
+    structs: Vec<StructDef<'a>>,
+    enums: Vec<EnumDef<'a>>,
+    comptime: Option<Comptime<'a>>,
+    // the open 'when' blocks, innermost last
+    whens: Vec<When<'a>>,
+    // names given with --define, for 'when'
+    defines: &'a [String],
+    // of the declaration being compiled, given to every name it defines
+    attributes: Attributes,
+    allow_unsafe: bool,
     had_error: bool,
 }
 
-pub fn compile(file_path: &String, verbose: bool) {
-    let path = Path::new(file_path.trim());
+pub struct Options {
+    pub verbosity: Verbosity,
+    pub heap_size: usize,
+    pub seed: Option<u64>,
+    // enables the syscall words
+    pub allow_unsafe: bool,
+    pub max_ops: Option<usize>,
+    pub timeout: Option<Duration>,
+    pub permissions: Permissions,
+    // directories searched for included files, after the directory of the including file
+    pub include_paths: Vec<String>,
+    // buffer println output, turned off for programs that print while interacting with a user
+    pub buffer_output: bool,
+    // merge common pairs of ops into single ops, turned off to see the ops as compiled
+    pub fuse_ops: bool,
+    // run programs that only compute and print while compiling, keeping just what they print
+    pub fold_program: bool,
+    // threads to use, more than one lexes files on other threads while they are compiled
+    pub jobs: usize,
+    // count how many times each line runs and report it when the program stops
+    pub coverage: bool,
+    // also write the counts as an lcov tracefile here
+    pub lcov: Option<String>,
+    // report what the run used when the program stops
+    pub stats: bool,
+    // path to record a trace to, or replay one from
+    pub record: Option<String>,
+    pub replay: Option<String>,
+    // path to save the state of a run stopped by --max-ops, --timeout or Ctrl-C to, or resume one from
+    pub snapshot: Option<String>,
+    pub resume: Option<String>,
+    // print the compiled program in this format instead of running it
+    pub dump_ir: Option<DumpFormat>,
+    // print what this phase of compiling made and stop there, instead of running the program
+    pub stop_after: Option<Phase>,
+    // names that 'when' blocks treat as defined, on top of the platform's name
+    pub defines: Vec<String>,
+}
 
-    let extension = path.extension();
-    if extension.is_none() || extension.unwrap() != "px2" {
-        eprintln!("Given file {:?} was not a '.px2' file", path);
-        return;
+#[derive(Clone, Copy)]
+pub enum DumpFormat {
+    Json,
+    Sexp,
+}
+
+impl DumpFormat {
+    pub fn from_name(name: &str) -> Option<DumpFormat> {
+        match name {
+            "json" => Some(DumpFormat::Json),
+            "sexp" => Some(DumpFormat::Sexp),
+            _ => None,
+        }
+    }
+}
+
+// The phases a program goes through before it runs, in order. Checking the program is
+// what emits its ops, so check shows them as emitted, opt after they are fused and folded,
+// and codegen shows the finished program with its constants.
+#[derive(Clone, Copy, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Phase {
+    Scan,
+    Check,
+    Opt,
+    Codegen,
+}
+
+impl Phase {
+    pub fn from_name(name: &str) -> Option<Phase> {
+        match name {
+            "scan" => Some(Phase::Scan),
+            "check" => Some(Phase::Check),
+            "opt" => Some(Phase::Opt),
+            "codegen" => Some(Phase::Codegen),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            verbosity: Verbosity::Quiet,
+            heap_size: DEFAULT_HEAP_SIZE,
+            seed: None,
+            allow_unsafe: false,
+            max_ops: None,
+            timeout: None,
+            permissions: Permissions::default(),
+            include_paths: Vec::new(),
+            buffer_output: true,
+            fuse_ops: true,
+            fold_program: false,
+            jobs: 1,
+            coverage: false,
+            lcov: None,
+            stats: false,
+            record: None,
+            replay: None,
+            snapshot: None,
+            resume: None,
+            dump_ir: None,
+            stop_after: None,
+            defines: Vec::new(),
+        }
+    }
+}
+
+// Exit codes for the px2 process, returned by compile and compile_with.
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+// what a shell reports for a program killed by SIGPIPE, as programs like cat are when
+// their output is closed
+pub const EXIT_BROKEN_PIPE: i32 = 128 + 13;
+// and for one stopped by SIGINT
+pub const EXIT_INTERRUPTED: i32 = 128 + 2;
+
+// Compiles and runs a file, returning the exit code for it.
+pub fn compile(file_path: &str, options: &Options) -> i32 {
+    compile_with(file_path, options, VM::new())
+}
+
+// Compiles and runs a file on a VM the host has already set up, e.g. with builtins registered.
+pub fn compile_with(file_path: &str, options: &Options, mut vm: VM) -> i32 {
+    let verbosity = options.verbosity;
+    let (mut sources, file) = match load_source(file_path) {
+        Some(source) => source,
+        None => return EXIT_FAILURE,
+    };
+
+    let files = load_includes(&mut sources, file, &options.include_paths, &options.defines, &mut **vm.error_output());
+    if !files.is_some_and(|files| compile_source(&sources, &files, options, &mut vm)) {
+        let _ = writeln!(vm.error_output(), "Stopping execution due to compilation errors");
+        return EXIT_FAILURE;
+    }
+    if options.dump_ir.is_some() || options.stop_after.is_some_and(|phase| phase < Phase::Opt) {
+        return EXIT_SUCCESS;
+    }
+
+    configure(&mut vm, options);
+    // coverage and the step limit count ops that folding would remove
+    if options.fold_program && !options.coverage && options.max_ops.is_none() && vm.fold_program(FOLD_FUEL) {
+        verbosity.log(Verbosity::Ops, "Folded the program into what it prints:");
+        for op in vm.ops() {
+            verbosity.log(Verbosity::Ops, op);
+        }
+    }
+    if let Some(phase) = options.stop_after {
+        print_ops(&sources, &vm, phase == Phase::Codegen, &mut io::stdout());
+        return EXIT_SUCCESS;
+    }
+    if options.coverage {
+        vm.enable_coverage();
+    }
+    if options.snapshot.is_some() {
+        vm.enable_snapshots();
+    }
+    if let Some(path) = &options.resume {
+        if let Err(message) = Snapshot::load(path).and_then(|snapshot| vm.resume_from(snapshot)) {
+            eprintln!("Error resuming from snapshot: {}", message);
+            return EXIT_FAILURE;
+        }
+    }
+    let mut trace = match (&options.record, &options.replay) {
+        (_, Some(path)) => match Trace::load(path) {
+            Ok(trace) => trace,
+            Err(message) => {
+                eprintln!("Error reading trace: {}", message);
+                return EXIT_FAILURE;
+            }
+        },
+        (Some(_), None) => Trace::record(),
+        (None, None) => Trace::off(),
+    };
+
+    let start = std::time::Instant::now();
+    let result = vm.run_with_trace(options.max_ops, &mut trace);
+    verbosity.log(Verbosity::Timing, format!("Execution finished in {:?}", start.elapsed()));
+    let exit_code = match &result {
+        Ok(_) => EXIT_SUCCESS,
+        Err(runtime_error) if runtime_error.kind == ErrorKind::BrokenPipe => EXIT_BROKEN_PIPE,
+        Err(runtime_error) if runtime_error.kind == ErrorKind::Interrupted => EXIT_INTERRUPTED,
+        Err(_) => EXIT_FAILURE,
+    };
+    if let Err(runtime_error) = result {
+        report_runtime_error(&runtime_error, &sources, &vm);
+    }
+
+    if options.stats {
+        report_stats(&vm.stats(), &mut **vm.error_output());
+    }
+
+    if let Some(counts) = vm.op_counts() {
+        coverage::report(&sources, vm.op_spans(), &counts, &mut **vm.error_output());
+        if let Some(path) = &options.lcov {
+            if let Err(message) = coverage::write_lcov(path, &sources, vm.op_spans(), &counts) {
+                eprintln!("Error writing coverage: {}", message);
+            }
+        }
+    }
+
+    if let (Some(path), Some(snapshot)) = (&options.snapshot, vm.take_snapshot()) {
+        match snapshot.save(path) {
+            Ok(()) => eprintln!("Saved the program's state to '{}', carry on from it with '--resume {}'", path, path),
+            Err(message) => eprintln!("Error writing snapshot: {}", message),
+        }
+    }
+
+    // saved even when the program failed, that is usually the run worth replaying
+    if let Some(path) = &options.record {
+        if let Err(message) = trace.save(path) {
+            eprintln!("Error writing trace: {}", message);
+        }
     }
 
+    exit_code
+}
+
+// Compiles a file once and runs it warmup times, then runs it runs more times and returns
+// how long each of those took to execute. The program's output is thrown away. Returns
+// None if the program failed to compile or run, its diagnostics having been reported.
+pub fn bench(file_path: &str, options: &Options, warmup: usize, runs: usize) -> Option<Vec<Duration>> {
+    let (mut sources, file) = load_source(file_path)?;
+
+    let mut vm = VM::new().with_output(io::sink());
+    let files = load_includes(&mut sources, file, &options.include_paths, &options.defines, &mut **vm.error_output())?;
+    if !compile_source(&sources, &files, options, &mut vm) {
+        return None;
+    }
+    configure(&mut vm, options);
+
+    let mut times = Vec::with_capacity(runs);
+    for run in 0..warmup + runs {
+        let start = std::time::Instant::now();
+        let result = vm.run_with_trace(options.max_ops, &mut Trace::off());
+        let elapsed = start.elapsed();
+        if let Err(runtime_error) = result {
+            report_runtime_error(&runtime_error, &sources, &vm);
+            return None;
+        }
+        if run >= warmup {
+            times.push(elapsed);
+        }
+    }
+    Some(times)
+}
+
+fn configure(vm: &mut VM, options: &Options) {
+    vm.set_heap_size(options.heap_size);
+    vm.set_seed(options.seed);
+    vm.set_timeout(options.timeout);
+    vm.set_permissions(options.permissions);
+    vm.set_buffered(options.buffer_output);
+}
+
+// Compiles a file without running it, for checking many files at once. Returns whether
+// the file compiled, its diagnostics having been reported if not.
+pub fn check(file_path: &str, options: &Options) -> bool {
+    let (mut sources, file) = match load_source(file_path) {
+        Some(source) => source,
+        None => return false,
+    };
+
+    let mut vm = VM::new();
+    let files = load_includes(&mut sources, file, &options.include_paths, &options.defines, &mut **vm.error_output());
+    if !files.is_some_and(|files| compile_source(&sources, &files, options, &mut vm)) {
+        return false;
+    }
+    if let Some(phase) = options.stop_after.filter(|phase| *phase >= Phase::Opt) {
+        print_ops(&sources, &vm, phase == Phase::Codegen, &mut io::stdout());
+    }
+    true
+}
+
+fn load_source(file_path: &str) -> Option<(SourceMap, FileId)> {
+    let path = Path::new(file_path.trim());
+
     if !path.exists() {
-        eprintln!("Given file {:?} does not exist", path);
-        return;
+        eprintln!("Given file '{}' does not exist", path.display());
+        return None;
     }
 
-    let code_string = match fs::read_to_string(file_path) {
+    let code_string = match fs::read_to_string(path) {
         Ok(s) => s,
         Err(error) => {
             eprintln!("Error reading file: {}", error);
-            return;
+            return None;
         }
     };
 
-    let mut scanner = Scanner::new(&code_string);
+    // extensions are not case sensitive on Windows, so 'MAIN.PX2' is a px2 file too, and
+    // a script run through its '#!' line can be named anything
+    let is_px2 = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("px2"));
+    if !is_px2 && !code_string.starts_with("#!") {
+        eprintln!("Given file '{}' was not a '.px2' file or a script starting with '#!'", path.display());
+        return None;
+    }
+
+    let mut sources = SourceMap::new();
+    let file = sources.add(file_path.trim().to_string(), code_string);
+    Some((sources, file))
+}
+
+// Compiles loaded files into the VM one after the other, returning whether they compiled.
+fn compile_source(sources: &SourceMap, files: &[FileId], options: &Options, vm: &mut VM) -> bool {
+    let verbosity = options.verbosity;
+    if options.stop_after == Some(Phase::Scan) {
+        print_tokens(sources, files, &mut io::stdout());
+        return true;
+    }
     let mut compiler = CompilerContext {
-        file_path: file_path.to_string(),
-        code_string: &code_string,
-        stack: Vec::<DataType>::new(),
-        vm: VM::new(),
+        sources,
+        stack: TypeStack::default(),
+        origin: None,
+        vm,
+        symbols: HashMap::new(),
+        modules: Vec::new(),
+        module_paths: HashSet::new(),
+        structs: Vec::new(),
+        enums: Vec::new(),
+        comptime: None,
+        whens: Vec::new(),
+        defines: &options.defines,
+        attributes: Attributes::default(),
+        allow_unsafe: options.allow_unsafe,
         had_error: false,
     };
 
     let start = std::time::Instant::now();
 
+    let compiled = if options.jobs > 1 {
+        thread::scope(|scope| {
+            let scanners = scan_in_background(scope, sources, files, options.jobs - 1);
+            scanners.into_iter().all(|mut scanner| compile_file(&mut scanner, &mut compiler, verbosity))
+        })
+    } else {
+        files.iter().all(|&file| compile_file(&mut Scanner::new(sources.code(file), file), &mut compiler, verbosity))
+    };
+    if !compiled {
+        return false;
+    }
+
+    if !compiler.stack.is_empty() {
+        unhandled_data(&mut compiler);
+        return false;
+    }
+    unused_warnings(&mut compiler);
+
+    compiler.vm.set_max_stack_depth(compiler.stack.max_depth);
+    // every op has been type checked, so they can skip checking at runtime
+    compiler.vm.set_checked(false);
+    if options.stop_after == Some(Phase::Check) {
+        print_ops(sources, compiler.vm, false, &mut io::stdout());
+        return true;
+    }
+    // a fused op has one span, so coverage keeps the ops apart to count both of their lines
+    if options.fuse_ops && !options.coverage {
+        compiler.vm.fuse_ops();
+    }
+
+    verbosity.log(Verbosity::Timing, format!("Compilation succeeded in {:?}", start.elapsed()));
+    if verbosity.enabled(Verbosity::Ops) {
+        for (index, value) in compiler.vm.constants().iter().enumerate() {
+            verbosity.log(Verbosity::Ops, format!("#{} = {}", index, value));
+        }
+        for op in compiler.vm.ops() {
+            verbosity.log(Verbosity::Ops, op);
+        }
+    }
+
+    if let Some(format) = options.dump_ir {
+        let program = dump::program(sources, files, compiler.vm, symbol_nodes(&compiler));
+        let _ = dump::write(format, &program, &mut io::stdout());
+    }
+
+    true
+}
+
+// For --stop-after scan, every token of every file, errors included.
+fn print_tokens(sources: &SourceMap, files: &[FileId], out: &mut dyn Write) {
+    for &file in files {
+        for token in Scanner::new(sources.code(file), file) {
+            let _ = writeln!(out, "{}:{}:{}  {:?}  '{}'", sources.path(file), token.span.line, token.span.column, token.token_type, token.text);
+        }
+    }
+}
+
+// For the later phases of --stop-after, the ops with where each came from, and for
+// codegen the constant pool they use first.
+fn print_ops(sources: &SourceMap, vm: &VM, with_constants: bool, out: &mut dyn Write) {
+    if with_constants {
+        for (index, value) in vm.constants().iter().enumerate() {
+            let _ = match value {
+                Value::Str(_) => writeln!(out, "#{} = {:?}", index, vm.string_constant(*value)),
+                Value::Big(_) => writeln!(out, "#{} = {}n", index, vm.big_constant(*value)),
+                _ => writeln!(out, "#{} = {}", index, value),
+            };
+        }
+    }
+    for (index, (op, span)) in vm.ops().iter().zip(vm.op_spans()).enumerate() {
+        let _ = match span {
+            Some(span) => writeln!(out, "{:>5}  {:<24}  {}:{}:{}", index, op.to_string(), sources.path(span.file), span.line, span.column),
+            None => writeln!(out, "{:>5}  {}", index, op),
+        };
+    }
+}
+
+// Every named item, sorted by path so the same program always dumps the same way.
+fn symbol_nodes(compiler: &CompilerContext) -> Vec<Node> {
+    let mut definitions: Vec<(&String, &Definition)> = compiler.symbols.iter().collect();
+    definitions.sort_by_key(|(path, _)| *path);
+
+    definitions.into_iter().map(|(path, definition)| {
+        let mut fields = vec![
+            ("name", Node::Str(path.clone())),
+            ("public", Node::Bool(definition.public)),
+            ("span", Node::span(&definition.token.span)),
+        ];
+        let names = |names: Vec<String>| Node::List(names.into_iter().map(Node::Str).collect());
+        match definition.symbol {
+            Symbol::Variable(address) => fields.extend([
+                ("kind", Node::Str("variable".to_string())),
+                ("address", Node::Int(address as i64)),
+                ("type", Node::Str(compiler.vm.global_type(address).to_string())),
+            ]),
+            Symbol::Array(array) => fields.extend([
+                ("kind", Node::Str("array".to_string())),
+                ("address", Node::Int(array.address as i64)),
+                ("length", Node::Int(array.length as i64)),
+                ("type", Node::Str(compiler.vm.global_type(array.address).to_string())),
+            ]),
+            Symbol::Struct(index) => fields.extend([
+                ("kind", Node::Str("struct".to_string())),
+                ("fields", names(compiler.structs[index].fields.iter().map(|(name, data_type)| format!("{}: {}", name, data_type)).collect())),
+            ]),
+            Symbol::Enum(index) => fields.extend([
+                ("kind", Node::Str("enum".to_string())),
+                ("variants", names(compiler.enums[index].variants.iter().map(|variant| variant.to_string()).collect())),
+            ]),
+            Symbol::Data(index, bytes) => fields.extend([
+                ("kind", Node::Str("data".to_string())),
+                ("address", Node::Int(bytes.address as i64)),
+                ("length", Node::Int(bytes.length as i64)),
+                ("text", Node::Str(compiler.vm.string_constant(Value::from_string_index(index)).to_string())),
+            ]),
+            Symbol::Extern(index) => {
+                let function = compiler.vm.extern_function(index);
+                fields.extend([
+                    ("kind", Node::Str("extern".to_string())),
+                    ("index", Node::Int(index as i64)),
+                    ("symbol", Node::Str(function.name.clone())),
+                    ("params", names(function.params.iter().map(DataType::to_string).collect())),
+                    ("returns", function.returns.map_or(Node::Null, |data_type| Node::Str(data_type.to_string()))),
+                ]);
+            }
+        }
+        Node::Record("symbol", fields)
+    }).collect()
+}
+
+// Chunks of tokens a scanner thread may get ahead of the compiler by, per file.
+const SCAN_AHEAD_CHUNKS: usize = 16;
+
+// Starts threads lexing the files while they are compiled, returning a scanner for each
+// file that hands out its tokens. The files are shared out between the threads in turn,
+// and each thread lexes its files in order, so the file being compiled is never waiting
+// on a thread stuck ahead on a later file.
+fn scan_in_background<'scope, 'a: 'scope>(scope: &'scope Scope<'scope, '_>, sources: &'a SourceMap, files: &[FileId], threads: usize) -> Vec<Scanner<'a>> {
+    let mut scanners = Vec::with_capacity(files.len());
+    let threads = threads.min(files.len());
+    let mut work = vec![Vec::new(); threads];
+    for (index, &file) in files.iter().enumerate() {
+        let (sender, receiver) = mpsc::sync_channel(SCAN_AHEAD_CHUNKS);
+        scanners.push(Scanner::streamed(sources.code(file), file, receiver));
+        work[index % threads].push((file, sender));
+    }
+
+    for files in work {
+        scope.spawn(move || {
+            for (file, sender) in files {
+                Scanner::new(sources.code(file), file).send_tokens(&sender);
+            }
+        });
+    }
+    scanners
+}
+
+// Compiles one file's tokens, returning whether it compiled.
+fn compile_file<'a>(scanner: &mut Scanner<'a>, compiler: &mut CompilerContext<'a>, verbosity: Verbosity) -> bool {
     loop {
         let token = scanner.scan_token();
 
-        #[cfg(debug_assertions)]
-        if verbose {
-            println!("{}", token);
+        verbosity.log(Verbosity::Tokens, token);
+
+        if compiler.comptime.is_some() && !allowed_in_comptime(token.token_type) {
+            error(&token, compiler, ErrorCode::NotAllowedInComptime, format!("'{}' cannot be used inside a comptime block", token.text));
+        }
+
+        if compiler.had_error {
+            return false;
         }
 
+        compiler.origin = Some(token.span);
         match token.token_type {
+            TokenType::Abs => int_op(&token, compiler, Op::Abs, "take the absolute value", 1),
+            TokenType::AddressOf => address_of(&token, compiler),
+            TokenType::Alloc => alloc(&token, compiler),
+            TokenType::Array => array_declaration(scanner, compiler, false),
+            TokenType::Attribute => attributed_declaration(&token, scanner, compiler),
+            TokenType::Big => big(&token, compiler),
+            TokenType::BigToInt => string_op(&token, compiler, Op::BigToInt, "convert to an integer", &[DataType::Big]),
+            TokenType::As => error(&token, compiler, ErrorCode::MisplacedWord, "'as' can only be used in an array declaration".to_string()),
+            // so scripts reading the output get 1 and 0 rather than true and false
+            TokenType::BoolToInt => cast(&token, compiler, DataType::Bool, DataType::Int),
+            TokenType::CharAt => string_op(&token, compiler, Op::CharAt, "get a character", &[DataType::Str, DataType::Int]),
+            TokenType::Compare => string_op(&token, compiler, Op::Compare, "compare strings", &[DataType::Str, DataType::Str]),
+            TokenType::ClockNs => compiler.push_op(Op::ClockNs),
+            TokenType::CheckedMinus => overflow_op(&token, compiler, Op::CheckedSubtract),
+            TokenType::CheckedPlus => overflow_op(&token, compiler, Op::CheckedAdd),
+            TokenType::CheckedStar => overflow_op(&token, compiler, Op::CheckedMultiply),
+            TokenType::Comptime => comptime_start(&token, compiler),
+            TokenType::Concat => string_op(&token, compiler, Op::Concat, "concatenate strings", &[DataType::Str, DataType::Str]),
+            TokenType::Data => data_declaration(scanner, compiler, false),
+            TokenType::DataBody => error(&token, compiler, ErrorCode::MisplacedWord, "a data body can only follow 'data <name>'".to_string()),
+            TokenType::DivMod => divide(&token, compiler, Op::DivMod),
+            TokenType::DoubleDash => error(&token, compiler, ErrorCode::MisplacedWord, "'--' can only be used in an extern declaration".to_string()),
             TokenType::Dup => {
                 if compiler.stack.is_empty() {
-                    error(&token, &mut compiler, "no data on the stack to dup".to_string());
+                    error(&token, compiler, ErrorCode::StackUnderflow, "no data on the stack to dup".to_string());
                 } else {
                     compiler.push_op(Op::Dup);
                 }
             }
             TokenType::Drop => {
                 if compiler.stack.is_empty() {
-                    error(&token, &mut compiler, "no data on the stack to drop".to_string());
+                    error(&token, compiler, ErrorCode::StackUnderflow, "no data on the stack to drop".to_string());
                 } else {
                     compiler.push_op(Op::Drop);
                 }
             }
-            TokenType::EndOfFile => break,
-            TokenType::Error => error(&token, &mut compiler, "invalid token".to_string()),
-            TokenType::False => compiler.push_op(Op::Push(Value::from_bool(false))),
-            TokenType::Int => int(&token, &mut compiler),
-            TokenType::Minus => subtract(&token, &mut compiler),
+            TokenType::Else => when_else(&token, scanner, compiler),
+            TokenType::End => {
+                if compiler.comptime.is_some() {
+                    comptime_end(&token, compiler);
+                } else if innermost_when(compiler).is_some() {
+                    compiler.whens.pop();
+                } else if compiler.modules.pop().is_none() {
+                    error(&token, compiler, ErrorCode::MisplacedWord, "'end' without a matching module, struct, enum or when".to_string());
+                }
+            }
+            TokenType::EndOfFile => {
+                if let Some(comptime) = compiler.comptime.as_ref().map(|comptime| comptime.token) {
+                    error(&comptime, compiler, ErrorCode::MissingEnd, "comptime block is missing 'end'".to_string());
+                } else if let Some(when) = innermost_when(compiler) {
+                    error(&when, compiler, ErrorCode::MissingEnd, "when block is missing 'end'".to_string());
+                } else if let Some(module) = compiler.modules.last().copied() {
+                    error(&module, compiler, ErrorCode::MissingEnd, format!("module '{}' is missing 'end'", module.text));
+                } else {
+                    break;
+                }
+            }
+            TokenType::Enum => enum_declaration(scanner, compiler, false),
+            TokenType::Equal => equal(&token, compiler),
+            TokenType::QueryStack => query_stack(&token, compiler),
+            TokenType::Error => error(&token, compiler, ErrorCode::InvalidToken, "invalid token".to_string()),
+            TokenType::Exec => string_op(&token, compiler, Op::Exec, "run a command", &[DataType::Str]),
+            TokenType::Extern => extern_declaration(scanner, compiler, false),
+            TokenType::False => compiler.push_constant(Value::from_bool(false)),
+            TokenType::Format => format(&token, compiler),
+            TokenType::Free => free(&token, compiler),
+            TokenType::GetEnv => string_op(&token, compiler, Op::GetEnv, "read an environment variable", &[DataType::Str]),
+            TokenType::Include => include(&token, scanner, compiler),
+            TokenType::Int => int(&token, compiler),
+            TokenType::InvalidNumber => error(&token, compiler, ErrorCode::InvalidInteger, format!("'{}' is not a valid integer literal", token.text)),
+            TokenType::IntToBig => cast(&token, compiler, DataType::Int, DataType::Big),
+            TokenType::IntToPtr => cast(&token, compiler, DataType::Int, DataType::Ptr),
+            TokenType::IntToStr => string_op(&token, compiler, Op::IntToStr, "convert to a string", &[DataType::Int]),
+            TokenType::IntToUInt => cast(&token, compiler, DataType::Int, DataType::UInt),
+            TokenType::Length => string_op(&token, compiler, Op::Length, "get the length of a string", &[DataType::Str]),
+            TokenType::Load => load(&token, compiler),
+            TokenType::Max => integer_op(&token, compiler, Op::Max, "take the maximum"),
+            TokenType::Min => integer_op(&token, compiler, Op::Min, "take the minimum"),
+            TokenType::Minus => subtract(&token, compiler),
+            TokenType::Module => module_declaration(scanner, compiler),
+            TokenType::When => when_start(&token, scanner, compiler),
+            TokenType::NowMs => compiler.push_op(Op::NowMs),
             TokenType::Over => {
                 let len = compiler.stack.len();
                 if len < 2 {
-                    error(&token, &mut compiler, format!("need 2 elements on the stack to perform over but found {}", len));
+                    error(&token, compiler, ErrorCode::StackUnderflow, format!("need 2 elements on the stack to perform over but found {}", len));
                 } else {
                     compiler.push_op(Op::Over);
                 }
             }
-            TokenType::Plus => add(&token, &mut compiler),
-            TokenType::PrintLn => println(&token, &mut compiler),
-            TokenType::Slash => divide(&token, &mut compiler),
+            TokenType::Plus => add(&token, compiler),
+            TokenType::Pow => int_op(&token, compiler, Op::Pow, "raise to a power", 2),
+            TokenType::PrintLn => println(&token, compiler, Op::PrintLn),
+            TokenType::EPrintLn => println(&token, compiler, Op::EPrintLn),
+            TokenType::Flush => compiler.push_op_at(Op::Flush, &token),
+            TokenType::PtrToInt => cast(&token, compiler, DataType::Ptr, DataType::Int),
+            TokenType::Pub => public_declaration(scanner, compiler),
+            TokenType::Slash => divide(&token, compiler, Op::Divide),
+            TokenType::Random => compiler.push_op(Op::Random),
+            TokenType::RandomRange => random_range(&token, compiler),
+            TokenType::Realloc => realloc(&token, compiler),
             TokenType::Rot => {
                 let len = compiler.stack.len();
                 if len < 3 {
-                    error(&token, &mut compiler, format!("need 3 elements on the stack to perform rot but found {}", len));
+                    error(&token, compiler, ErrorCode::StackUnderflow, format!("need 3 elements on the stack to perform rot but found {}", len));
                 } else {
                     compiler.push_op(Op::Rot);
                 }
             }
-            TokenType::Star => multiply(&token, &mut compiler),
+            TokenType::ShiftLeft => shift(&token, compiler, Op::ShiftLeft),
+            TokenType::ShiftRight => shift(&token, compiler, Op::ShiftRight),
+            TokenType::SaturatingMinus => overflow_op(&token, compiler, Op::SaturatingSubtract),
+            TokenType::SaturatingPlus => overflow_op(&token, compiler, Op::SaturatingAdd),
+            TokenType::SaturatingStar => overflow_op(&token, compiler, Op::SaturatingMultiply),
+            TokenType::SizeOf => size_of(scanner, compiler),
+            TokenType::Sleep => sleep(&token, compiler),
+            TokenType::Star => multiply(&token, compiler),
+            TokenType::Store => store(&token, compiler),
+            TokenType::StrToInt => string_op(&token, compiler, Op::StrToInt, "convert to an integer", &[DataType::Str]),
+            TokenType::String => string(&token, compiler),
+            TokenType::Struct => struct_declaration(scanner, compiler, false),
+            TokenType::Substring => string_op(&token, compiler, Op::Substring, "take a substring", &[DataType::Str, DataType::Int, DataType::Int]),
             TokenType::Swap => {
                 let len = compiler.stack.len();
                 if len < 2 {
-                    error(&token, &mut compiler, format!("need 2 elements on the stack to perform swap but found {}", len));
+                    error(&token, compiler, ErrorCode::StackUnderflow, format!("need 2 elements on the stack to perform swap but found {}", len));
                 } else {
                     compiler.push_op(Op::Swap);
                 }
             } 
-            TokenType::True => compiler.push_op(Op::Push(Value::from_bool(true))),
-            TokenType::Identifier => error(&token, &mut compiler, "identifiers are not implemented yet".to_string())
+            TokenType::Syscall => syscall(&token, compiler),
+            TokenType::TcpAccept => net_op(&token, compiler, Op::TcpAccept, "accept a connection", &[DataType::Int]),
+            TokenType::TcpClose => net_op(&token, compiler, Op::TcpClose, "close a socket", &[DataType::Int]),
+            TokenType::TcpConnect => net_op(&token, compiler, Op::TcpConnect, "connect", &[DataType::Str]),
+            TokenType::TcpListen => net_op(&token, compiler, Op::TcpListen, "listen", &[DataType::Str]),
+            TokenType::TcpRead => net_op(&token, compiler, Op::TcpRead, "read from a socket", &[DataType::Int]),
+            TokenType::TcpWrite => net_op(&token, compiler, Op::TcpWrite, "write to a socket", &[DataType::Str, DataType::Int]),
+            TokenType::True => compiler.push_constant(Value::from_bool(true)),
+            TokenType::UInt => uint(&token, compiler),
+            TokenType::UIntToInt => cast(&token, compiler, DataType::UInt, DataType::Int),
+            TokenType::UnterminatedData => error(&token, compiler, ErrorCode::MissingEnd, "data block is missing an 'end' line".to_string()),
+            TokenType::UnterminatedString => error(&token, compiler, ErrorCode::InvalidToken, "unterminated string".to_string()),
+            TokenType::Var => var_declaration(scanner, compiler, false),
+            TokenType::Identifier => identifier(&token, compiler),
         }
 
         if compiler.had_error {
-            eprintln!("Stopping execution due to compilation errors");
-            return;
+            return false;
         }
     }
 
-    if !compiler.stack.is_empty() {
-        eprintln!("Unhandled data on the stack");
-        return;
-    }
+    true
+}
 
-    if verbose {
-        println!("Compilation succeeded in {:?}", start.elapsed());
+impl<'a> CompilerContext<'a> {
+    fn push_op(&mut self, op: Op) {
+        self.push_op_with_span(op, None);
     }
 
-    #[cfg(debug_assertions)]
-    compiler.vm.print_ops();
+    fn push_constant(&mut self, value: Value) {
+        let index = self.vm.add_constant(value);
+        self.push_op(Op::PushConst(index));
+    }
 
-    compiler.vm.run();
-}
+    // For ops that can fail at runtime, so the error can point at the word.
+    fn push_op_at(&mut self, op: Op, token: &Token) {
+        self.push_op_with_span(op, Some(token.span));
+    }
 
-impl<'a> CompilerContext<'a> {
-    fn push_op(&mut self, op: Op) {
+    fn push_op_with_span(&mut self, op: Op, span: Option<Span>) {
+        // every op is pushed while compiling a word, ops without a span of their own get its span
+        let origin = self.origin.unwrap();
         match op {
-            Op::Add|Op::Divide|Op::Subtract|Op::Multiply|Op::Drop|Op::PrintLn => { self.stack.pop(); },
-            Op::Dup => self.stack.push(*self.stack.last().unwrap()),
+            Op::Add => {
+                // ptr + int and int + ptr => ptr, uint + uint => uint, big + int => big
+                let (a, b) = (self.stack[self.stack.len() - 2], self.stack[self.stack.len() - 1]);
+                self.stack.pop_n(2);
+                self.stack.push(if a == DataType::Ptr || b == DataType::Ptr { DataType::Ptr } else { wider(a, b) }, origin);
+            }
+            Op::Subtract => {
+                // ptr - int => ptr, ptr - ptr => int, uint - uint => uint, big - int => big
+                let (a, b) = (self.stack[self.stack.len() - 2], self.stack[self.stack.len() - 1]);
+                self.stack.pop_n(2);
+                self.stack.push(match (a, b) {
+                    (DataType::Ptr, DataType::Ptr) => DataType::Int,
+                    (DataType::Ptr, _) => DataType::Ptr,
+                    _ => wider(a, b),
+                }, origin);
+            }
+            Op::Syscall(count) => {
+                // args number => result
+                self.stack.pop_n(count + 1);
+                self.stack.push(DataType::Int, origin);
+            }
+            Op::CallBuiltin(index) => {
+                // args => results
+                let (params, returns) = self.vm.builtin_signature(index);
+                let (param_count, returns) = (params.len(), returns.to_vec());
+                self.stack.pop_n(param_count);
+                for data_type in returns {
+                    self.stack.push(data_type, origin);
+                }
+            }
+            Op::CallExtern(index) => {
+                // args => result?
+                let function = self.vm.extern_function(index);
+                let (param_count, returns) = (function.params.len(), function.returns);
+                self.stack.pop_n(param_count);
+                if let Some(data_type) = returns {
+                    self.stack.push(data_type, origin);
+                }
+            }
+            Op::Random|Op::NowMs|Op::ClockNs => self.stack.push(DataType::Int, origin),
+            Op::Flush => {}
+            Op::AddConst(_)|Op::SubtractConst(_)|Op::MultiplyConst(_)|Op::DivideConst(_)|Op::MinConst(_)|Op::MaxConst(_)
+                |Op::Square|Op::PrintConst(_) => {
+                unreachable!("ops are only fused once the program is compiled")
+            }
+            Op::TcpAccept => {
+                // int => int
+                self.stack.pop();
+                self.stack.push(DataType::Int, origin);
+            }
+            Op::Abs => {
+                // int => int, big => big
+                let data_type = self.stack[self.stack.len() - 1];
+                self.stack.pop();
+                self.stack.push(data_type, origin);
+            }
+            Op::Pow|Op::ShiftLeft|Op::ShiftRight => {
+                // int int => int, big int => big for pow, uint int => uint for the shifts
+                let data_type = self.stack[self.stack.len() - 2];
+                self.stack.pop_n(2);
+                self.stack.push(data_type, origin);
+            }
+            Op::CheckedAdd|Op::CheckedSubtract|Op::CheckedMultiply => {
+                // int int => int bool, uint uint => uint bool
+                let data_type = self.stack[self.stack.len() - 2];
+                self.stack.pop_n(2);
+                self.stack.push(data_type, origin);
+                self.stack.push(DataType::Bool, origin);
+            }
+            Op::SaturatingAdd|Op::SaturatingSubtract|Op::SaturatingMultiply => {
+                // int int => int, uint uint => uint
+                let data_type = self.stack[self.stack.len() - 2];
+                self.stack.pop_n(2);
+                self.stack.push(data_type, origin);
+            }
+            Op::DivMod => {
+                // int int => int int, and the same for uints and bigs
+                let (a, b) = (self.stack[self.stack.len() - 2], self.stack[self.stack.len() - 1]);
+                self.stack.pop_n(2);
+                self.stack.push(wider(a, b), origin);
+                self.stack.push(wider(a, b), origin);
+            }
+            Op::Divide|Op::Multiply|Op::Min|Op::Max => {
+                // int int => int, uint uint => uint, big and int or big => big
+                let (a, b) = (self.stack[self.stack.len() - 2], self.stack[self.stack.len() - 1]);
+                self.stack.pop_n(2);
+                self.stack.push(wider(a, b), origin);
+            }
+            Op::Drop|Op::RandomRange|Op::Sleep|Op::TcpClose|Op::Free|Op::PrintLn|Op::EPrintLn|Op::Store(_) => self.stack.pop(),
+            Op::Alloc => {
+                // count => ptr
+                self.stack.pop();
+                self.stack.push(DataType::Ptr, origin);
+            }
+            Op::Realloc => {
+                // ptr count => ptr
+                self.stack.pop_n(2);
+                self.stack.push(DataType::Ptr, origin);
+            }
+            Op::CharAt|Op::Concat => {
+                // str index => str
+                // str str => str
+                self.stack.pop_n(2);
+                self.stack.push(DataType::Str, origin);
+            }
+            Op::Substring => {
+                // str start length => str
+                self.stack.pop_n(3);
+                self.stack.push(DataType::Str, origin);
+            }
+            Op::Length|Op::TcpConnect|Op::TcpListen => {
+                // str => int
+                self.stack.pop();
+                self.stack.push(DataType::Int, origin);
+            }
+            Op::IntToStr|Op::TcpRead => {
+                // int => str
+                self.stack.pop();
+                self.stack.push(DataType::Str, origin);
+            }
+            Op::GetEnv => {
+                // str => str bool
+                self.stack.pop();
+                self.stack.push(DataType::Str, origin);
+                self.stack.push(DataType::Bool, origin);
+            }
+            Op::Exec => {
+                // str => str int
+                self.stack.pop();
+                self.stack.push(DataType::Str, origin);
+                self.stack.push(DataType::Int, origin);
+            }
+            Op::StrToInt|Op::BigToInt => {
+                // str => int bool
+                // big => int bool
+                self.stack.pop();
+                self.stack.push(DataType::Int, origin);
+                self.stack.push(DataType::Bool, origin);
+            }
+            Op::Format(count) => {
+                // values... format => str
+                self.stack.pop_n(count + 1);
+                self.stack.push(DataType::Str, origin);
+            }
+            Op::Compare => {
+                // str str => int
+                self.stack.pop_n(2);
+                self.stack.push(DataType::Int, origin);
+            }
+            Op::Equal => {
+                self.stack.pop_n(2);
+                self.stack.push(DataType::Bool, origin);
+            }
+            Op::Cast(data_type) => {
+                self.stack.pop();
+                self.stack.push(data_type, origin);
+            }
+            Op::Dup => self.stack.copy_to_top(0),
+            Op::Load(address) => self.stack.push(self.vm.global_type(address), origin),
+            Op::LoadIndexed(array) => {
+                // index => element
+                self.stack.pop();
+                self.stack.push(self.vm.global_type(array.address), origin);
+            }
+            Op::LoadPtr(data_type) => {
+                // ptr => value
+                self.stack.pop();
+                self.stack.push(data_type, origin);
+            }
+            Op::StoreIndexed(_)|Op::StorePtr(_)|Op::TcpWrite => {
+                // value index =>
+                // value ptr =>
+                // str handle =>
+                self.stack.pop_n(2);
+            }
             Op::Over => {
                 // a b => a b a
-                self.stack.push(self.stack[self.stack.len() - 2]);
+                self.stack.copy_to_top(1);
             }
-            Op::Push(value) => self.stack.push(value.data_type),
+            Op::PushConst(index) => self.stack.push(self.vm.constant(index).data_type(), origin),
             Op::Rot => {
                 // a b c => b c a
-                let v = self.stack.remove(self.stack.len() - 3);
-                self.stack.push(v);
+                self.stack.move_to_top(2);
             }
             Op::Swap => {
                 // a b => b a
-                let v = self.stack.remove(self.stack.len() - 2);
-                self.stack.push(v);
+                self.stack.move_to_top(1);
             }
         };
 
-        self.vm.push_op(op);
+        self.vm.push_op(op, Some(span.unwrap_or(origin)));
     }
 }
 
-fn get_code_at_line(line: usize, code_string: &String) -> String {
-    let mut curr_line = 1usize;
-    let mut str_index = 0usize;
-    let code_bytes = code_string.as_bytes();
+fn int(token: &Token, compiler: &mut CompilerContext) {
+    match token.text.parse::<i64>() {
+        Ok(value) => compiler.push_constant(Value::from_int(value)),
+        Err(parse_error) => invalid_integer(token, compiler, parse_error.kind()),
+    }
+}
 
-    while curr_line < line {
-        if code_bytes[str_index] as char == '\n' {
-            curr_line += 1;
-        }
-        str_index += 1;
+fn uint(token: &Token, compiler: &mut CompilerContext) {
+    // without the 'u' suffix
+    match token.text[..token.text.len() - 1].parse::<u64>() {
+        Ok(value) => compiler.push_constant(Value::UInt(value)),
+        Err(parse_error) => invalid_integer(token, compiler, parse_error.kind()),
     }
+}
 
-    let code_substr = &code_string.as_str()[str_index..];
-    code_substr[0..code_substr.find('\n').unwrap()].to_string()
+fn big(token: &Token, compiler: &mut CompilerContext) {
+    let big = BigInt::parse(&token.text[..token.text.len() - 1]).expect("the scanner only makes big literals of digits");
+    let index = compiler.vm.add_big(big);
+    compiler.push_constant(Value::Big(index));
 }
 
-fn int(token: &Token, compiler: &mut CompilerContext) {
-    let parse_result = token.text.parse::<i64>();
-    if parse_result.is_err() {
-        error(token, compiler, match parse_result.err().unwrap().kind() {
-            IntErrorKind::Empty => "tried to parse int from empty string",
-            IntErrorKind::InvalidDigit => "invalid digit found in string",
-            IntErrorKind::PosOverflow => "positive integer out of range",
-            IntErrorKind::NegOverflow => "negative integer out of range",
-            IntErrorKind::Zero => "value cannot be zero",
-            _ => "unexpected error"
-        }.to_string());
-        return;
-    }
+fn invalid_integer(token: &Token, compiler: &mut CompilerContext, kind: &IntErrorKind) {
+    error(token, compiler, ErrorCode::InvalidInteger, match kind {
+        IntErrorKind::Empty => "tried to parse int from empty string",
+        IntErrorKind::InvalidDigit => "invalid digit found in string",
+        IntErrorKind::PosOverflow => "positive integer out of range",
+        IntErrorKind::NegOverflow => "negative integer out of range",
+        IntErrorKind::Zero => "value cannot be zero",
+        _ => "unexpected error"
+    }.to_string());
+}
 
-    compiler.push_op(Op::Push(Value::from_int(parse_result.unwrap())));
+fn string(token: &Token, compiler: &mut CompilerContext) {
+    if let Some(string) = string_literal(token, compiler) {
+        let index = compiler.vm.add_string(string);
+        compiler.push_constant(Value::from_string_index(index));
+    }
 }
 
-fn add(token: &Token, compiler: &mut CompilerContext) {
-    let len = compiler.stack.len();
-    if len < 2 {
-        error(token, compiler, format!("expected 2 values on the stack to perform addition, found {}", len));
-        return;
+fn string_literal(token: &Token, compiler: &mut CompilerContext) -> Option<String> {
+    let (prefix, contents, raw) = scanner::string_contents(token.text);
+    // a string over several lines written on Windows still has plain '\n' line breaks
+    if raw {
+        return Some(contents.replace("\r\n", "\n"));
     }
-    if compiler.stack[len - 1] != DataType::Int {
-        error(token, compiler, format!("expected integer on top of the stack to perform addition, found {}", compiler.stack[len - 1]));
-        return;
+
+    match unescape(contents) {
+        Ok(string) => Some(string),
+        Err((range, message)) => {
+            emit(compiler, Diagnostic::error(ErrorCode::InvalidToken, escape_span(token, prefix, contents, range), message));
+            None
+        }
     }
-    if compiler.stack[len - 2] != DataType::Int {
-        error(token, compiler, format!("expected integer one down from the top of the stack to perform addition, found {}", compiler.stack[len - 2]));
-        return;
+}
+
+// Replaces the escapes in a string literal's contents, or returns where in the contents
+// the first invalid escape is, in bytes, with what is wrong with it.
+fn unescape(contents: &str) -> Result<String, (Range<usize>, String)> {
+    let mut string = String::with_capacity(contents.len());
+    let mut chars = contents.char_indices().peekable();
+    while let Some((offset, c)) = chars.next() {
+        if c == '\r' && chars.peek().is_some_and(|(_, next)| *next == '\n') {
+            continue;
+        }
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+
+        let escaped = match chars.next() {
+            Some((_, 'n')) => Ok('\n'),
+            Some((_, 't')) => Ok('\t'),
+            Some((_, 'r')) => Ok('\r'),
+            Some((_, '0')) => Ok('\0'),
+            Some((_, '"')) => Ok('"'),
+            Some((_, '\\')) => Ok('\\'),
+            Some((_, 'x')) => {
+                // exactly two hex digits, no higher than 7f so the result is a whole character
+                let digits: String = (0..2).filter_map(|_| chars.next_if(|(_, c)| c.is_ascii_hexdigit()).map(|(_, c)| c)).collect();
+                match u8::from_str_radix(&digits, 16) {
+                    Ok(byte) if digits.len() == 2 && byte.is_ascii() => Ok(byte as char),
+                    Ok(_) if digits.len() == 2 => Err("'\\x' escapes only go up to '\\x7f', use '\\u{...}' for other characters".to_string()),
+                    _ => Err("expected two hex digits after '\\x'".to_string()),
+                }
+            }
+            Some((_, 'u')) => {
+                let mut digits = String::new();
+                let closed = chars.next_if(|(_, c)| *c == '{').is_some() && {
+                    while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_hexdigit()) {
+                        digits.push(c);
+                    }
+                    chars.next_if(|(_, c)| *c == '}').is_some()
+                };
+                match u32::from_str_radix(&digits, 16).ok().filter(|_| closed && digits.len() <= 6).map(char::from_u32) {
+                    Some(Some(c)) => Ok(c),
+                    Some(None) => Err(format!("'{}' is not a unicode character", digits)),
+                    None => Err("expected one to six hex digits in braces after '\\u', like '\\u{e9}'".to_string()),
+                }
+            }
+            Some((_, other)) => Err(format!("unknown escape sequence '\\{}' in string", other)),
+            None => Err("unfinished escape sequence in string".to_string()),
+        };
+
+        match escaped {
+            Ok(escaped) => string.push(escaped),
+            // the escape itself rather than the whole string
+            Err(message) => return Err((offset..chars.peek().map_or(contents.len(), |(end, _)| *end), message)),
+        }
     }
 
-    compiler.push_op(Op::Add);
+    Ok(string)
 }
 
-fn subtract(token: &Token, compiler: &mut CompilerContext) {
-    let len = compiler.stack.len();
-    if len < 2 {
-        error(token, compiler, format!("expected 2 values on the stack to perform subtraction, found {}", len));
-        return;
+// Where a range of a string literal's contents is in the source, prefix being how many
+// bytes of the token come before its contents.
+fn escape_span(token: &Token, prefix: usize, contents: &str, range: Range<usize>) -> Span {
+    let before = &contents[..range.start];
+    let (line, column) = match before.rfind('\n') {
+        Some(newline) => (token.span.line + before.matches('\n').count(), before[newline + 1..].chars().count() + 1),
+        None => (token.span.line, token.span.column + prefix + before.chars().count()),
+    };
+    Span {
+        start: token.span.start + prefix + range.start,
+        end: token.span.start + prefix + range.end,
+        line,
+        column,
+        file: token.span.file,
     }
-    if compiler.stack[len - 1] != DataType::Int {
-        error(token, compiler, format!("expected integer on top of the stack to perform subtraction, found {}", compiler.stack[len - 1]));
+}
+
+fn string_op<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>, op: Op, action: &str, expected: &[DataType]) {
+    if !check_stack(token, compiler, action, expected) {
         return;
     }
-    if compiler.stack[len - 2] != DataType::Int {
-        error(token, compiler, format!("expected integer one down from the top of the stack to perform subtraction, found {}", compiler.stack[len - 2]));
-        return;
+
+    // indexing ops can go out of bounds, commands can fail to start, and
+    // environment and command access need permission
+    if matches!(op, Op::CharAt|Op::Substring|Op::Exec|Op::GetEnv) {
+        compiler.push_op_at(op, token);
+    } else {
+        compiler.push_op(op);
+    }
+}
+
+fn format(token: &Token, compiler: &mut CompilerContext) {
+    // the format string has to be a literal so that the number of values it takes is known here
+    let format_string = match compiler.vm.last_op() {
+        Some(Op::PushConst(index)) if compiler.vm.constant(*index).data_type() == DataType::Str => compiler.vm.constant(*index),
+        _ => {
+            error(token, compiler, ErrorCode::FormatString, "format must directly follow a string literal, e.g. '1 2 \"{} {}\" format'".to_string());
+            return;
+        }
+    };
+
+    let count = match vm::count_placeholders(compiler.vm.string_constant(format_string)) {
+        Ok(count) => count,
+        Err(message) => {
+            error(token, compiler, ErrorCode::FormatString, message);
+            return;
+        }
+    };
+
+    let available = compiler.stack.len() - 1;
+    if available < count {
+        error(token, compiler, ErrorCode::FormatString, format!("format string has {} placeholders but only {} values are on the stack below it", count, available));
+        return;
+    }
+
+    compiler.push_op(Op::Format(count));
+}
+
+fn check_stack(token: &Token, compiler: &mut CompilerContext, action: &str, expected: &[DataType]) -> bool {
+    // expected types are listed bottom to top, the same order the values are pushed in
+    let len = compiler.stack.len();
+    if len < expected.len() {
+        error(token, compiler, ErrorCode::StackUnderflow, format!("expected {} values on the stack to {}, found {}", expected.len(), action, len));
+        return false;
+    }
+
+    for (depth, expected_type) in expected.iter().rev().enumerate() {
+        let found = compiler.stack[len - 1 - depth];
+        if found != *expected_type {
+            let position = match depth {
+                0 => "on top of the stack".to_string(),
+                1 => "one down from the top of the stack".to_string(),
+                2 => "two down from the top of the stack".to_string(),
+                _ => format!("{} down from the top of the stack", depth),
+            };
+            type_error(token, compiler, depth, format!("expected {} {} to {}, found {}", expected_type, position, action, found));
+            return false;
+        }
+    }
+
+    true
+}
+
+fn add(token: &Token, compiler: &mut CompilerContext) {
+    let len = compiler.stack.len();
+    if len < 2 {
+        error(token, compiler, ErrorCode::StackUnderflow, format!("expected 2 values on the stack to perform addition, found {}", len));
+        return;
+    }
+    if !is_numeric(compiler.stack[len - 1]) {
+        type_error(token, compiler, 0, format!("expected integer or pointer on top of the stack to perform addition, found {}", compiler.stack[len - 1]));
+        return;
+    }
+    if !is_numeric(compiler.stack[len - 2]) {
+        type_error(token, compiler, 1, format!("expected integer or pointer one down from the top of the stack to perform addition, found {}", compiler.stack[len - 2]));
+        return;
+    }
+    if compiler.stack[len - 1] == DataType::Ptr && compiler.stack[len - 2] == DataType::Ptr {
+        error(token, compiler, ErrorCode::PointerArithmetic, "cannot add two pointers, subtract them to get the distance between them".to_string());
+        return;
+    }
+    if !check_integer_kinds(token, compiler, "add") {
+        return;
+    }
+
+    compiler.push_op(Op::Add);
+}
+
+fn subtract(token: &Token, compiler: &mut CompilerContext) {
+    let len = compiler.stack.len();
+    if len < 2 {
+        error(token, compiler, ErrorCode::StackUnderflow, format!("expected 2 values on the stack to perform subtraction, found {}", len));
+        return;
+    }
+    if !is_numeric(compiler.stack[len - 1]) {
+        type_error(token, compiler, 0, format!("expected integer or pointer on top of the stack to perform subtraction, found {}", compiler.stack[len - 1]));
+        return;
+    }
+    if !is_numeric(compiler.stack[len - 2]) {
+        type_error(token, compiler, 1, format!("expected integer or pointer one down from the top of the stack to perform subtraction, found {}", compiler.stack[len - 2]));
+        return;
+    }
+    if compiler.stack[len - 1] == DataType::Ptr && compiler.stack[len - 2] == DataType::Int {
+        error(token, compiler, ErrorCode::PointerArithmetic, "cannot subtract a pointer from an integer".to_string());
+        return;
+    }
+    if !check_integer_kinds(token, compiler, "subtract") {
+        return;
     }
     compiler.push_op(Op::Subtract);
 }
 
+// The type of arithmetic on two integers that mix, an int becoming a big next to a big.
+fn wider(a: DataType, b: DataType) -> DataType {
+    if b == DataType::Big { b } else { a }
+}
+
+fn is_numeric(data_type: DataType) -> bool {
+    is_integer(data_type) || data_type == DataType::Ptr
+}
+
+fn is_integer(data_type: DataType) -> bool {
+    matches!(data_type, DataType::Int|DataType::UInt|DataType::Big)
+}
+
+// A uint only meets another uint, mixing it with an int or a pointer needs an explicit
+// conversion so it is clear which way the bits are read. A big meets a big or an int,
+// which it makes a big of.
+fn check_integer_kinds(token: &Token, compiler: &mut CompilerContext, action: &str) -> bool {
+    let len = compiler.stack.len();
+    let (below, top) = (compiler.stack[len - 2], compiler.stack[len - 1]);
+    let (depth, conversion) = match (below, top) {
+        (DataType::UInt, DataType::UInt) => return true,
+        (DataType::UInt, _) => (1, "'int->uint' or 'uint->int'"),
+        (_, DataType::UInt) => (0, "'int->uint' or 'uint->int'"),
+        (DataType::Big, DataType::Big|DataType::Int)|(DataType::Int, DataType::Big) => return true,
+        (DataType::Big, _) => (1, "'int->big' or 'big->int'"),
+        (_, DataType::Big) => (0, "'int->big' or 'big->int'"),
+        _ => return true,
+    };
+
+    let diagnostic = Diagnostic::error(ErrorCode::TypeMismatch, token.span, format!("cannot {} {} and {}", action, below, top))
+        .label(compiler.stack.origin(depth), format!("this is {}", compiler.stack[len - 1 - depth]))
+        .note(format!("convert one of them with {}", conversion));
+    emit(compiler, diagnostic);
+    false
+}
+
+fn equal(token: &Token, compiler: &mut CompilerContext) {
+    let len = compiler.stack.len();
+    if len < 2 {
+        error(token, compiler, ErrorCode::StackUnderflow, format!("expected 2 values on the stack to compare for equality, found {}", len));
+        return;
+    }
+    let (below, top) = (compiler.stack[len - 2], compiler.stack[len - 1]);
+    if below != top {
+        let diagnostic = Diagnostic::error(ErrorCode::TypeMismatch, token.span, format!("cannot compare {} and {} for equality", below, top))
+            .label(compiler.stack.origin(1), format!("this is {}", below))
+            .label(compiler.stack.origin(0), format!("this is {}", top));
+        emit(compiler, diagnostic);
+        return;
+    }
+    compiler.push_op(Op::Equal);
+}
+
 fn multiply(token: &Token, compiler: &mut CompilerContext) {
     let len = compiler.stack.len();
     if len < 2 {
-        error(token, compiler, format!("expected 2 values on the stack to perform multiplication, found {}", len));
+        error(token, compiler, ErrorCode::StackUnderflow, format!("expected 2 values on the stack to perform multiplication, found {}", len));
         return;
     }
-    if compiler.stack[len - 1] != DataType::Int {
-        error(token, compiler, format!("expected integer on top of the stack to perform multiplication, found {}", compiler.stack[len - 1]));
+    if !is_integer(compiler.stack[len - 1]) {
+        type_error(token, compiler, 0, format!("expected integer on top of the stack to perform multiplication, found {}", compiler.stack[len - 1]));
+        return;
+    }
+    if !is_integer(compiler.stack[len - 2]) {
+        type_error(token, compiler, 1, format!("expected integer one down from the top of the stack to perform multiplication, found {}", compiler.stack[len - 2]));
         return;
     }
-    if compiler.stack[len - 2] != DataType::Int {
-        error(token, compiler, format!("expected integer one down from the top of the stack to perform multiplication, found {}", compiler.stack[len - 2]));
+    if !check_integer_kinds(token, compiler, "multiply") {
         return;
     }
     compiler.push_op(Op::Multiply);
 }
 
-fn divide(token: &Token, compiler: &mut CompilerContext) {
+// Div and divmod.
+fn divide<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>, op: Op) {
+    let len = compiler.stack.len();
+    if len < 2 {
+        error(token, compiler, ErrorCode::StackUnderflow, format!("expected 2 values on the stack to perform division, found {}", len));
+        return;
+    }
+    if !is_integer(compiler.stack[len - 1]) {
+        type_error(token, compiler, 0, format!("expected integer on top of the stack to perform division, found {}", compiler.stack[len - 1]));
+        return;
+    }
+    if !is_integer(compiler.stack[len - 2]) {
+        type_error(token, compiler, 1, format!("expected integer one down from the top of the stack to perform division, found {}", compiler.stack[len - 2]));
+        return;
+    }
+    if !check_integer_kinds(token, compiler, "divide") {
+        return;
+    }
+    // a literal zero divisor would fail every time the division runs
+    if let Some(Op::PushConst(index)) = compiler.vm.last_op() {
+        if compiler.vm.constant(*index).is_zero() {
+            let origin = compiler.stack.origin(0);
+            let diagnostic = Diagnostic::error(ErrorCode::DivisionByZero, token.span, "division by a constant zero".to_string())
+                .label(origin, "this divisor is always zero".to_string());
+            emit(compiler, diagnostic);
+            return;
+        }
+    }
+    compiler.push_op_at(op, token);
+}
+
+fn int_op<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>, op: Op, action: &str, arity: usize) {
+    // the value abs takes and the base of pow can also be a big
+    let len = compiler.stack.len();
+    let first = match len.checked_sub(arity).map(|index| compiler.stack[index]) {
+        Some(DataType::Big) => DataType::Big,
+        _ => DataType::Int,
+    };
+    if !check_stack(token, compiler, action, &[first, DataType::Int][..arity]) {
+        return;
+    }
+
+    // these can overflow
+    if matches!(op, Op::Abs|Op::Pow) {
+        compiler.push_op_at(op, token);
+    } else {
+        compiler.push_op(op);
+    }
+}
+
+// Min and max, on two integers of kinds that mix.
+fn integer_op<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>, op: Op, action: &str) {
+    let len = compiler.stack.len();
+    if len < 2 {
+        error(token, compiler, ErrorCode::StackUnderflow, format!("expected 2 values on the stack to {}, found {}", action, len));
+        return;
+    }
+    if !is_integer(compiler.stack[len - 1]) {
+        type_error(token, compiler, 0, format!("expected integer on top of the stack to {}, found {}", action, compiler.stack[len - 1]));
+        return;
+    }
+    if !is_integer(compiler.stack[len - 2]) {
+        type_error(token, compiler, 1, format!("expected integer one down from the top of the stack to {}, found {}", action, compiler.stack[len - 2]));
+        return;
+    }
+    if check_integer_kinds(token, compiler, action) {
+        compiler.push_op(op);
+    }
+}
+
+// The checked and saturating forms of '+', '-' and '*', which only make sense for the
+// integers that can overflow.
+fn overflow_op<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>, op: Op) {
+    let len = compiler.stack.len();
+    if len < 2 {
+        error(token, compiler, ErrorCode::StackUnderflow, format!("expected 2 values on the stack for '{}', found {}", token.text, len));
+        return;
+    }
+    for depth in 0..2 {
+        let found = compiler.stack[len - 1 - depth];
+        if !matches!(found, DataType::Int|DataType::UInt) {
+            let position = if depth == 0 { "on top of the stack" } else { "one down from the top of the stack" };
+            type_error(token, compiler, depth, format!("expected int or uint {} for '{}', found {}", position, token.text, found));
+            return;
+        }
+    }
+    if check_integer_kinds(token, compiler, &format!("use '{}' on", token.text)) {
+        compiler.push_op(op);
+    }
+}
+
+fn shift<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>, op: Op) {
+    // value amount shl|shr => value, where the value is an int or a uint
     let len = compiler.stack.len();
     if len < 2 {
-        error(token, compiler, format!("expected 2 values on the stack to perform division, found {}", len));
+        error(token, compiler, ErrorCode::StackUnderflow, format!("expected value and shift amount on the stack to {}, found {} values", token.text, len));
+        return;
+    }
+    if compiler.stack[len - 1] != DataType::Int {
+        type_error(token, compiler, 0, format!("expected integer shift amount on top of the stack to {}, found {}", token.text, compiler.stack[len - 1]));
+        return;
+    }
+    if !matches!(compiler.stack[len - 2], DataType::Int|DataType::UInt) {
+        type_error(token, compiler, 1, format!("expected int or uint one down from the top of the stack to {}, found {}", token.text, compiler.stack[len - 2]));
+        return;
+    }
+
+    // the amount can be out of range
+    compiler.push_op_at(op, token);
+}
+
+fn net_op<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>, op: Op, action: &str, expected: &[DataType]) {
+    // sockets are int handles, any of these can fail at runtime
+    if !check_stack(token, compiler, action, expected) {
+        return;
+    }
+    compiler.push_op_at(op, token);
+}
+
+fn syscall<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>) {
+    // arg* number syscallN => result
+    if !compiler.allow_unsafe {
+        let diagnostic = Diagnostic::error(ErrorCode::RequiresUnsafe, token.span, format!("'{}' can only be used when running with --unsafe", token.text))
+            .note("syscalls are not limited by the permission flags".to_string());
+        emit(compiler, diagnostic);
+        return;
+    }
+    if cfg!(not(target_os = "linux")) {
+        error(token, compiler, ErrorCode::Unsupported, "syscalls are only supported on linux".to_string());
+        return;
+    }
+
+    let count = token.text["syscall".len()..].parse::<usize>().unwrap();
+    let len = compiler.stack.len();
+    if len < count + 1 {
+        error(token, compiler, ErrorCode::StackUnderflow, format!("expected {} values on the stack to perform {}, found {}", count + 1, token.text, len));
         return;
     }
     if compiler.stack[len - 1] != DataType::Int {
-        error(token, compiler, format!("expected integer on top of the stack to perform division, found {}", compiler.stack[len - 1]));
+        type_error(token, compiler, 0, format!("expected syscall number on top of the stack, found {}", compiler.stack[len - 1]));
+        return;
+    }
+    if let Some(index) = compiler.stack.types()[len - 1 - count..len - 1].iter().rposition(|data_type| *data_type == DataType::Ptr) {
+        // px2 pointers are cell addresses, the kernel has no way to use them
+        type_error(token, compiler, count - index, format!("syscall argument {} is a ptr, only int, bool and str can be passed", index + 1));
+        return;
+    }
+
+    compiler.push_op_at(Op::Syscall(count), token);
+}
+
+fn random_range<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>) {
+    // low high => int in [low, high)
+    if !check_stack(token, compiler, "pick a random number in a range", &[DataType::Int, DataType::Int]) {
         return;
     }
-    if compiler.stack[len - 2] != DataType::Int {
-        error(token, compiler, format!("expected integer one down from the top of the stack to perform division, found {}", compiler.stack[len - 2]));
+    compiler.push_op_at(Op::RandomRange, token);
+}
+
+fn sleep<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>) {
+    // milliseconds =>
+    if !check_stack(token, compiler, "sleep", &[DataType::Int]) {
         return;
     }
-    compiler.push_op(Op::Divide);
+    compiler.push_op_at(Op::Sleep, token);
 }
 
-fn println(token: &Token, compiler: &mut CompilerContext) {
+// op is PrintLn or EPrintLn, which only differ in where they print to.
+fn println(token: &Token, compiler: &mut CompilerContext, op: Op) {
     if compiler.stack.is_empty() {
-        error(token, compiler, "nothing on stack to print".to_string());
+        error(token, compiler, ErrorCode::StackUnderflow, "nothing on stack to print".to_string());
         return;
     }
-    compiler.push_op(Op::PrintLn);
+    compiler.push_op_at(op, token);
 }
 
-fn error(token: &Token, compiler: &mut CompilerContext, message: String) {
-    compiler.had_error = true;
-    eprintln!("{} at '{}': {}", "Compiler Error".red(), token.text, message);
-    eprintln!("       --> {}:{}:{}", compiler.file_path, token.line, token.column);
-    eprintln!("        |");
-    eprintln!("{:7} | {}", token.line, get_code_at_line(token.line, compiler.code_string));
-    eprint!("        | ");
+fn public_declaration<'a>(scanner: &mut Scanner<'a>, compiler: &mut CompilerContext<'a>) {
+    // pub var|array|struct|enum|extern|data ...
+    let token = scanner.scan_token();
+    match token.token_type {
+        TokenType::Array => array_declaration(scanner, compiler, true),
+        TokenType::Data => data_declaration(scanner, compiler, true),
+        TokenType::Enum => enum_declaration(scanner, compiler, true),
+        TokenType::Extern => extern_declaration(scanner, compiler, true),
+        TokenType::Struct => struct_declaration(scanner, compiler, true),
+        TokenType::Var => var_declaration(scanner, compiler, true),
+        _ => error(&token, compiler, ErrorCode::UnexpectedToken, format!("expected var, array, struct, enum, extern or data after 'pub', found '{}'", token.text)),
+    }
+}
+
+fn attributed_declaration<'a>(token: &Token<'a>, scanner: &mut Scanner<'a>, compiler: &mut CompilerContext<'a>) {
+    // (#deprecated "<reason>"? | #allow-unused)... followed by the declaration they describe
+    let mut attributes = Attributes::default();
+    let mut token = *token;
+    while token.token_type == TokenType::Attribute {
+        match token.text {
+            "#deprecated" => {
+                token = scanner.scan_token();
+                let reason = if token.token_type == TokenType::String {
+                    let reason = match string_literal(&token, compiler) {
+                        Some(reason) => reason,
+                        None => return,
+                    };
+                    token = scanner.scan_token();
+                    reason
+                } else {
+                    String::new()
+                };
+                attributes.deprecated = Some(reason);
+            }
+            "#allow-unused" => {
+                attributes.allow_unused = true;
+                token = scanner.scan_token();
+            }
+            _ => {
+                let diagnostic = Diagnostic::error(ErrorCode::UnknownName, token.span, format!("unknown attribute '{}'", token.text))
+                    .help("the attributes are '#deprecated' and '#allow-unused'".to_string());
+                emit(compiler, diagnostic);
+                return;
+            }
+        }
+    }
+
+    compiler.attributes = attributes;
+    match token.token_type {
+        TokenType::Pub => public_declaration(scanner, compiler),
+        TokenType::Array => array_declaration(scanner, compiler, false),
+        TokenType::Data => data_declaration(scanner, compiler, false),
+        TokenType::Enum => enum_declaration(scanner, compiler, false),
+        TokenType::Extern => extern_declaration(scanner, compiler, false),
+        TokenType::Struct => struct_declaration(scanner, compiler, false),
+        TokenType::Var => var_declaration(scanner, compiler, false),
+        _ => error(&token, compiler, ErrorCode::UnexpectedToken, format!("expected var, array, struct, enum, extern or data after an attribute, found '{}'", token.text)),
+    }
+    compiler.attributes = Attributes::default();
+}
+
+fn var_declaration<'a>(scanner: &mut Scanner<'a>, compiler: &mut CompilerContext<'a>, public: bool) {
+    // var <name> <type>
+    let name = scanner.scan_token();
+    if !check_symbol_name(&name, compiler, "variable") {
+        return;
+    }
+
+    let type_token = scanner.scan_token();
+    let data_type = match data_type(&type_token) {
+        Some(data_type) => data_type,
+        None => {
+            error(&type_token, compiler, ErrorCode::UnexpectedToken, format!("expected type of variable '{}', found '{}'", name.text, type_token.text));
+            return;
+        }
+    };
+
+    let address = compiler.vm.allocate_global(data_type);
+    define(compiler, &name, Symbol::Variable(address), public);
+}
+
+fn array_declaration<'a>(scanner: &mut Scanner<'a>, compiler: &mut CompilerContext<'a>, public: bool) {
+    // array <length> <type> as <name>
+    let length_token = scanner.scan_token();
+    let length = match length_token.text.parse::<usize>() {
+        Ok(length) if length_token.token_type == TokenType::Int && length > 0 => length,
+        _ => {
+            error(&length_token, compiler, ErrorCode::UnexpectedToken, format!("expected array length greater than zero, found '{}'", length_token.text));
+            return;
+        }
+    };
+
+    let type_token = scanner.scan_token();
+    let data_type = match data_type(&type_token) {
+        Some(data_type) => data_type,
+        None => {
+            error(&type_token, compiler, ErrorCode::UnexpectedToken, format!("expected element type of array, found '{}'", type_token.text));
+            return;
+        }
+    };
+
+    if let Err(token) = scanner.expect(TokenType::As) {
+        error(&token, compiler, ErrorCode::UnexpectedToken, format!("expected 'as' after array element type, found '{}'", token.text));
+        return;
+    }
+
+    let name = scanner.scan_token();
+    if !check_symbol_name(&name, compiler, "array") {
+        return;
+    }
+
+    let array = compiler.vm.allocate_array(data_type, length);
+    define(compiler, &name, Symbol::Array(array), public);
+}
+
+fn data_declaration<'a>(scanner: &mut Scanner<'a>, compiler: &mut CompilerContext<'a>, public: bool) {
+    // data <name>
+    // <lines>
+    // end
+    let name = scanner.scan_token();
+    if !check_symbol_name(&name, compiler, "data block") {
+        return;
+    }
+
+    let body = scanner.scan_token();
+    match body.token_type {
+        TokenType::DataBody => {}
+        TokenType::UnterminatedData => {
+            error(&name, compiler, ErrorCode::MissingEnd, format!("data block '{}' is missing an 'end' line", name.text));
+            return;
+        }
+        _ => {
+            error(&body, compiler, ErrorCode::UnexpectedToken, format!("expected data block '{}' to start on the line after its name, found '{}'", name.text, body.text));
+            return;
+        }
+    }
+    // the body token stops at the 'end' line
+    scanner.scan_token();
+
+    // the line break before 'end' is not part of the data
+    let text = body.text.replace("\r\n", "\n");
+    let text = text.strip_suffix('\n').unwrap_or(&text).to_string();
+    let bytes = compiler.vm.allocate_data(text.as_bytes());
+    let index = compiler.vm.add_string(text);
+    define(compiler, &name, Symbol::Data(index, bytes), public);
+}
+
+fn struct_declaration<'a>(scanner: &mut Scanner<'a>, compiler: &mut CompilerContext<'a>, public: bool) {
+    // struct <name> (<type> <field>)* end
+    let name = scanner.scan_token();
+    if !check_symbol_name(&name, compiler, "struct") {
+        return;
+    }
+
+    let mut fields = Vec::<(Token<'a>, DataType)>::new();
+    loop {
+        let type_token = scanner.scan_token();
+        if type_token.token_type == TokenType::End {
+            break;
+        }
+
+        let data_type = match data_type(&type_token) {
+            Some(data_type) => data_type,
+            None => {
+                error(&type_token, compiler, ErrorCode::UnexpectedToken, format!("expected field type or 'end' in struct '{}', found '{}'", name.text, type_token.text));
+                return;
+            }
+        };
+
+        let field = scanner.scan_token();
+        if !check_name(&field, compiler, "field") {
+            return;
+        }
+        if let Some((first, _)) = fields.iter().find(|(first, _)| first.text == field.text) {
+            already_defined(&field, compiler, first.span, format!("field '{}' is already defined in struct '{}'", field.text, name.text));
+            return;
+        }
+
+        fields.push((field, data_type));
+    }
+
+    if fields.is_empty() {
+        error(&name, compiler, ErrorCode::EmptyDeclaration, format!("struct '{}' must have at least one field", name.text));
+        return;
+    }
+
+    let fields = fields.into_iter().map(|(field, data_type)| (field.text, data_type)).collect();
+    compiler.structs.push(StructDef { fields });
+    let index = compiler.structs.len() - 1;
+    define(compiler, &name, Symbol::Struct(index), public);
+}
+
+fn enum_declaration<'a>(scanner: &mut Scanner<'a>, compiler: &mut CompilerContext<'a>, public: bool) {
+    // enum <name> <variant>* end
+    let name = scanner.scan_token();
+    if !check_symbol_name(&name, compiler, "enum") {
+        return;
+    }
+
+    let mut variants = Vec::<Token<'a>>::new();
+    loop {
+        let variant = scanner.scan_token();
+        if variant.token_type == TokenType::End {
+            break;
+        }
 
-    for _ in 0..token.column - 1{
-        eprint!(" ");
+        if !check_name(&variant, compiler, "variant") {
+            return;
+        }
+        if let Some(first) = variants.iter().find(|first| first.text == variant.text) {
+            already_defined(&variant, compiler, first.span, format!("variant '{}' is already defined in enum '{}'", variant.text, name.text));
+            return;
+        }
+
+        variants.push(variant);
+    }
+
+    if variants.is_empty() {
+        error(&name, compiler, ErrorCode::EmptyDeclaration, format!("enum '{}' must have at least one variant", name.text));
+        return;
+    }
+
+    let variants = variants.iter().map(|variant| variant.text).collect();
+    compiler.enums.push(EnumDef { variants });
+    let index = compiler.enums.len() - 1;
+    define(compiler, &name, Symbol::Enum(index), public);
+}
+
+fn extern_declaration<'a>(scanner: &mut Scanner<'a>, compiler: &mut CompilerContext<'a>, public: bool) {
+    // extern "<library>" <name> <type>* -- <type>? end
+    let library = scanner.scan_token();
+    if !compiler.allow_unsafe {
+        let diagnostic = Diagnostic::error(ErrorCode::RequiresUnsafe, library.span, "'extern' can only be used when running with --unsafe".to_string())
+            .note("C code can do anything, so it is not limited by the permission flags".to_string());
+        emit(compiler, diagnostic);
+        return;
+    }
+    if library.token_type != TokenType::String {
+        error(&library, compiler, ErrorCode::UnexpectedToken, format!("expected library path after 'extern', found '{}'", library.text));
+        return;
+    }
+    let library_path = match string_literal(&library, compiler) {
+        Some(library_path) => library_path,
+        None => return,
+    };
+
+    let name = scanner.scan_token();
+    if !check_symbol_name(&name, compiler, "extern") {
+        return;
+    }
+
+    let mut params = Vec::<DataType>::new();
+    loop {
+        let type_token = scanner.scan_token();
+        if type_token.token_type == TokenType::DoubleDash {
+            break;
+        }
+        match data_type(&type_token) {
+            Some(data_type) if check_extern_type(&type_token, compiler, data_type) => params.push(data_type),
+            Some(_) => return,
+            None => {
+                error(&type_token, compiler, ErrorCode::UnexpectedToken, format!("expected argument type or '--' in extern '{}', found '{}'", name.text, type_token.text));
+                return;
+            }
+        }
+    }
+
+    if params.len() > ffi::MAX_ARGS {
+        error(&name, compiler, ErrorCode::ExternSignature, format!("extern '{}' takes {} arguments, at most {} are supported", name.text, params.len(), ffi::MAX_ARGS));
+        return;
     }
 
-    for _ in 0..token.length {
-        eprint!("{}", "^".red());
+    let mut returns = None;
+    if scanner.peek().token_type != TokenType::End {
+        let type_token = scanner.scan_token();
+        match data_type(&type_token) {
+            Some(data_type) if check_extern_type(&type_token, compiler, data_type) => returns = Some(data_type),
+            Some(_) => return,
+            None => {
+                error(&type_token, compiler, ErrorCode::UnexpectedToken, format!("expected return type or 'end' in extern '{}', found '{}'", name.text, type_token.text));
+                return;
+            }
+        }
     }
 
-    eprintln!();
+    if let Err(token) = scanner.expect(TokenType::End) {
+        error(&token, compiler, ErrorCode::UnexpectedToken, format!("expected 'end' after the return type of extern '{}', found '{}'", name.text, token.text));
+        return;
+    }
+
+    let address = match ffi::load(&library_path, name.text) {
+        Ok(address) => address,
+        Err(message) => {
+            error(&name, compiler, ErrorCode::ExternLoad, message);
+            return;
+        }
+    };
+
+    let index = compiler.vm.add_extern(ExternFunction {
+        name: name.text.to_string(),
+        address,
+        params,
+        returns,
+    });
+    define(compiler, &name, Symbol::Extern(index), public);
+}
+
+fn check_extern_type(token: &Token, compiler: &mut CompilerContext, data_type: DataType) -> bool {
+    if data_type == DataType::Ptr {
+        // px2 pointers are cell addresses, C has no way to use them
+        error(token, compiler, ErrorCode::ExternSignature, "ptr cannot be passed to or returned from an extern function".to_string());
+        return false;
+    }
+    if data_type == DataType::Big {
+        error(token, compiler, ErrorCode::ExternSignature, "big cannot be passed to or returned from an extern function".to_string());
+        return false;
+    }
+
+    true
+}
+
+fn call_extern<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>, index: usize) {
+    if compiler.comptime.is_some() {
+        error(token, compiler, ErrorCode::NotAllowedInComptime, format!("extern '{}' cannot be called inside a comptime block", token.text));
+        return;
+    }
+
+    let function = compiler.vm.extern_function(index);
+    let action = format!("call '{}'", function.name);
+    let params = function.params.clone();
+    if !check_stack(token, compiler, &action, &params) {
+        return;
+    }
+
+    // strings can fail to convert to C strings
+    compiler.push_op_at(Op::CallExtern(index), token);
+}
+
+fn call_builtin<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>, index: usize) {
+    if compiler.comptime.is_some() {
+        error(token, compiler, ErrorCode::NotAllowedInComptime, format!("builtin '{}' cannot be called inside a comptime block", token.text));
+        return;
+    }
+
+    let params = compiler.vm.builtin_signature(index).0.to_vec();
+    if !check_stack(token, compiler, &format!("call '{}'", token.text), &params) {
+        return;
+    }
+
+    // builtins can fail, or return something other than what they declared
+    compiler.push_op_at(Op::CallBuiltin(index), token);
+}
+
+fn include<'a>(token: &Token<'a>, scanner: &mut Scanner<'a>, compiler: &mut CompilerContext<'a>) {
+    // include "<path>", the file was already loaded and compiled before this one
+    if !compiler.modules.is_empty() {
+        error(token, compiler, ErrorCode::MisplacedWord, "'include' can only be used outside of modules".to_string());
+        return;
+    }
+    scanner.scan_token();
+}
+
+fn when_start<'a>(token: &Token<'a>, scanner: &mut Scanner<'a>, compiler: &mut CompilerContext<'a>) {
+    // when <name> ... [else ...] end, only one of the branches is compiled
+    let name = scanner.scan_token();
+    if name.token_type != TokenType::Identifier {
+        error(&name, compiler, ErrorCode::UnexpectedToken, format!("expected a name to test after 'when', found '{}'", name.text));
+        return;
+    }
+
+    if condition::is_defined(name.text, compiler.defines) {
+        compiler.whens.push(When { token: *token, modules: compiler.modules.len(), in_else: false });
+        return;
+    }
+    let closing = condition::skip_branch(scanner);
+    match closing.token_type {
+        // the else branch is compiled, and closed by the next 'end'
+        TokenType::Else => compiler.whens.push(When { token: *token, modules: compiler.modules.len(), in_else: true }),
+        TokenType::End => {}
+        _ => error(token, compiler, ErrorCode::MissingEnd, "when block is missing 'end'".to_string()),
+    }
+}
+
+fn when_else<'a>(token: &Token<'a>, scanner: &mut Scanner<'a>, compiler: &mut CompilerContext<'a>) {
+    // reached at the end of a branch that was compiled, so the else branch is skipped
+    let when = match innermost_when(compiler) {
+        Some(when) if compiler.comptime.is_none() => when,
+        _ => {
+            error(token, compiler, ErrorCode::MisplacedWord, "'else' without a matching 'when'".to_string());
+            return;
+        }
+    };
+    if compiler.whens.last().is_some_and(|when| when.in_else) {
+        error(token, compiler, ErrorCode::UnexpectedToken, "a when block can only have one 'else'".to_string());
+        return;
+    }
+
+    let closing = condition::skip_branch(scanner);
+    match closing.token_type {
+        TokenType::End => {
+            compiler.whens.pop();
+        }
+        TokenType::Else => error(&closing, compiler, ErrorCode::UnexpectedToken, "a when block can only have one 'else'".to_string()),
+        _ => error(&when, compiler, ErrorCode::MissingEnd, "when block is missing 'end'".to_string()),
+    }
+}
+
+// The when block an 'end' here would close, if the innermost open block is one.
+fn innermost_when<'a>(compiler: &CompilerContext<'a>) -> Option<Token<'a>> {
+    compiler.whens.last().filter(|when| when.modules == compiler.modules.len()).map(|when| when.token)
+}
+
+fn module_declaration<'a>(scanner: &mut Scanner<'a>, compiler: &mut CompilerContext<'a>) {
+    // module <name> ... end, modules can be nested and reopened
+    let name = scanner.scan_token();
+    if !check_name(&name, compiler, "module") {
+        return;
+    }
+
+    let path = qualify(compiler, name.text);
+    compiler.module_paths.insert(path);
+    compiler.modules.push(name);
+}
+
+fn comptime_start<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>) {
+    // the block starts on an empty stack so it can only use values it computes itself
+    let outer_stack = std::mem::take(&mut compiler.stack);
+    compiler.comptime = Some(Comptime {
+        token: *token,
+        start: compiler.vm.op_count(),
+        outer_stack,
+    });
+}
+
+fn comptime_end(token: &Token, compiler: &mut CompilerContext) {
+    let comptime = compiler.comptime.take().unwrap();
+    let results = compiler.vm.evaluate_from(comptime.start, COMPTIME_FUEL);
+
+    compiler.stack = comptime.outer_stack;
+    match results {
+        Ok(values) => {
+            // the block's results were all pushed by the block as a whole
+            compiler.origin = Some(comptime.token.span);
+            for value in values {
+                compiler.push_constant(value);
+            }
+        }
+        Err(runtime_error) => {
+            // runtime errors point at the op that failed, running out of fuel points at the block
+            let message = format!("comptime block failed: {}", runtime_error.message);
+            let diagnostic = match runtime_error.span {
+                Some(span) => Diagnostic::error(ErrorCode::ComptimeFailed, span, message)
+                    .label(comptime.token.span, "while evaluating this comptime block".to_string()),
+                None => Diagnostic::error(ErrorCode::ComptimeFailed, comptime.token.span, message)
+                    .label(token.span, "comptime block ends here".to_string()),
+            };
+            emit(compiler, diagnostic);
+        }
+    }
+}
+
+// Comptime blocks cannot touch memory, print, declare anything or depend on when they run.
+fn allowed_in_comptime(token_type: TokenType) -> bool {
+    !matches!(token_type,
+        TokenType::AddressOf | TokenType::Alloc | TokenType::Array | TokenType::Attribute | TokenType::ClockNs | TokenType::Comptime |
+        TokenType::Enum | TokenType::EPrintLn | TokenType::Exec | TokenType::Extern | TokenType::Flush | TokenType::Free | TokenType::GetEnv | TokenType::Include | TokenType::Load |
+        TokenType::Module | TokenType::NowMs | TokenType::PrintLn | TokenType::Pub | TokenType::Random |
+        TokenType::RandomRange | TokenType::Realloc | TokenType::Sleep | TokenType::Store | TokenType::Struct | TokenType::Syscall |
+        TokenType::TcpAccept | TokenType::TcpClose | TokenType::TcpConnect | TokenType::TcpListen |
+        TokenType::TcpRead | TokenType::TcpWrite | TokenType::Var | TokenType::Data | TokenType::When | TokenType::Else)
+}
+
+// Prints the types on the stack at the '?stack' and the words that pushed them, without
+// compiling anything.
+fn query_stack(token: &Token, compiler: &mut CompilerContext) {
+    let count = compiler.stack.len();
+    let types = compiler.stack.types().iter().map(|data_type| data_type.to_string()).collect::<Vec<String>>().join(", ");
+    let message = match count {
+        0 => "the stack is empty here".to_string(),
+        1 => format!("1 value on the stack here [{}]", types),
+        _ => format!("{} values on the stack here [{}], the top last", count, types),
+    };
+
+    let mut diagnostic = Diagnostic::info(token.span, message);
+    for depth in 0..count {
+        diagnostic = diagnostic.label(compiler.stack.origin(depth), format!("{} pushed here", compiler.stack[count - 1 - depth]));
+    }
+    diagnostic.emit(compiler.sources, &mut **compiler.vm.error_output());
+}
+
+// Every value left over at the end of the program is labelled with the word that pushed
+// it, the top of the stack being the primary span.
+fn unhandled_data(compiler: &mut CompilerContext) {
+    let count = compiler.stack.len();
+    let types = compiler.stack.types().iter().map(|data_type| data_type.to_string()).collect::<Vec<String>>().join(", ");
+    let message = if count == 1 {
+        format!("1 value left on the stack at the end of the program [{}]", types)
+    } else {
+        format!("{} values left on the stack at the end of the program [{}]", count, types)
+    };
+
+    let mut diagnostic = Diagnostic::error(ErrorCode::UnhandledData, compiler.stack.origin(0), message)
+        .with_label(format!("{} pushed here", compiler.stack[count - 1]));
+    for depth in 1..count {
+        diagnostic = diagnostic.label(compiler.stack.origin(depth), format!("{} pushed here", compiler.stack[count - 1 - depth]));
+    }
+    let diagnostic = diagnostic.help("use 'drop' to discard values that are not needed, or 'println' to print them".to_string());
+    emit(compiler, diagnostic);
+}
+
+fn module_path(compiler: &CompilerContext, depth: usize) -> String {
+    compiler.modules[..depth].iter().map(|module| module.text).collect::<Vec<&str>>().join("::")
+}
+
+fn qualify(compiler: &CompilerContext, name: &str) -> String {
+    let mut path = module_path(compiler, compiler.modules.len());
+    if !path.is_empty() {
+        path.push_str("::");
+    }
+    path.push_str(name);
+    path
+}
+
+fn define<'a>(compiler: &mut CompilerContext<'a>, name: &Token<'a>, symbol: Symbol, public: bool) {
+    let path = qualify(compiler, name.text);
+    let attributes = compiler.attributes.clone();
+    compiler.symbols.insert(path, Definition { symbol, token: *name, public, attributes, used: false });
+}
+
+// Candidate paths for a name used inside the open modules, innermost first.
+fn candidate_paths(compiler: &CompilerContext, name: &str) -> Vec<String> {
+    (0..=compiler.modules.len()).rev().map(|depth| {
+        if depth == 0 {
+            name.to_string()
+        } else {
+            format!("{}::{}", module_path(compiler, depth), name)
+        }
+    }).collect()
+}
+
+// Finds the item a name used at token refers to, warning if it is deprecated.
+fn lookup(token: &Token, compiler: &mut CompilerContext, name: &str) -> Option<Symbol> {
+    let path = match find(compiler, name) {
+        Lookup::Found(path) => path,
+        Lookup::Private(_) | Lookup::Missing => return None,
+    };
+
+    let definition = compiler.symbols.get_mut(&path).unwrap();
+    definition.used = true;
+    let symbol = definition.symbol;
+    if let Some(reason) = &definition.attributes.deprecated {
+        let mut diagnostic = Diagnostic::warning(token.span, format!("'{}' is deprecated", name))
+            .label(definition.token.span, format!("'{}' is defined here", definition.token.text));
+        if !reason.is_empty() {
+            diagnostic = diagnostic.note(reason.clone());
+        }
+        diagnostic.emit(compiler.sources, &mut **compiler.vm.error_output());
+    }
+    Some(symbol)
+}
+
+fn find(compiler: &CompilerContext, name: &str) -> Lookup {
+    let current = module_path(compiler, compiler.modules.len());
+    let mut private = None;
+    for path in candidate_paths(compiler, name) {
+        let definition = match compiler.symbols.get(&path) {
+            Some(definition) => definition,
+            None => continue,
+        };
+
+        // private items are visible inside their own module and the modules nested in it
+        let visible = match path.rsplit_once("::") {
+            Some((module, _)) => definition.public || current == module || current.starts_with(&format!("{}::", module)),
+            None => true,
+        };
+        if visible {
+            return Lookup::Found(path);
+        }
+        if private.is_none() {
+            private = Some(path);
+        }
+    }
+
+    match private {
+        Some(path) => Lookup::Private(path),
+        None => Lookup::Missing,
+    }
+}
+
+// Warns about the names the program never uses. Names marked 'pub' are left out, as
+// they are there for other files to use.
+fn unused_warnings(compiler: &mut CompilerContext) {
+    let mut unused: Vec<&Definition> = compiler.symbols.values()
+        .filter(|definition| !definition.used && !definition.public && !definition.attributes.allow_unused)
+        .collect();
+    unused.sort_by_key(|definition| (definition.token.span.file.0, definition.token.span.start));
+
+    for definition in unused {
+        let diagnostic = Diagnostic::warning(definition.token.span, format!("'{}' is never used", definition.token.text))
+            .help("remove it, or mark it '#allow-unused' if it is kept on purpose".to_string());
+        diagnostic.emit(compiler.sources, &mut **compiler.vm.error_output());
+    }
+}
+
+fn missing_name_error(token: &Token, compiler: &mut CompilerContext, name: &str, kind: &str) {
+    if let Lookup::Private(path) = find(compiler, name) {
+        let definition = compiler.symbols[&path].token;
+        let module = path.rsplit_once("::").unwrap().0.to_string();
+        let diagnostic = Diagnostic::error(ErrorCode::PrivateItem, token.span, format!("'{}' is private to module '{}'", name, module))
+            .label(definition.span, format!("'{}' is defined here", definition.text))
+            .help(format!("mark it 'pub' to use it outside of '{}'", module));
+        emit(compiler, diagnostic);
+        return;
+    }
+
+    let message = match name.rsplit_once("::") {
+        Some((module, item)) => {
+            let module_exists = candidate_paths(compiler, module).iter().any(|path| compiler.module_paths.contains(path));
+            if module_exists {
+                format!("module '{}' has no {} named '{}'", module, kind, item)
+            } else {
+                format!("no module named '{}'", module)
+            }
+        }
+        None => format!("no {} named '{}'", kind, name),
+    };
+    error(token, compiler, ErrorCode::UnknownName, message);
+}
+
+fn size_of<'a>(scanner: &mut Scanner<'a>, compiler: &mut CompilerContext<'a>) {
+    // sizeof <struct>
+    let name = scanner.scan_token();
+    match lookup(&name, compiler, name.text) {
+        Some(Symbol::Struct(index)) => {
+            let size = compiler.structs[index].fields.len();
+            compiler.push_constant(Value::from_int(size as i64));
+        }
+        _ => error(&name, compiler, ErrorCode::UnexpectedToken, format!("expected struct name after 'sizeof', found '{}'", name.text)),
+    }
+}
+
+fn check_symbol_name(name: &Token, compiler: &mut CompilerContext, kind: &str) -> bool {
+    if !check_name(name, compiler, kind) {
+        return false;
+    }
+    if let Some(definition) = compiler.symbols.get(&qualify(compiler, name.text)) {
+        already_defined(name, compiler, definition.token.span, format!("'{}' is already defined", name.text));
+        return false;
+    }
+    if compiler.vm.builtin_index(name.text).is_some() {
+        error(name, compiler, ErrorCode::AlreadyDefined, format!("'{}' is already defined as a builtin", name.text));
+        return false;
+    }
+
+    true
+}
+
+fn already_defined(name: &Token, compiler: &mut CompilerContext, first: Span, message: String) {
+    let diagnostic = Diagnostic::error(ErrorCode::AlreadyDefined, name.span, message)
+        .with_label("defined again here".to_string())
+        .label(first, "first defined here".to_string());
+    emit(compiler, diagnostic);
+}
+
+fn check_name(name: &Token, compiler: &mut CompilerContext, kind: &str) -> bool {
+    if name.token_type != TokenType::Identifier {
+        error(name, compiler, ErrorCode::UnexpectedToken, format!("expected {} name, found '{}'", kind, name.text));
+        return false;
+    }
+    if name.text.contains('.') || name.text.contains(':') {
+        error(name, compiler, ErrorCode::InvalidName, format!("{} name '{}' cannot contain '.' or ':'", kind, name.text));
+        return false;
+    }
+    if DataType::from_name(name.text).is_some() {
+        error(name, compiler, ErrorCode::InvalidName, format!("cannot use type name '{}' as {} name", name.text, kind));
+        return false;
+    }
+
+    true
+}
+
+fn data_type(token: &Token) -> Option<DataType> {
+    if token.token_type != TokenType::Identifier {
+        return None;
+    }
+
+    DataType::from_name(token.text)
+}
+
+fn load<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>) {
+    let name = &token.text[1..];
+    if let Some(data_type) = DataType::from_name(name) {
+        // @int, @bool, @ptr load through the pointer on top of the stack
+        match compiler.stack.last() {
+            Some(DataType::Ptr) => {
+                compiler.push_op_at(Op::LoadPtr(data_type), token);
+            }
+            Some(found) => type_error(token, compiler, 0, format!("expected pointer on top of the stack to load {}, found {}", data_type, found)),
+            None => error(token, compiler, ErrorCode::StackUnderflow, format!("expected pointer on top of the stack to load {}, found nothing", data_type)),
+        }
+        return;
+    }
+
+    match lookup(token, compiler, name) {
+        Some(Symbol::Variable(address)) => compiler.push_op(Op::Load(address)),
+        Some(Symbol::Array(array)) => {
+            match compiler.stack.last() {
+                None => error(token, compiler, ErrorCode::StackUnderflow, format!("expected index on top of the stack to load from '{}', found nothing", name)),
+                Some(DataType::Int) => {
+                    compiler.push_op_at(Op::LoadIndexed(array), token);
+                }
+                Some(data_type) => {
+                    type_error(token, compiler, 0, format!("expected integer index on top of the stack to load from '{}', found {}", name, data_type));
+                }
+            }
+        }
+        Some(Symbol::Struct(_)) | Some(Symbol::Enum(_)) | Some(Symbol::Extern(_)) | Some(Symbol::Data(..)) | None => {
+            missing_name_error(token, compiler, name, "variable or array");
+        }
+    }
+}
+
+fn store<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>) {
+    let name = &token.text[1..];
+    if let Some(data_type) = DataType::from_name(name) {
+        // !int, !bool, !ptr store through the pointer on top of the stack
+        let len = compiler.stack.len();
+        if len < 2 {
+            error(token, compiler, ErrorCode::StackUnderflow, format!("expected value and pointer on the stack to store {}, found {} values", data_type, len));
+            return;
+        }
+        if compiler.stack[len - 1] != DataType::Ptr {
+            type_error(token, compiler, 0, format!("expected pointer on top of the stack to store {}, found {}", data_type, compiler.stack[len - 1]));
+            return;
+        }
+        if compiler.stack[len - 2] != data_type {
+            type_error(token, compiler, 1, format!("expected {} one down from the top of the stack to store, found {}", data_type, compiler.stack[len - 2]));
+            return;
+        }
+
+        compiler.push_op_at(Op::StorePtr(data_type), token);
+        return;
+    }
+
+    match lookup(token, compiler, name) {
+        Some(Symbol::Variable(address)) => {
+            let expected = compiler.vm.global_type(address);
+            match compiler.stack.last() {
+                None => error(token, compiler, ErrorCode::StackUnderflow, format!("nothing on stack to store in '{}'", name)),
+                Some(data_type) if *data_type != expected => {
+                    type_error(token, compiler, 0, format!("cannot store {} in variable '{}' of type {}", data_type, name, expected));
+                }
+                Some(_) => compiler.push_op(Op::Store(address)),
+            }
+        }
+        Some(Symbol::Array(array)) => {
+            let expected = compiler.vm.global_type(array.address);
+            let len = compiler.stack.len();
+            if len < 2 {
+                error(token, compiler, ErrorCode::StackUnderflow, format!("expected value and index on the stack to store in '{}', found {} values", name, len));
+                return;
+            }
+            if compiler.stack[len - 1] != DataType::Int {
+                type_error(token, compiler, 0, format!("expected integer index on top of the stack to store in '{}', found {}", name, compiler.stack[len - 1]));
+                return;
+            }
+            if compiler.stack[len - 2] != expected {
+                type_error(token, compiler, 1, format!("cannot store {} in array '{}' of {}", compiler.stack[len - 2], name, expected));
+                return;
+            }
+
+            compiler.push_op_at(Op::StoreIndexed(array), token);
+        }
+        Some(Symbol::Struct(_)) | Some(Symbol::Enum(_)) | Some(Symbol::Extern(_)) | Some(Symbol::Data(..)) | None => {
+            missing_name_error(token, compiler, name, "variable or array");
+        }
+    }
+}
+
+fn address_of(token: &Token, compiler: &mut CompilerContext) {
+    let name = &token.text[1..];
+    match lookup(token, compiler, name) {
+        Some(Symbol::Variable(address)) => compiler.push_constant(Value::from_ptr(address)),
+        Some(Symbol::Array(array)) => compiler.push_constant(Value::from_ptr(array.address)),
+        // a pointer to its bytes and how many there are
+        Some(Symbol::Data(_, bytes)) => {
+            compiler.push_constant(Value::from_ptr(bytes.address));
+            compiler.push_constant(Value::from_int(bytes.length as i64));
+        }
+        _ => {
+            missing_name_error(token, compiler, name, "variable, array or data block");
+        }
+    }
+}
+
+fn alloc<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>) {
+    match compiler.stack.last() {
+        Some(DataType::Int) => {
+            compiler.push_op_at(Op::Alloc, token);
+        }
+        Some(data_type) => type_error(token, compiler, 0, format!("expected integer cell count on top of the stack to alloc, found {}", data_type)),
+        None => error(token, compiler, ErrorCode::StackUnderflow, "expected integer cell count on top of the stack to alloc, found nothing".to_string()),
+    }
+}
+
+fn free<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>) {
+    match compiler.stack.last() {
+        Some(DataType::Ptr) => {
+            compiler.push_op_at(Op::Free, token);
+        }
+        Some(data_type) => type_error(token, compiler, 0, format!("expected pointer on top of the stack to free, found {}", data_type)),
+        None => error(token, compiler, ErrorCode::StackUnderflow, "expected pointer on top of the stack to free, found nothing".to_string()),
+    }
+}
+
+fn realloc<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>) {
+    let len = compiler.stack.len();
+    if len < 2 {
+        error(token, compiler, ErrorCode::StackUnderflow, format!("expected pointer and cell count on the stack to realloc, found {} values", len));
+        return;
+    }
+    if compiler.stack[len - 1] != DataType::Int {
+        type_error(token, compiler, 0, format!("expected integer cell count on top of the stack to realloc, found {}", compiler.stack[len - 1]));
+        return;
+    }
+    if compiler.stack[len - 2] != DataType::Ptr {
+        type_error(token, compiler, 1, format!("expected pointer one down from the top of the stack to realloc, found {}", compiler.stack[len - 2]));
+        return;
+    }
+
+    compiler.push_op_at(Op::Realloc, token);
+}
+
+fn cast(token: &Token, compiler: &mut CompilerContext, from: DataType, to: DataType) {
+    match compiler.stack.last() {
+        Some(data_type) if *data_type == from => compiler.push_op(Op::Cast(to)),
+        Some(data_type) => type_error(token, compiler, 0, format!("expected {} on top of the stack to convert to {}, found {}", from, to, data_type)),
+        None => error(token, compiler, ErrorCode::StackUnderflow, format!("expected {} on top of the stack to convert to {}, found nothing", from, to)),
+    }
+}
+
+fn identifier<'a>(token: &Token<'a>, compiler: &mut CompilerContext<'a>) {
+    if let Some((owner, member)) = token.text.split_once('.') {
+        qualified_name(token, compiler, owner, member);
+        return;
+    }
+
+    let symbol = lookup(token, compiler, token.text);
+    if symbol.is_none() {
+        if let Some(index) = compiler.vm.builtin_index(token.text) {
+            call_builtin(token, compiler, index);
+            return;
+        }
+    }
+
+    match symbol {
+        Some(Symbol::Variable(_)) => {
+            error(token, compiler, ErrorCode::MisusedName, format!("'{0}' is a variable, use '@{0}' to load its value or '!{0}' to store to it", token.text));
+        }
+        Some(Symbol::Array(_)) => {
+            error(token, compiler, ErrorCode::MisusedName, format!("'{0}' is an array, use '@{0}' to load an element or '!{0}' to store one", token.text));
+        }
+        Some(Symbol::Struct(_)) => {
+            error(token, compiler, ErrorCode::MisusedName, format!("'{0}' is a struct, use '{0}.<field>' for a field offset or 'sizeof {0}' for its size", token.text));
+        }
+        Some(Symbol::Enum(_)) => {
+            error(token, compiler, ErrorCode::MisusedName, format!("'{0}' is an enum, use '{0}.<variant>' for one of its variants", token.text));
+        }
+        Some(Symbol::Extern(index)) => call_extern(token, compiler, index),
+        Some(Symbol::Data(index, _)) => compiler.push_constant(Value::from_string_index(index)),
+        None if token.text.contains("::") => {
+            missing_name_error(token, compiler, token.text, "word");
+        }
+        None => error(token, compiler, ErrorCode::UnknownName, format!("unknown word '{}'", token.text)),
+    }
+}
+
+fn qualified_name(token: &Token, compiler: &mut CompilerContext, owner: &str, member: &str) {
+    // Struct.field pushes the field offset, Enum.Variant pushes the variant's ordinal
+    let (index, kind) = match lookup(token, compiler, owner) {
+        Some(Symbol::Struct(index)) => {
+            (compiler.structs[index].fields.iter().position(|(name, _)| *name == member), "field")
+        }
+        Some(Symbol::Enum(index)) => {
+            (compiler.enums[index].variants.iter().position(|name| *name == member), "variant")
+        }
+        Some(_) => {
+            error(token, compiler, ErrorCode::MisusedName, format!("'{}' is not a struct or enum", owner));
+            return;
+        }
+        None => {
+            missing_name_error(token, compiler, owner, "struct or enum");
+            return;
+        }
+    };
+
+    match index {
+        Some(index) => compiler.push_constant(Value::from_int(index as i64)),
+        None => error(token, compiler, ErrorCode::UnknownName, format!("'{}' has no {} '{}'", owner, kind, member)),
+    }
+}
+
+fn error(token: &Token, compiler: &mut CompilerContext, code: ErrorCode, message: String) {
+    emit(compiler, Diagnostic::error(code, token.span, message));
+}
+
+// For a value of the wrong type at depth, pointing back at the word that pushed it.
+fn type_error(token: &Token, compiler: &mut CompilerContext, depth: usize, message: String) {
+    let origin = compiler.stack.origin(depth);
+    let diagnostic = Diagnostic::error(ErrorCode::TypeMismatch, token.span, message)
+        .label(origin, "this value was pushed here".to_string());
+    emit(compiler, diagnostic);
+}
+
+fn emit(compiler: &mut CompilerContext, diagnostic: Diagnostic) {
+    compiler.had_error = true;
+    diagnostic.emit(compiler.sources, &mut **compiler.vm.error_output());
+}
+
+fn report_stats(stats: &Stats, out: &mut dyn Write) {
+    let _ = writeln!(out, "Stats:");
+    let _ = writeln!(out, "    ops executed      {}", stats.ops_executed);
+    let _ = writeln!(out, "    peak stack depth  {}", stats.peak_stack_depth);
+    let _ = writeln!(out, "    allocations       {}", stats.allocations);
+    let _ = writeln!(out, "    peak heap cells   {}", stats.peak_heap_cells);
+    let _ = writeln!(out, "    bytes printed     {}", stats.bytes_printed);
+}
+
+fn report_runtime_error(runtime_error: &RuntimeError, sources: &SourceMap, vm: &VM) {
+    if runtime_error.kind == ErrorKind::BrokenPipe {
+        // nothing is reading any more, which is how output piped into e.g. head normally ends
+        return;
+    }
+
+    let code = ErrorCode::from(runtime_error.kind);
+    if matches!(runtime_error.kind, ErrorKind::StepLimit|ErrorKind::Timeout|ErrorKind::Interrupted) {
+        // limits and Ctrl-C can stop the program at any op, which has no token to point at
+        let _ = writeln!(vm.error_output(), "{}[{}]: {}", "Aborted".red(), code.code(), runtime_error.message);
+        return;
+    }
+
+    // every op that can fail is pushed with a span, only flushing output after the last op has none
+    match runtime_error.span {
+        Some(span) => Diagnostic::runtime_error(code, span, runtime_error.message.clone()).emit(sources, &mut **vm.error_output()),
+        None => {
+            let _ = writeln!(vm.error_output(), "{}[{}]: {}", "Runtime Error".red(), code.code(), runtime_error.message);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_span, unescape};
+    use crate::scanner::{self, Scanner, TokenType};
+    use crate::span::{FileId, Span};
+
+    // The error for the first invalid escape, as the text it covers and its message.
+    fn invalid(contents: &str) -> (&str, String) {
+        let (range, message) = unescape(contents).unwrap_err();
+        (&contents[range], message)
+    }
+
+    #[test]
+    fn replaces_escapes() {
+        assert_eq!(unescape(r#"a\nb\tc\rd\0e\"f\\g"#).unwrap(), "a\nb\tc\rd\0e\"f\\g");
+        assert_eq!(unescape(r"\x41\x7f\x0a").unwrap(), "A\x7f\n");
+        assert_eq!(unescape(r"\u{e9}\u{1F600}\u{0}\u{10FFFF}").unwrap(), "é😀\0\u{10FFFF}");
+        // a Windows line break inside the string is read as a plain one
+        assert_eq!(unescape("a\r\nb").unwrap(), "a\nb");
+    }
+
+    #[test]
+    fn rejects_invalid_x_escapes() {
+        assert_eq!(invalid(r"ab\xZZ"), (r"\x", "expected two hex digits after '\\x'".to_string()));
+        assert_eq!(invalid(r"\x4"), (r"\x4", "expected two hex digits after '\\x'".to_string()));
+        assert_eq!(invalid(r"\x"), (r"\x", "expected two hex digits after '\\x'".to_string()));
+        assert_eq!(invalid(r"\x80 more"), (r"\x80", "'\\x' escapes only go up to '\\x7f', use '\\u{...}' for other characters".to_string()));
+    }
+
+    #[test]
+    fn rejects_invalid_u_escapes() {
+        let expected = "expected one to six hex digits in braces after '\\u', like '\\u{e9}'".to_string();
+        assert_eq!(invalid(r"\u{D800}!"), (r"\u{D800}", "'D800' is not a unicode character".to_string()));
+        assert_eq!(invalid(r"\u{110000}"), (r"\u{110000}", "'110000' is not a unicode character".to_string()));
+        assert_eq!(invalid(r"\u{"), (r"\u{", expected.clone()));
+        assert_eq!(invalid(r"\u{41 "), (r"\u{41", expected.clone()));
+        assert_eq!(invalid(r"\u{}"), (r"\u{}", expected.clone()));
+        assert_eq!(invalid(r"\u{1000000}"), (r"\u{1000000}", expected.clone()));
+        assert_eq!(invalid(r"\u41"), (r"\u", expected));
+    }
+
+    #[test]
+    fn rejects_unknown_and_unfinished_escapes() {
+        assert_eq!(invalid(r"ok\q"), (r"\q", "unknown escape sequence '\\q' in string".to_string()));
+        assert_eq!(invalid(r"\é"), (r"\é", "unknown escape sequence '\\é' in string".to_string()));
+        assert_eq!(invalid("end\\"), ("\\", "unfinished escape sequence in string".to_string()));
+    }
+
+    // The span of the first invalid escape in the only string in code.
+    fn error_span(code: &str) -> Span {
+        let code = code.to_string();
+        let token = Scanner::new(&code, FileId(0)).find(|token| token.token_type == TokenType::String).unwrap();
+        let (prefix, contents, _) = scanner::string_contents(token.text);
+        let (range, _) = unescape(contents).unwrap_err();
+        escape_span(&token, prefix, contents, range)
+    }
+
+    #[test]
+    fn points_at_the_invalid_escape() {
+        let span = error_span("1 println \"ab\\qc\" println");
+        assert_eq!((span.start, span.end, span.line, span.column), (13, 15, 1, 14));
+
+        // columns count characters and offsets count bytes
+        let span = error_span("\"é\\u{D800}\"");
+        assert_eq!((span.start, span.end, span.line, span.column), (3, 11, 1, 3));
+
+        // an escape on a later line of a long string is on that line
+        let span = error_span("1 drop\n  \"\"\"first\n  sécond \\x\n\"\"\"");
+        assert_eq!((span.start, span.end, span.line, span.column), (28, 30, 3, 10));
+    }
 }
@@ -0,0 +1,77 @@
+use crate::scanner::{Scanner, Token, TokenType};
+
+// 'when <name> ... else ... end' compiles its first branch when the name is defined and
+// its else branch when it is not. The branch that is left out is skipped without being
+// compiled, so it can use words or externs that only exist on another platform.
+
+// Defined with --define, or naming the platform px2 was built for.
+pub fn is_defined(name: &str, defines: &[String]) -> bool {
+    let platform = match name {
+        "windows" => cfg!(windows),
+        "unix" => cfg!(unix),
+        "linux" => cfg!(target_os = "linux"),
+        "macos" => cfg!(target_os = "macos"),
+        _ => false,
+    };
+    platform || defines.iter().any(|define| define == name)
+}
+
+// Skips the tokens of a branch left out, up to the 'else' or 'end' that closes it, which
+// is returned. Blocks opened inside the branch are skipped along with their own 'end'.
+// Returns EndOfFile if the branch is never closed.
+pub fn skip_branch<'a>(scanner: &mut Scanner<'a>) -> Token<'a> {
+    let mut depth = 0;
+    loop {
+        let token = scanner.scan_token();
+        match token.token_type {
+            TokenType::Comptime|TokenType::Data|TokenType::Enum|TokenType::Extern|TokenType::Module|TokenType::Struct|TokenType::When => depth += 1,
+            TokenType::End|TokenType::Else if depth == 0 => return token,
+            TokenType::End => depth -= 1,
+            TokenType::EndOfFile => return token,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::skip_branch;
+    use crate::scanner::{Scanner, TokenType};
+    use crate::span::FileId;
+
+    // Skips the branch after 'when nosuch' and returns the closing token's type and the
+    // text of the token after it.
+    fn skip(code: &str) -> (TokenType, String) {
+        let code = code.to_string();
+        let mut scanner = Scanner::new(&code, FileId(0));
+        assert_eq!(scanner.scan_token().token_type, TokenType::When);
+        scanner.scan_token();
+        let closing = skip_branch(&mut scanner).token_type;
+        (closing, scanner.scan_token().text.to_string())
+    }
+
+    #[test]
+    fn skips_nested_blocks() {
+        let blocks = [
+            "comptime 1 2 + end",
+            "data blob\nhello\nend",
+            "enum Colour Red Green end",
+            "extern \"libc.so.6\" abs int -- int end",
+            "module inner var x int end",
+            "struct Point int x int y end",
+            "when other 1 else 2 end",
+        ];
+        for block in blocks {
+            let code = format!("when nosuch\n{}\n1 println\nend\n2 println\n", block);
+            assert_eq!(skip(&code), (TokenType::End, "2".to_string()), "skipping {}", block);
+
+            let code = format!("when nosuch\n{}\nelse\n2 println\nend\n", block);
+            assert_eq!(skip(&code), (TokenType::Else, "2".to_string()), "skipping {}", block);
+        }
+    }
+
+    #[test]
+    fn stops_at_end_of_file() {
+        assert_eq!(skip("when nosuch\nstruct Point int x end\n").0, TokenType::EndOfFile);
+    }
+}
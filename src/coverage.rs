@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Write;
+
+use crate::source_map::SourceMap;
+use crate::span::{FileId, Span};
+
+// How many times the ops compiled from each line ran, by file and line. Only lines that
+// ops were compiled from are counted, so declarations and comptime blocks are left out.
+struct LineCounts {
+    files: BTreeMap<usize, BTreeMap<usize, u64>>,
+}
+
+impl LineCounts {
+    fn new(spans: &[Option<Span>], counts: &[u64]) -> Self {
+        let mut files: BTreeMap<usize, BTreeMap<usize, u64>> = BTreeMap::new();
+        for (span, count) in spans.iter().zip(counts) {
+            if let Some(span) = span {
+                // a line is hit as many times as its most run op, not the sum of its ops
+                let line = files.entry(span.file.0).or_default().entry(span.line).or_default();
+                *line = (*line).max(*count);
+            }
+        }
+        LineCounts { files }
+    }
+}
+
+// Prints how many of each file's lines ran and which did not, followed by the total.
+pub fn report(sources: &SourceMap, spans: &[Option<Span>], counts: &[u64], out: &mut dyn Write) {
+    let lines = LineCounts::new(spans, counts);
+    let (mut total_found, mut total_hit) = (0, 0);
+
+    let _ = writeln!(out, "Coverage:");
+    for (file, file_lines) in &lines.files {
+        let missed: Vec<String> = file_lines.iter().filter(|(_, count)| **count == 0).map(|(line, _)| line.to_string()).collect();
+        let hit = file_lines.len() - missed.len();
+        total_found += file_lines.len();
+        total_hit += hit;
+
+        let _ = write!(out, "    {}: {}/{} lines", sources.path(FileId(*file)), hit, file_lines.len());
+        if missed.is_empty() {
+            let _ = writeln!(out);
+        } else {
+            let _ = writeln!(out, ", not run: {}", missed.join(", "));
+        }
+    }
+
+    let percent = if total_found == 0 { 100.0 } else { total_hit as f64 * 100.0 / total_found as f64 };
+    let _ = writeln!(out, "    total: {}/{} lines ({:.1}%)", total_hit, total_found, percent);
+}
+
+// Writes the line counts as an lcov tracefile, which coverage viewers and CI services read.
+pub fn write_lcov(path: &str, sources: &SourceMap, spans: &[Option<Span>], counts: &[u64]) -> Result<(), String> {
+    let lines = LineCounts::new(spans, counts);
+    let mut lcov = String::new();
+    for (file, file_lines) in &lines.files {
+        let _ = writeln!(lcov, "TN:");
+        let _ = writeln!(lcov, "SF:{}", sources.path(FileId(*file)));
+        for (line, count) in file_lines {
+            let _ = writeln!(lcov, "DA:{},{}", line, count);
+        }
+        let _ = writeln!(lcov, "LF:{}", file_lines.len());
+        let _ = writeln!(lcov, "LH:{}", file_lines.values().filter(|count| **count > 0).count());
+        let _ = writeln!(lcov, "end_of_record");
+    }
+    fs::write(path, lcov).map_err(|error| format!("cannot write coverage to '{}': {}", path, error))
+}
@@ -0,0 +1,173 @@
+use std::io::{self, Write};
+
+use colored::*;
+
+use crate::error_code::ErrorCode;
+use crate::source_map::SourceMap;
+use crate::span::Span;
+
+#[derive(Clone, Copy)]
+enum Severity {
+    Error,
+    // reported without stopping the program from compiling
+    Warning,
+    Note,
+}
+
+struct Label {
+    span: Span,
+    message: Option<String>,
+    primary: bool,
+}
+
+// An error pointing at one span, with optional labels on other spans and notes and
+// help lines printed underneath, e.g.
+//
+//     Compiler Error[E0006] at 'a::x': 'a::x' is private to module 'a'
+//            --> main.px2:6:1
+//             |
+//           2 | var x int
+//             |     - 'x' is defined here
+//             ...
+//           6 | a::x drop
+//             | ^^^^
+//             = help: mark it 'pub' to use it outside of 'a'
+pub struct Diagnostic {
+    title: &'static str,
+    severity: Severity,
+    // None for a report that is not an error
+    code: Option<ErrorCode>,
+    message: String,
+    labels: Vec<Label>,
+    notes: Vec<String>,
+    help: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: ErrorCode, span: Span, message: String) -> Self {
+        Diagnostic::new("Compiler Error", Severity::Error, Some(code), span, message)
+    }
+
+    pub fn runtime_error(code: ErrorCode, span: Span, message: String) -> Self {
+        Diagnostic::new("Runtime Error", Severity::Error, Some(code), span, message)
+    }
+
+    // Something that compiles but is likely a mistake, e.g. using a deprecated name.
+    pub fn warning(span: Span, message: String) -> Self {
+        Diagnostic::new("Warning", Severity::Warning, None, span, message)
+    }
+
+    // Information the compiler was asked for, e.g. by '?stack'.
+    pub fn info(span: Span, message: String) -> Self {
+        Diagnostic::new("Note", Severity::Note, None, span, message)
+    }
+
+    fn new(title: &'static str, severity: Severity, code: Option<ErrorCode>, span: Span, message: String) -> Self {
+        Diagnostic {
+            title,
+            severity,
+            code,
+            message,
+            labels: vec![Label { span, message: None, primary: true }],
+            notes: Vec::new(),
+            help: Vec::new(),
+        }
+    }
+
+    // Text printed next to the main span's underline.
+    pub fn with_label(mut self, message: String) -> Self {
+        self.labels[0].message = Some(message);
+        self
+    }
+
+    pub fn label(mut self, span: Span, message: String) -> Self {
+        self.labels.push(Label { span, message: Some(message), primary: false });
+        self
+    }
+
+    pub fn note(mut self, message: String) -> Self {
+        self.notes.push(message);
+        self
+    }
+
+    pub fn help(mut self, message: String) -> Self {
+        self.help.push(message);
+        self
+    }
+
+    // A diagnostic that cannot be written has nowhere else to be reported, so write
+    // errors are ignored.
+    pub fn emit(&self, sources: &SourceMap, out: &mut dyn Write) {
+        let _ = self.write(sources, out);
+    }
+
+    fn write(&self, sources: &SourceMap, out: &mut dyn Write) -> io::Result<()> {
+        let primary = &self.labels[0].span;
+        let text = sources.text(primary).lines().next().unwrap_or("");
+        match (self.severity, self.code) {
+            (_, Some(code)) => writeln!(out, "{}[{}] at '{}': {}", self.title.red(), code.code(), text, self.message)?,
+            (Severity::Warning, None) => writeln!(out, "{} at '{}': {}", self.title.yellow(), text, self.message)?,
+            (_, None) => writeln!(out, "{} at '{}': {}", self.title.cyan(), text, self.message)?,
+        }
+
+        // the primary span's file comes first, other files in the order they are labelled
+        let mut files = vec![primary.file];
+        for label in &self.labels {
+            if !files.contains(&label.span.file) {
+                files.push(label.span.file);
+            }
+        }
+
+        for file in files {
+            let mut labels: Vec<&Label> = self.labels.iter().filter(|label| label.span.file == file).collect();
+            labels.sort_by_key(|label| (label.span.line, label.span.column));
+
+            let first = labels.iter().find(|label| label.primary).unwrap_or(&labels[0]);
+            writeln!(out, "       --> {}:{}:{}", sources.path(file), first.span.line, first.span.column)?;
+            writeln!(out, "        |")?;
+
+            let mut previous_line = None;
+            for label in &labels {
+                let line = label.span.line;
+                if previous_line != Some(line) {
+                    if previous_line.is_some_and(|previous| line > previous + 1) {
+                        writeln!(out, "        ...")?;
+                    }
+                    writeln!(out, "{:7} | {}", line, sources.line(file, line))?;
+                    previous_line = Some(line);
+                }
+                write_underline(label, self.severity, sources, out)?;
+            }
+        }
+
+        for note in &self.notes {
+            writeln!(out, "        = {}: {}", "note".cyan(), note)?;
+        }
+        for help in &self.help {
+            writeln!(out, "        = {}: {}", "help".green(), help)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn write_underline(label: &Label, severity: Severity, sources: &SourceMap, out: &mut dyn Write) -> io::Result<()> {
+    // tabs are kept so the underline lines up with the line above however wide they are shown
+    let line = sources.line(label.span.file, label.span.line);
+    let padding: String = line.chars().take(label.span.column - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+    // the end of a file has no text, but still gets a mark, and a string over several
+    // lines is only marked on the first
+    let length = sources.text(&label.span).lines().next().unwrap_or("").chars().count().max(1);
+    let color = match (label.primary, severity) {
+        (false, _) => Color::Cyan,
+        (true, Severity::Warning) => Color::Yellow,
+        (true, _) => Color::Red,
+    };
+    let underline = if label.primary { "^" } else { "-" }.repeat(length).color(color);
+
+    match &label.message {
+        Some(message) if label.primary => writeln!(out, "        | {}{} {}", padding, underline, message.color(color)),
+        Some(message) => writeln!(out, "        | {}{} {}", padding, underline, message.cyan()),
+        None => writeln!(out, "        | {}{}", padding, underline),
+    }
+}
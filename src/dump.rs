@@ -0,0 +1,211 @@
+use std::io::{self, Write};
+
+use crate::compiler::DumpFormat;
+use crate::source_map::SourceMap;
+use crate::span::{FileId, Span};
+use crate::vm::{Op, Value, VM};
+
+// A compiled program as a tree that is written out as JSON or as S-expressions, so
+// tools can read the ops the compiler made without compiling px2 themselves.
+pub enum Node {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Str(String),
+    List(Vec<Node>),
+    // JSON writes the fields as an object, S-expressions as a list headed by the tag
+    Record(&'static str, Vec<(&'static str, Node)>),
+}
+
+impl Node {
+    pub fn span(span: &Span) -> Node {
+        Node::Record("span", vec![
+            ("file", Node::Int(span.file.0 as i64)),
+            ("line", Node::Int(span.line as i64)),
+            ("column", Node::Int(span.column as i64)),
+            ("start", Node::Int(span.start as i64)),
+            ("end", Node::Int(span.end as i64)),
+        ])
+    }
+
+    fn value(value: Value, vm: &VM) -> Node {
+        match value {
+            Value::Int(value)|Value::Ptr(value) => Node::Int(value),
+            Value::Bool(value) => Node::Bool(value),
+            Value::Str(_) => Node::Str(vm.string_constant(value).to_string()),
+            Value::UInt(value) => Node::UInt(value),
+            // the digits, which would lose precision as a JSON number
+            Value::Big(_) => Node::Str(vm.big_constant(value).to_string()),
+        }
+    }
+}
+
+// The whole program: its files, constant pool, named symbols and ops in order.
+pub fn program(sources: &SourceMap, files: &[FileId], vm: &VM, symbols: Vec<Node>) -> Node {
+    let files = files.iter().map(|file| Node::Record("file", vec![
+        ("id", Node::Int(file.0 as i64)),
+        ("path", Node::Str(sources.path(*file).to_string())),
+    ])).collect();
+
+    let constants = vm.constants().iter().enumerate().map(|(index, value)| Node::Record("constant", vec![
+        ("index", Node::Int(index as i64)),
+        ("type", Node::Str(value.data_type().to_string())),
+        ("value", Node::value(*value, vm)),
+    ])).collect();
+
+    let ops = vm.ops().iter().zip(vm.op_spans()).enumerate().map(|(index, (op, span))| Node::Record("op", vec![
+        ("index", Node::Int(index as i64)),
+        ("name", Node::Str(op.to_string().split(' ').next().unwrap().to_string())),
+        ("operands", Node::List(operands(op, vm))),
+        ("span", span.as_ref().map_or(Node::Null, Node::span)),
+    ])).collect();
+
+    Node::Record("program", vec![
+        ("files", Node::List(files)),
+        ("constants", Node::List(constants)),
+        ("symbols", Node::List(symbols)),
+        ("ops", Node::List(ops)),
+    ])
+}
+
+fn operands(op: &Op, vm: &VM) -> Vec<Node> {
+    match op {
+        Op::AddConst(value)|Op::SubtractConst(value)|Op::MultiplyConst(value)|Op::DivideConst(value)|Op::PrintConst(value) => vec![Node::value(*value, vm)],
+        Op::MinConst(value)|Op::MaxConst(value) => vec![Node::Int(*value)],
+        Op::PushConst(index)|Op::Load(index)|Op::Store(index)|Op::Format(index)
+            |Op::CallExtern(index)|Op::CallBuiltin(index)|Op::Syscall(index) => vec![Node::Int(*index as i64)],
+        Op::LoadIndexed(array)|Op::StoreIndexed(array) => vec![Node::Int(array.address as i64), Node::Int(array.length as i64)],
+        Op::LoadPtr(data_type)|Op::StorePtr(data_type)|Op::Cast(data_type) => vec![Node::Str(data_type.to_string())],
+        _ => Vec::new(),
+    }
+}
+
+pub fn write(format: DumpFormat, node: &Node, out: &mut dyn Write) -> io::Result<()> {
+    let mut text = String::new();
+    match format {
+        DumpFormat::Json => write_json(node, 0, &mut text),
+        DumpFormat::Sexp => write_sexp(node, 0, &mut text),
+    }
+    text.push('\n');
+    out.write_all(text.as_bytes())
+}
+
+// Lists of records go one item per line, so each op is a line of its own.
+fn is_flat(node: &Node) -> bool {
+    match node {
+        Node::List(items) => items.iter().all(|item| !matches!(item, Node::Record(..)) && is_flat(item)),
+        Node::Record(_, fields) => fields.iter().all(|(_, value)| is_flat(value)),
+        _ => true,
+    }
+}
+
+fn write_json(node: &Node, depth: usize, text: &mut String) {
+    let (indent, inner) = ("  ".repeat(depth), "  ".repeat(depth + 1));
+    let flat = is_flat(node);
+    let separator = if flat { ", ".to_string() } else { format!(",\n{}", inner) };
+    match node {
+        Node::Null => text.push_str("null"),
+        Node::Bool(value) => text.push_str(&value.to_string()),
+        Node::Int(value) => text.push_str(&value.to_string()),
+        Node::UInt(value) => text.push_str(&value.to_string()),
+        Node::Str(value) => quote(value, text),
+        Node::List(items) if items.is_empty() => text.push_str("[]"),
+        Node::List(items) => {
+            text.push_str(if flat { "[" } else { "[\n" });
+            if !flat {
+                text.push_str(&inner);
+            }
+            for (index, item) in items.iter().enumerate() {
+                if index > 0 {
+                    text.push_str(&separator);
+                }
+                write_json(item, depth + 1, text);
+            }
+            if !flat {
+                text.push('\n');
+                text.push_str(&indent);
+            }
+            text.push(']');
+        }
+        Node::Record(_, fields) => {
+            text.push_str(if flat { "{" } else { "{\n" });
+            if !flat {
+                text.push_str(&inner);
+            }
+            for (index, (key, value)) in fields.iter().enumerate() {
+                if index > 0 {
+                    text.push_str(&separator);
+                }
+                quote(key, text);
+                text.push_str(": ");
+                write_json(value, depth + 1, text);
+            }
+            if !flat {
+                text.push('\n');
+                text.push_str(&indent);
+            }
+            text.push('}');
+        }
+    }
+}
+
+fn write_sexp(node: &Node, depth: usize, text: &mut String) {
+    let inner = "  ".repeat(depth + 1);
+    match node {
+        Node::Null => text.push_str("nil"),
+        Node::Bool(value) => text.push_str(if *value { "#t" } else { "#f" }),
+        Node::Int(value) => text.push_str(&value.to_string()),
+        Node::UInt(value) => text.push_str(&value.to_string()),
+        Node::Str(value) => quote(value, text),
+        Node::List(items) => {
+            let flat = is_flat(node);
+            text.push('(');
+            for (index, item) in items.iter().enumerate() {
+                if !flat {
+                    text.push('\n');
+                    text.push_str(&inner);
+                } else if index > 0 {
+                    text.push(' ');
+                }
+                write_sexp(item, depth + 1, text);
+            }
+            text.push(')');
+        }
+        Node::Record(tag, fields) => {
+            text.push('(');
+            text.push_str(tag);
+            for (key, value) in fields {
+                if is_flat(value) {
+                    text.push(' ');
+                } else {
+                    text.push('\n');
+                    text.push_str(&inner);
+                }
+                text.push('(');
+                text.push_str(key);
+                text.push(' ');
+                write_sexp(value, depth + 1, text);
+                text.push(')');
+            }
+            text.push(')');
+        }
+    }
+}
+
+// Both formats quote strings the same way, with control characters escaped.
+fn quote(value: &str, text: &mut String) {
+    text.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => text.push_str("\\\""),
+            '\\' => text.push_str("\\\\"),
+            '\n' => text.push_str("\\n"),
+            '\r' => text.push_str("\\r"),
+            '\t' => text.push_str("\\t"),
+            c if c.is_control() => text.push_str(&format!("\\u{:04x}", c as u32)),
+            c => text.push(c),
+        }
+    }
+    text.push('"');
+}
@@ -0,0 +1,597 @@
+use crate::vm::ErrorKind;
+
+// Every error px2 reports has a code, shown in the diagnostic and looked up with
+// `px2 explain <code>`. Codes are stable: new errors get the next free number and a
+// retired error keeps its number unused rather than handing it to something else.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorCode {
+    InvalidToken,
+    UnexpectedToken,
+    MisplacedWord,
+    MissingEnd,
+    UnknownName,
+    PrivateItem,
+    AlreadyDefined,
+    InvalidName,
+    MisusedName,
+    EmptyDeclaration,
+    StackUnderflow,
+    TypeMismatch,
+    PointerArithmetic,
+    InvalidInteger,
+    FormatString,
+    RequiresUnsafe,
+    Unsupported,
+    ExternSignature,
+    ExternLoad,
+    NotAllowedInComptime,
+    ComptimeFailed,
+    RuntimeFailure,
+    PermissionDenied,
+    StepLimit,
+    Timeout,
+    UnhandledData,
+    IncludeFailed,
+    DivisionByZero,
+    Interrupted,
+    Overflow,
+    InvalidArgument,
+    OutOfBounds,
+    InvalidAccess,
+    AllocationFailed,
+    IoFailed,
+    InvalidOperand,
+}
+
+const ALL: [ErrorCode; 36] = [
+    ErrorCode::InvalidToken,
+    ErrorCode::UnexpectedToken,
+    ErrorCode::MisplacedWord,
+    ErrorCode::MissingEnd,
+    ErrorCode::UnknownName,
+    ErrorCode::PrivateItem,
+    ErrorCode::AlreadyDefined,
+    ErrorCode::InvalidName,
+    ErrorCode::MisusedName,
+    ErrorCode::EmptyDeclaration,
+    ErrorCode::StackUnderflow,
+    ErrorCode::TypeMismatch,
+    ErrorCode::PointerArithmetic,
+    ErrorCode::InvalidInteger,
+    ErrorCode::FormatString,
+    ErrorCode::RequiresUnsafe,
+    ErrorCode::Unsupported,
+    ErrorCode::ExternSignature,
+    ErrorCode::ExternLoad,
+    ErrorCode::NotAllowedInComptime,
+    ErrorCode::ComptimeFailed,
+    ErrorCode::RuntimeFailure,
+    ErrorCode::PermissionDenied,
+    ErrorCode::StepLimit,
+    ErrorCode::Timeout,
+    ErrorCode::UnhandledData,
+    ErrorCode::IncludeFailed,
+    ErrorCode::DivisionByZero,
+    ErrorCode::Interrupted,
+    ErrorCode::Overflow,
+    ErrorCode::InvalidArgument,
+    ErrorCode::OutOfBounds,
+    ErrorCode::InvalidAccess,
+    ErrorCode::AllocationFailed,
+    ErrorCode::IoFailed,
+    ErrorCode::InvalidOperand,
+];
+
+impl ErrorCode {
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorCode::InvalidToken => "E0001",
+            ErrorCode::UnexpectedToken => "E0002",
+            ErrorCode::MisplacedWord => "E0003",
+            ErrorCode::MissingEnd => "E0004",
+            ErrorCode::UnknownName => "E0005",
+            ErrorCode::PrivateItem => "E0006",
+            ErrorCode::AlreadyDefined => "E0007",
+            ErrorCode::InvalidName => "E0008",
+            ErrorCode::MisusedName => "E0009",
+            ErrorCode::EmptyDeclaration => "E0010",
+            ErrorCode::StackUnderflow => "E0011",
+            ErrorCode::TypeMismatch => "E0012",
+            ErrorCode::PointerArithmetic => "E0013",
+            ErrorCode::InvalidInteger => "E0014",
+            ErrorCode::FormatString => "E0015",
+            ErrorCode::RequiresUnsafe => "E0016",
+            ErrorCode::Unsupported => "E0017",
+            ErrorCode::ExternSignature => "E0018",
+            ErrorCode::ExternLoad => "E0019",
+            ErrorCode::NotAllowedInComptime => "E0020",
+            ErrorCode::ComptimeFailed => "E0021",
+            ErrorCode::RuntimeFailure => "E0022",
+            ErrorCode::PermissionDenied => "E0023",
+            ErrorCode::StepLimit => "E0024",
+            ErrorCode::Timeout => "E0025",
+            ErrorCode::UnhandledData => "E0026",
+            ErrorCode::IncludeFailed => "E0027",
+            ErrorCode::DivisionByZero => "E0028",
+            ErrorCode::Interrupted => "E0029",
+            ErrorCode::Overflow => "E0030",
+            ErrorCode::InvalidArgument => "E0031",
+            ErrorCode::OutOfBounds => "E0032",
+            ErrorCode::InvalidAccess => "E0033",
+            ErrorCode::AllocationFailed => "E0034",
+            ErrorCode::IoFailed => "E0035",
+            ErrorCode::InvalidOperand => "E0036",
+        }
+    }
+
+    // Accepts the code with or without its leading 'E', e.g. "E0012", "e0012" or "12".
+    pub fn from_code(code: &str) -> Option<Self> {
+        let digits = code.strip_prefix(['E', 'e']).unwrap_or(code);
+        let number = digits.parse::<usize>().ok()?;
+        ALL.iter().copied().find(|error_code| error_code.code()[1..].parse::<usize>() == Ok(number))
+    }
+
+    // A longer description of the error for `px2 explain`, with an example of the failing
+    // pattern and how to fix it.
+    pub fn explanation(self) -> &'static str {
+        match self {
+            ErrorCode::InvalidToken => "\
+The source contains text that is not a valid token, such as a string literal that
+is never closed or an escape sequence px2 does not know.
+
+    \"hello println
+
+Close the string, and only use the escapes \\n, \\t, \\r, \\0, \\\\, \\\", \\xNN for an
+ASCII character and \\u{NNNN} for any other character:
+
+    \"hello\" println",
+
+            ErrorCode::UnexpectedToken => "\
+A declaration expected a particular kind of token next, such as a type or a
+length, and found something else.
+
+    var count 3
+
+Give the variable a type after its name:
+
+    var count int",
+
+            ErrorCode::MisplacedWord => "\
+A word that only has meaning inside another construct was used on its own, such
+as 'as' outside an array declaration, '--' outside an extern declaration, 'else'
+outside a 'when' block or an 'end' that closes nothing.
+
+    1 2 + end
+
+Remove the stray word, or add the construct it belongs to:
+
+    module maths
+        1 2 +
+    end",
+
+            ErrorCode::MissingEnd => "\
+A module, comptime or when block was still open when the file ended.
+
+    module maths
+        pub var total int
+
+Close it with 'end':
+
+    module maths
+        pub var total int
+    end",
+
+            ErrorCode::UnknownName => "\
+A word, variable, array, module, member or attribute was used that has not been defined.
+
+    @totl println
+
+Check the spelling, and that the name is declared before it is used:
+
+    var total int
+    @total println",
+
+            ErrorCode::PrivateItem => "\
+An item declared inside a module was used from outside that module, but it is
+not marked 'pub'.
+
+    module counter
+        var count int
+    end
+    @counter::count println
+
+Mark the item 'pub' so it can be used outside of the module:
+
+    module counter
+        pub var count int
+    end
+    @counter::count println",
+
+            ErrorCode::AlreadyDefined => "\
+A name was declared twice in the same scope, a struct has two fields with the
+same name, an enum has two variants with the same name, or a declaration reuses
+the name of a builtin.
+
+    var total int
+    var total int
+
+Give each declaration its own name:
+
+    var total int
+    var subtotal int
+
+A name from an enclosing module may be declared again inside a module, where it
+hides the outer one, so that is not an error.",
+
+            ErrorCode::InvalidName => "\
+A declaration used a name that cannot be declared, either because it contains
+'.' or ':', which are used to reach into structs, enums and modules, or because
+it is the name of a type.
+
+    var int int
+
+Choose a name that is not a type and has no '.' or ':' in it:
+
+    var count int",
+
+            ErrorCode::MisusedName => "\
+A variable, array, struct or enum name was used on its own as though it were a
+word. Each kind of name has its own syntax.
+
+    var total int
+    total println
+
+Load a variable with '@' and store to it with '!':
+
+    var total int
+    @total println",
+
+            ErrorCode::EmptyDeclaration => "\
+A struct was declared without any fields, or an enum without any variants.
+
+    enum Colour end
+
+Give it at least one field or variant:
+
+    enum Colour Red Green Blue end",
+
+            ErrorCode::StackUnderflow => "\
+An op needs more values than there are on the stack at that point.
+
+    1 +
+
+Push every value the op needs before using it:
+
+    1 2 +",
+
+            ErrorCode::TypeMismatch => "\
+An op found a value of the wrong type on the stack. The message says which
+position on the stack was wrong, counting from the top.
+
+    \"1\" 2 +
+
+Convert the value to the type the op expects first:
+
+    \"1\" str->int drop 2 +
+
+Int and UInt values do not mix either, convert one of them with 'int->uint' or
+'uint->int', which keep the bits as they are:
+
+    5u 1 -
+    5u 1 int->uint -
+
+A Big mixes with an Int, but not with a UInt or a pointer.",
+
+            ErrorCode::PointerArithmetic => "\
+Pointers can be offset by integers and subtracted from each other, but adding two
+pointers or subtracting a pointer from an integer has no meaning.
+
+    var buffer ptr
+    4 alloc !buffer
+    @buffer @buffer +
+
+Add an integer offset instead:
+
+    @buffer 1 +",
+
+            ErrorCode::InvalidInteger => "\
+An integer literal could not be parsed, because it does not fit in a 64 bit
+signed integer or has letters or a '.' written straight after its digits.
+
+    99999999999999999999 println
+    3abc println
+
+Use a value between -9223372036854775808 and 9223372036854775807, with only an
+optional '+' or '-' sign before the digits:
+
+    9223372036854775807 println
+    -3 println",
+
+            ErrorCode::FormatString => "\
+'format' was used without a string literal directly before it, the literal is
+not a valid format string, or it has more '{}' placeholders than there are
+values on the stack below it.
+
+    1 \"{} and {}\" format println
+
+Push one value for each placeholder before the format string:
+
+    1 2 \"{} and {}\" format println",
+
+            ErrorCode::RequiresUnsafe => "\
+Words that touch the machine directly, such as syscalls and extern declarations,
+are only available when the program is run with --unsafe.
+
+    px2 syscalls.px2
+
+Run the program with --unsafe if you trust it:
+
+    px2 syscalls.px2 --unsafe",
+
+            ErrorCode::Unsupported => "\
+The program used a word that is not supported on this platform, such as a
+syscall on a system other than linux.
+
+There is no way to use the word on this platform, so the program has to avoid it,
+for example by using an extern function from the platform's C library instead.",
+
+            ErrorCode::ExternSignature => "\
+An extern declaration has a signature px2 cannot call, because it takes too many
+arguments or passes a 'ptr' to or from the function.
+
+    extern \"libc.so.6\" labs ptr -- int end
+
+Keep to at most the supported number of arguments, and use types other than 'ptr':
+
+    extern \"libc.so.6\" labs int -- int end",
+
+            ErrorCode::ExternLoad => "\
+The library named in an extern declaration could not be opened, or it has no
+function with the declared name.
+
+    extern \"libc.so\" labs int -- int end
+
+Check the library name and path, and that it exports the function:
+
+    extern \"libc.so.6\" labs int -- int end",
+
+            ErrorCode::NotAllowedInComptime => "\
+A comptime block is run while the program is compiled, so it cannot do anything
+that depends on the outside world, such as reading variables, calling extern
+functions or builtins, or performing io.
+
+    comptime 1 7 random-range end
+
+Move the word out of the comptime block so it runs with the program:
+
+    1 7 random-range",
+
+            ErrorCode::ComptimeFailed => "\
+A comptime block failed while it was being evaluated, either because one of its
+ops failed or because it ran for too long.
+
+    comptime 1 0 / end println
+
+Fix the failing op, which is pointed at by the error:
+
+    comptime 1 1 / end println",
+
+            ErrorCode::RuntimeFailure => "\
+An op failed while the program was running, in a way not covered by a more
+specific code, for example a builtin function registered by a host returning an
+error.
+
+The message says what went wrong. Check the values passed to the op before it
+runs.",
+
+            ErrorCode::PermissionDenied => "\
+The program tried to read environment variables, use the network or run
+commands without permission. Programs can only print to stdout unless they are
+allowed to do more.
+
+    px2 echo_server.px2
+
+Run the program with the --allow-env, --allow-net or --allow-run flag named in
+the error:
+
+    px2 echo_server.px2 --allow-net",
+
+            ErrorCode::StepLimit => "\
+The program executed more ops than the limit given with --max-ops, which
+usually means it is stuck in a loop.
+
+    px2 program.px2 --max-ops 1000
+
+Raise the limit if the program needs more ops, or fix the loop that never ends:
+
+    px2 program.px2 --max-ops 1000000",
+
+            ErrorCode::Timeout => "\
+The program ran for longer than the limit given with --timeout.
+
+    px2 program.px2 --timeout 10
+
+Raise the limit if the program needs more time, or find what it is waiting on:
+
+    px2 program.px2 --timeout 1000",
+
+            ErrorCode::UnhandledData => "\
+The program ended with values still on the stack. Every value a program pushes
+has to be used, so a leftover value usually means a missing 'drop' or 'println'.
+
+    \"12\" str->int println
+
+'str->int' pushes whether the string was parsed on top of the number, print or
+drop both of them:
+
+    \"12\" str->int drop println",
+
+            ErrorCode::IncludeFailed => "\
+A file named by 'include' could not be loaded. Included files are looked up next
+to the file that includes them, then in each include path from px2.toml, then in
+those given with --include or -I, then in the directories listed in the PX2_PATH
+environment variable. The first file found is used, and when none is the error
+lists every path that was tried. A file cannot include itself, directly or
+through other files.
+
+    include \"shapes.px2\"
+
+Check the file exists, and add the directory it is in to the include paths:
+
+    px2 main.px2 -I lib
+    PX2_PATH=~/px2/lib px2 main.px2",
+            ErrorCode::DivisionByZero => "\
+A '/' or 'divmod' divided by zero. The compiler reports this when the divisor is a
+zero literal or constant, and the program stops with it when a computed divisor
+turns out to be zero.
+
+    10 0 /
+
+Divide by a value that is not zero, or check a computed divisor before dividing:
+
+    10 2 /",
+            ErrorCode::Interrupted => "\
+The program was stopped with Ctrl-C. It stops between two ops, after printing
+everything it had printed so far. Pressing Ctrl-C again while an op is waiting,
+e.g. in tcp-accept, stops px2 straight away.
+
+With --snapshot the state of the program is saved, and the run can be carried on
+from where it was stopped:
+
+    px2 program.px2 --snapshot state.bin
+    px2 program.px2 --resume state.bin",
+            ErrorCode::Overflow => "\
+An int operation gave a result too big or too small to fit in an int, so the
+program stopped instead of carrying on with a wrong value.
+
+    9223372036854775807 1 + println
+
+Use a big when the values can grow past the int range, or the '?' form of the op
+when wrapping around is what you want:
+
+    9223372036854775807 int->big 1 + println
+    9223372036854775807 1 +? drop println",
+            ErrorCode::InvalidArgument => "\
+An op was given a value it cannot work with, such as a negative power, a shift of
+64 bits or more, an empty range for random, or a string holding a nul byte passed
+to an extern function.
+
+    2 -1 pow println
+
+Check the value before passing it to the op:
+
+    2 1 pow println",
+            ErrorCode::OutOfBounds => "\
+An index or address was outside the array, string or memory it was used on.
+
+    array 3 int as squares
+    3 @squares println
+
+Keep indices below the length, e.g. for an array of 3 the last index is 2:
+
+    array 3 int as squares
+    2 @squares println",
+            ErrorCode::InvalidAccess => "\
+Memory was read after it was freed, or read as a different type than the one
+stored there.
+
+    var buffer ptr
+    1 alloc !buffer
+    @buffer free
+    @buffer @int println
+
+Only use heap memory between its alloc and its free, and read it with the type it
+was written with.",
+            ErrorCode::AllocationFailed => "\
+The heap could not give out the memory asked for, or was given an address it did
+not hand out, for example by freeing the same memory twice.
+
+    var buffer ptr
+    1 alloc !buffer
+    @buffer free
+    @buffer free
+
+Free each allocation exactly once, and ask for a size the heap can hold.",
+            ErrorCode::IoFailed => "\
+Reading or writing outside the program failed, for example running a command with
+exec, or connecting to, reading from or writing to a tcp socket.
+
+    \"localhost:1\" tcp-connect drop
+
+The message says what the operating system reported. Check that the address,
+file or command exists and can be used by the user running px2.",
+            ErrorCode::InvalidOperand => "\
+In checked mode an op did not find the values it takes on the stack, or found
+values of the wrong type. Programs compiled by px2 are type checked and skip
+these checks, so this only comes from ops pushed by a host embedding the VM.
+
+Make sure each op pushed by the host has its operands, with their types, on the
+stack when it runs.",
+        }
+    }
+}
+
+impl From<ErrorKind> for ErrorCode {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::Failed => ErrorCode::RuntimeFailure,
+            ErrorKind::DivisionByZero => ErrorCode::DivisionByZero,
+            ErrorKind::Overflow => ErrorCode::Overflow,
+            ErrorKind::InvalidArgument => ErrorCode::InvalidArgument,
+            ErrorKind::OutOfBounds => ErrorCode::OutOfBounds,
+            ErrorKind::InvalidAccess => ErrorCode::InvalidAccess,
+            ErrorKind::Allocation => ErrorCode::AllocationFailed,
+            ErrorKind::Io|ErrorKind::BrokenPipe => ErrorCode::IoFailed,
+            ErrorKind::InvalidOperand => ErrorCode::InvalidOperand,
+            ErrorKind::StepLimit => ErrorCode::StepLimit,
+            ErrorKind::Timeout => ErrorCode::Timeout,
+            ErrorKind::Denied => ErrorCode::PermissionDenied,
+            ErrorKind::Interrupted => ErrorCode::Interrupted,
+        }
+    }
+}
+
+// Prints the explanation of an error code for `px2 explain`, returning false when the
+// code is not one of px2's.
+pub fn explain(code: &str) -> bool {
+    match ErrorCode::from_code(code) {
+        Some(error_code) => {
+            println!("{}\n\n{}", error_code.code(), error_code.explanation());
+            true
+        }
+        None => {
+            eprintln!("'{}' is not a px2 error code", code);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_unique_and_found_again() {
+        for (i, error_code) in ALL.iter().enumerate() {
+            assert_eq!(error_code.code(), format!("E{:04}", i + 1));
+            assert_eq!(ErrorCode::from_code(error_code.code()), Some(*error_code));
+        }
+        assert_eq!(ErrorCode::from_code("e12"), Some(ErrorCode::TypeMismatch));
+        assert_eq!(ErrorCode::from_code("E0000"), None);
+        assert_eq!(ErrorCode::from_code(&format!("E{:04}", ALL.len() + 1)), None);
+        assert_eq!(ErrorCode::from_code("nonsense"), None);
+    }
+
+    #[test]
+    fn runtime_kinds_have_their_own_codes() {
+        let kinds = [
+            ErrorKind::Failed, ErrorKind::DivisionByZero, ErrorKind::Overflow, ErrorKind::InvalidArgument, ErrorKind::OutOfBounds,
+            ErrorKind::InvalidAccess, ErrorKind::Allocation, ErrorKind::Io, ErrorKind::InvalidOperand, ErrorKind::StepLimit,
+            ErrorKind::Timeout, ErrorKind::Denied, ErrorKind::Interrupted,
+        ];
+        let codes: Vec<ErrorCode> = kinds.iter().map(|kind| ErrorCode::from(*kind)).collect();
+        for (i, code) in codes.iter().enumerate() {
+            assert!(!codes[i + 1..].contains(code), "{:?} shares {}", kinds[i], code.code());
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use crate::vm::DataType;
+
+// Calls go through a fixed set of signatures rather than libffi: every argument is
+// passed as a 64-bit integer register, so an extern can take up to this many.
+pub const MAX_ARGS: usize = 6;
+
+// int is passed as int64_t, bool as 0 or 1 and str as a NUL-terminated char pointer.
+// Returns are read as int64_t, bool (low byte) or a char pointer that is copied.
+pub struct ExternFunction {
+    pub name: String,
+    pub address: usize,
+    pub params: Vec<DataType>,
+    pub returns: Option<DataType>,
+}
+
+#[cfg(unix)]
+mod dl {
+    use std::os::raw::{c_char, c_int, c_void};
+
+    pub const RTLD_NOW: c_int = 2;
+
+    #[cfg_attr(target_os = "linux", link(name = "dl"))]
+    extern "C" {
+        pub fn dlopen(filename: *const c_char, flag: c_int) -> *mut c_void;
+        pub fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+        pub fn dlerror() -> *mut c_char;
+    }
+}
+
+// Looks the symbol up when the extern is declared, so a typo is a compile error.
+// Libraries are never closed, the address stays valid for the rest of the run.
+#[cfg(unix)]
+pub fn load(library: &str, symbol: &str) -> Result<usize, String> {
+    use std::ffi::{CStr, CString};
+
+    let library_name = CString::new(library).map_err(|_| "library name cannot contain NUL".to_string())?;
+    let symbol_name = CString::new(symbol).map_err(|_| "symbol name cannot contain NUL".to_string())?;
+
+    unsafe {
+        let last_error = || {
+            let message = dl::dlerror();
+            if message.is_null() {
+                "unknown error".to_string()
+            } else {
+                CStr::from_ptr(message).to_string_lossy().into_owned()
+            }
+        };
+
+        let handle = dl::dlopen(library_name.as_ptr(), dl::RTLD_NOW);
+        if handle.is_null() {
+            return Err(format!("cannot load library '{}': {}", library, last_error()));
+        }
+
+        let address = dl::dlsym(handle, symbol_name.as_ptr());
+        if address.is_null() {
+            return Err(format!("cannot find '{}' in '{}': {}", symbol, library, last_error()));
+        }
+
+        Ok(address as usize)
+    }
+}
+
+#[cfg(not(unix))]
+pub fn load(_library: &str, _symbol: &str) -> Result<usize, String> {
+    Err("extern functions are only supported on unix".to_string())
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    #[link_name = "syscall"]
+    fn libc_syscall(number: i64, ...) -> i64;
+}
+
+// Returns the kernel's result, with failures as the negated errno like the raw syscall.
+// Safety: the caller is running arbitrary syscalls, there is nothing to check here.
+#[cfg(target_os = "linux")]
+pub unsafe fn syscall(number: i64, args: &[i64]) -> i64 {
+    let result = match *args {
+        [] => libc_syscall(number),
+        [a] => libc_syscall(number, a),
+        [a, b] => libc_syscall(number, a, b),
+        [a, b, c] => libc_syscall(number, a, b, c),
+        [a, b, c, d] => libc_syscall(number, a, b, c, d),
+        [a, b, c, d, e] => libc_syscall(number, a, b, c, d, e),
+        [a, b, c, d, e, f] => libc_syscall(number, a, b, c, d, e, f),
+        _ => unreachable!("syscall arity is checked in the compiler"),
+    };
+
+    if result == -1 {
+        -(std::io::Error::last_os_error().raw_os_error().unwrap_or(0) as i64)
+    } else {
+        result
+    }
+}
+
+// Safety: address must point to a C function taking args.len() integer sized
+// arguments, which is what the declared signature promises.
+pub unsafe fn call(address: usize, args: &[i64]) -> i64 {
+    type F0 = extern "C" fn() -> i64;
+    type F1 = extern "C" fn(i64) -> i64;
+    type F2 = extern "C" fn(i64, i64) -> i64;
+    type F3 = extern "C" fn(i64, i64, i64) -> i64;
+    type F4 = extern "C" fn(i64, i64, i64, i64) -> i64;
+    type F5 = extern "C" fn(i64, i64, i64, i64, i64) -> i64;
+    type F6 = extern "C" fn(i64, i64, i64, i64, i64, i64) -> i64;
+
+    let pointer = address as *const ();
+    match *args {
+        [] => std::mem::transmute::<*const (), F0>(pointer)(),
+        [a] => std::mem::transmute::<*const (), F1>(pointer)(a),
+        [a, b] => std::mem::transmute::<*const (), F2>(pointer)(a, b),
+        [a, b, c] => std::mem::transmute::<*const (), F3>(pointer)(a, b, c),
+        [a, b, c, d] => std::mem::transmute::<*const (), F4>(pointer)(a, b, c, d),
+        [a, b, c, d, e] => std::mem::transmute::<*const (), F5>(pointer)(a, b, c, d, e),
+        [a, b, c, d, e, f] => std::mem::transmute::<*const (), F6>(pointer)(a, b, c, d, e, f),
+        _ => unreachable!("extern arity is checked in the compiler"),
+    }
+}
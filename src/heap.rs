@@ -0,0 +1,299 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::vm::{DataType, Value};
+
+pub const DEFAULT_HEAP_SIZE: usize = 64 * 1024;
+
+// The heap lives in the VM's memory directly after the data segment and grows
+// on demand up to `size` cells. Allocation is first-fit over the live blocks.
+pub struct Heap {
+    start: usize,
+    size: usize,
+    // address => length of every live allocation
+    blocks: BTreeMap<usize, usize>,
+    // addresses of freed blocks, so a second free can be reported as such
+    freed: HashSet<usize>,
+    // total length of the live allocations
+    cells_in_use: usize,
+}
+
+impl Heap {
+    pub fn new(start: usize, size: usize) -> Self {
+        Heap {
+            start,
+            size,
+            blocks: BTreeMap::new(),
+            freed: HashSet::new(),
+            cells_in_use: 0,
+        }
+    }
+
+    // A heap with the allocations a snapshot was taken with.
+    pub fn restore(start: usize, size: usize, blocks: &[(usize, usize)], freed: &[usize]) -> Self {
+        Heap {
+            start,
+            size,
+            blocks: blocks.iter().copied().collect(),
+            freed: freed.iter().copied().collect(),
+            cells_in_use: blocks.iter().map(|(_, length)| length).sum(),
+        }
+    }
+
+    pub fn blocks(&self) -> Vec<(usize, usize)> {
+        self.blocks.iter().map(|(address, length)| (*address, *length)).collect()
+    }
+
+    pub fn freed(&self) -> Vec<usize> {
+        self.freed.iter().copied().collect()
+    }
+
+    pub fn cells_in_use(&self) -> usize {
+        self.cells_in_use
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        address >= self.start
+    }
+
+    pub fn is_live(&self, address: usize) -> bool {
+        match self.blocks.range(..=address).next_back() {
+            Some((start, length)) => address < start + length,
+            None => false,
+        }
+    }
+
+    pub fn alloc(&mut self, memory: &mut Vec<Value>, count: i64) -> Result<usize, String> {
+        if count <= 0 {
+            return Err(format!("cannot allocate {} cells", count));
+        }
+
+        let count = count as usize;
+        let address = self.find_gap(count, None).ok_or_else(|| self.out_of_memory(count))?;
+        Self::initialise(memory, address, count);
+        self.blocks.insert(address, count);
+        self.freed.remove(&address);
+        self.cells_in_use += count;
+        Ok(address)
+    }
+
+    pub fn free(&mut self, address: i64) -> Result<(), String> {
+        let address = self.live_block(address, "free")?;
+        self.cells_in_use -= self.blocks.remove(&address).unwrap();
+        self.freed.insert(address);
+        Ok(())
+    }
+
+    pub fn realloc(&mut self, memory: &mut Vec<Value>, address: i64, count: i64) -> Result<usize, String> {
+        let old_address = self.live_block(address, "realloc")?;
+        if count <= 0 {
+            return Err(format!("cannot reallocate to {} cells", count));
+        }
+
+        let count = count as usize;
+        let old_length = self.blocks[&old_address];
+        // the block being resized counts as free space, so it can grow or shrink in place
+        let new_address = self.find_gap(count, Some(old_address)).ok_or_else(|| self.out_of_memory(count))?;
+        let kept = old_length.min(count);
+
+        if memory.len() < new_address + count {
+            memory.resize(new_address + count, Value::default_of(DataType::Int));
+        }
+        memory.copy_within(old_address..old_address + kept, new_address);
+        Self::initialise(memory, new_address + kept, count - kept);
+
+        self.blocks.remove(&old_address);
+        if new_address != old_address {
+            self.freed.insert(old_address);
+        }
+        self.blocks.insert(new_address, count);
+        self.freed.remove(&new_address);
+        self.cells_in_use = self.cells_in_use - old_length + count;
+        Ok(new_address)
+    }
+
+    fn live_block(&self, address: i64, action: &str) -> Result<usize, String> {
+        if address >= 0 && self.blocks.contains_key(&(address as usize)) {
+            return Ok(address as usize);
+        }
+
+        if address >= 0 && self.freed.contains(&(address as usize)) {
+            Err(format!("cannot {} pointer {}, it has already been freed", action, address))
+        } else {
+            Err(format!("cannot {} pointer {}, it was not returned by alloc", action, address))
+        }
+    }
+
+    fn find_gap(&self, count: usize, ignoring: Option<usize>) -> Option<usize> {
+        let mut candidate = self.start;
+        for (&address, &length) in self.blocks.iter() {
+            if Some(address) == ignoring {
+                continue;
+            }
+            if address - candidate >= count {
+                return Some(candidate);
+            }
+            candidate = address + length;
+        }
+
+        if self.start + self.size - candidate >= count {
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+
+    fn initialise(memory: &mut Vec<Value>, address: usize, count: usize) {
+        // fresh cells read as Int 0 until something else is stored in them
+        if memory.len() < address + count {
+            memory.resize(address + count, Value::default_of(DataType::Int));
+        }
+        memory[address..address + count].fill(Value::default_of(DataType::Int));
+    }
+
+    fn out_of_memory(&self, count: usize) -> String {
+        format!("out of heap memory, cannot allocate {} cells with a heap size of {}", count, self.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Heap;
+    use crate::vm::Value;
+
+    const START: usize = 4;
+
+    // A heap of size cells after a data segment of START cells.
+    fn heap(size: usize) -> (Heap, Vec<Value>) {
+        (Heap::new(START, size), vec![Value::from_int(-1); START])
+    }
+
+    fn fill(memory: &mut [Value], address: usize, values: &[i64]) {
+        for (offset, value) in values.iter().enumerate() {
+            memory[address + offset] = Value::from_int(*value);
+        }
+    }
+
+    fn read(memory: &[Value], address: usize, count: usize) -> Vec<i64> {
+        memory[address..address + count].iter().map(|value| value.as_int()).collect()
+    }
+
+    #[test]
+    fn allocates_first_fit_after_the_data_segment() {
+        let (mut heap, mut memory) = heap(16);
+        assert_eq!(heap.alloc(&mut memory, 3), Ok(START));
+        assert_eq!(heap.alloc(&mut memory, 2), Ok(START + 3));
+        assert_eq!(read(&memory, START, 5), [0; 5]);
+        assert_eq!(heap.cells_in_use(), 5);
+        assert!(heap.is_live(START + 4));
+        assert!(!heap.is_live(START + 5));
+        // the data segment is left alone
+        assert_eq!(read(&memory, 0, START), [-1; START]);
+
+        assert!(heap.alloc(&mut memory, 0).is_err());
+        assert!(heap.alloc(&mut memory, -1).is_err());
+    }
+
+    #[test]
+    fn reuses_freed_neighbours_as_one_gap() {
+        let (mut heap, mut memory) = heap(16);
+        let a = heap.alloc(&mut memory, 2).unwrap();
+        let b = heap.alloc(&mut memory, 3).unwrap();
+        let c = heap.alloc(&mut memory, 1).unwrap();
+        fill(&mut memory, a, &[1, 2]);
+
+        heap.free(a as i64).unwrap();
+        heap.free(b as i64).unwrap();
+        assert_eq!(heap.cells_in_use(), 1);
+        // the two freed blocks together hold five cells, which are cleared when handed out
+        assert_eq!(heap.alloc(&mut memory, 5), Ok(a));
+        assert_eq!(read(&memory, a, 5), [0; 5]);
+        // a block too big for any gap goes after the last one
+        heap.free(a as i64).unwrap();
+        assert_eq!(heap.alloc(&mut memory, 6), Ok(c + 1));
+    }
+
+    #[test]
+    fn rejects_freeing_twice_or_what_alloc_did_not_return() {
+        let (mut heap, mut memory) = heap(16);
+        let a = heap.alloc(&mut memory, 2).unwrap() as i64;
+        heap.free(a).unwrap();
+        assert_eq!(heap.free(a), Err(format!("cannot free pointer {}, it has already been freed", a)));
+        assert_eq!(heap.realloc(&mut memory, a, 4), Err(format!("cannot realloc pointer {}, it has already been freed", a)));
+        for address in [a + 1, 0, -1] {
+            assert_eq!(heap.free(address), Err(format!("cannot free pointer {}, it was not returned by alloc", address)));
+        }
+
+        // allocating at the address again makes it live, so it can be freed again
+        assert_eq!(heap.alloc(&mut memory, 1), Ok(a as usize));
+        assert_eq!(heap.free(a), Ok(()));
+    }
+
+    #[test]
+    fn realloc_grows_and_shrinks_keeping_the_contents() {
+        let (mut heap, mut memory) = heap(16);
+        let a = heap.alloc(&mut memory, 3).unwrap();
+        fill(&mut memory, a, &[1, 2, 3]);
+
+        // nothing follows the block, so it grows in place with the new cells cleared
+        assert_eq!(heap.realloc(&mut memory, a as i64, 5), Ok(a));
+        assert_eq!(read(&memory, a, 5), [1, 2, 3, 0, 0]);
+        assert_eq!(heap.realloc(&mut memory, a as i64, 2), Ok(a));
+        assert_eq!(read(&memory, a, 2), [1, 2]);
+        assert_eq!(heap.cells_in_use(), 2);
+
+        // a block following it makes it move, and the old address is then freed
+        let b = heap.alloc(&mut memory, 1).unwrap();
+        assert_eq!(b, a + 2);
+        let moved = heap.realloc(&mut memory, a as i64, 4).unwrap();
+        assert_eq!(moved, b + 1);
+        assert_eq!(read(&memory, moved, 4), [1, 2, 0, 0]);
+        assert!(heap.free(a as i64).is_err());
+        assert_eq!(heap.cells_in_use(), 5);
+    }
+
+    #[test]
+    fn realloc_moves_into_a_gap_overlapping_the_old_block() {
+        let (mut heap, mut memory) = heap(16);
+        let a = heap.alloc(&mut memory, 2).unwrap();
+        let b = heap.alloc(&mut memory, 3).unwrap();
+        let c = heap.alloc(&mut memory, 1).unwrap();
+        fill(&mut memory, b, &[7, 8, 9]);
+        heap.free(a as i64).unwrap();
+
+        // the gap before b and b itself make room for four cells starting at a
+        assert_eq!(heap.realloc(&mut memory, b as i64, 4), Ok(a));
+        assert_eq!(read(&memory, a, 4), [7, 8, 9, 0]);
+        assert!(heap.is_live(c));
+        assert_eq!(heap.cells_in_use(), 5);
+    }
+
+    #[test]
+    fn runs_out_of_memory() {
+        let (mut heap, mut memory) = heap(8);
+        let a = heap.alloc(&mut memory, 6).unwrap();
+        let error = "out of heap memory, cannot allocate 3 cells with a heap size of 8".to_string();
+        assert_eq!(heap.alloc(&mut memory, 3), Err(error.clone()));
+        assert_eq!(heap.alloc(&mut memory, 2), Ok(a + 6));
+        assert!(heap.alloc(&mut memory, 1).is_err());
+        assert_eq!(heap.realloc(&mut memory, a as i64, 9), Err("out of heap memory, cannot allocate 9 cells with a heap size of 8".to_string()));
+        // a failed realloc leaves the block as it was
+        assert!(heap.is_live(a + 5));
+        assert_eq!(heap.cells_in_use(), 8);
+        assert!(heap.realloc(&mut memory, a as i64, 0).is_err());
+        assert!(heap.alloc(&mut memory, i64::MAX).is_err());
+    }
+
+    #[test]
+    fn restores_from_a_snapshot() {
+        let (mut heap, mut memory) = heap(16);
+        let a = heap.alloc(&mut memory, 2).unwrap();
+        let b = heap.alloc(&mut memory, 3).unwrap();
+        heap.free(a as i64).unwrap();
+
+        let mut restored = Heap::restore(START, 16, &heap.blocks(), &heap.freed());
+        assert_eq!(restored.cells_in_use(), 3);
+        assert!(restored.free(a as i64).is_err());
+        assert_eq!(restored.alloc(&mut memory, 2), Ok(a));
+        assert_eq!(restored.free(b as i64), Ok(()));
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::condition;
+use crate::diagnostics::Diagnostic;
+use crate::error_code::ErrorCode;
+use crate::scanner::{self, Scanner, TokenType};
+use crate::source_map::SourceMap;
+use crate::span::{FileId, Span};
+
+// Loads every file reachable through 'include "<path>"' from the main file into the
+// source map. Returns the files in the order they are compiled in, each file after the
+// files it includes, so a file included from several places is only compiled once.
+//
+// An include path is looked up next to the file that includes it first, then in each of
+// the include directories in order, the first file found being the one included. Includes
+// in a 'when' branch that is left out are not loaded.
+pub fn load_includes(sources: &mut SourceMap, main: FileId, include_paths: &[String], defines: &[String], out: &mut dyn Write) -> Option<Vec<FileId>> {
+    let mut loader = Loader {
+        include_paths,
+        defines,
+        loaded: HashMap::new(),
+        in_progress: Vec::new(),
+        order: Vec::new(),
+        had_error: false,
+    };
+
+    let canonical = fs::canonicalize(sources.path(main)).unwrap_or_else(|_| PathBuf::from(sources.path(main)));
+    loader.loaded.insert(canonical.clone(), main);
+    loader.visit(sources, main, canonical, out);
+
+    if loader.had_error {
+        None
+    } else {
+        Some(loader.order)
+    }
+}
+
+struct Loader<'a> {
+    include_paths: &'a [String],
+    defines: &'a [String],
+    // keyed by canonical path, so the same file reached through different paths is loaded once
+    loaded: HashMap<PathBuf, FileId>,
+    // the chain of files currently being loaded, to catch a file that includes itself
+    in_progress: Vec<PathBuf>,
+    order: Vec<FileId>,
+    had_error: bool,
+}
+
+impl<'a> Loader<'a> {
+    fn visit(&mut self, sources: &mut SourceMap, file: FileId, canonical: PathBuf, out: &mut dyn Write) {
+        self.in_progress.push(canonical);
+
+        for (span, include) in self.find_includes(sources, file, out) {
+            let path = match self.resolve(sources.path(file), &include) {
+                Ok(path) => path,
+                Err(tried) => {
+                    let message = format!("cannot find '{}' next to this file or in the include paths", include);
+                    let diagnostic = tried.iter().fold(Diagnostic::error(ErrorCode::IncludeFailed, span, message), |diagnostic, path| {
+                        diagnostic.note(format!("tried '{}'", path.display()))
+                    });
+                    self.had_error = true;
+                    diagnostic.emit(sources, out);
+                    continue;
+                }
+            };
+
+            let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+            if self.in_progress.contains(&canonical) {
+                let message = format!("'{}' includes itself", include);
+                self.error(sources, span, message, out);
+                continue;
+            }
+            if self.loaded.contains_key(&canonical) {
+                continue;
+            }
+
+            let code = match fs::read_to_string(&path) {
+                Ok(code) => code,
+                Err(error) => {
+                    let message = format!("cannot read '{}': {}", path.display(), error);
+                    self.error(sources, span, message, out);
+                    continue;
+                }
+            };
+
+            let included = sources.add(path.to_string_lossy().into_owned(), code);
+            self.loaded.insert(canonical.clone(), included);
+            self.visit(sources, included, canonical, out);
+        }
+
+        self.in_progress.pop();
+        self.order.push(file);
+    }
+
+    // The path of every include in the file, with the span of the path to report errors at.
+    fn find_includes(&mut self, sources: &SourceMap, file: FileId, out: &mut dyn Write) -> Vec<(Span, String)> {
+        let mut includes = Vec::new();
+        let mut scanner = Scanner::new(sources.code(file), file);
+        while let Some(token) = scanner.next() {
+            match token.token_type {
+                // the compiler reports a when or else in the wrong place, here they only pick the branch
+                TokenType::When => {
+                    let name = scanner.scan_token();
+                    if !condition::is_defined(name.text, self.defines) {
+                        condition::skip_branch(&mut scanner);
+                    }
+                    continue;
+                }
+                TokenType::Else => {
+                    condition::skip_branch(&mut scanner);
+                    continue;
+                }
+                TokenType::Include => {}
+                _ => continue,
+            }
+
+            match scanner.expect(TokenType::String) {
+                Ok(path) => includes.push((path.span, scanner::string_contents(path.text).1.to_string())),
+                Err(found) => {
+                    let message = format!("expected a file path after 'include', found '{}'", found.text);
+                    self.error(sources, found.span, message, out);
+                }
+            }
+        }
+        includes
+    }
+
+    // The file found, or every path that was tried in the order they were tried.
+    fn resolve(&self, including_path: &str, include: &str) -> Result<PathBuf, Vec<PathBuf>> {
+        let next_to = Path::new(including_path).parent().unwrap_or(Path::new("")).join(include);
+        let mut tried = Vec::new();
+        for path in std::iter::once(next_to).chain(self.include_paths.iter().map(|directory| Path::new(directory).join(include))) {
+            if path.is_file() {
+                return Ok(path);
+            }
+            if !tried.contains(&path) {
+                tried.push(path);
+            }
+        }
+        Err(tried)
+    }
+
+    fn error(&mut self, sources: &SourceMap, span: Span, message: String, out: &mut dyn Write) {
+        self.had_error = true;
+        Diagnostic::error(ErrorCode::IncludeFailed, span, message).emit(sources, out);
+    }
+}
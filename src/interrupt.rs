@@ -0,0 +1,44 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Set by the SIGINT handler and read by the VM between ops, so Ctrl-C stops a program
+// at an op boundary with its buffered output flushed rather than killing it mid-write.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+mod signal {
+    use std::os::raw::c_int;
+
+    pub const SIGINT: c_int = 2;
+    pub const SIG_DFL: usize = 0;
+
+    extern "C" {
+        // the handler is a function pointer or SIG_DFL
+        pub fn signal(signum: c_int, handler: usize) -> usize;
+    }
+}
+
+// Makes Ctrl-C stop the running program instead of the process. Left to the px2 binary,
+// so a host embedding the VM keeps its own handling of SIGINT.
+#[cfg(unix)]
+pub fn install() {
+    unsafe {
+        signal::signal(signal::SIGINT, on_interrupt as extern "C" fn(std::os::raw::c_int) as usize);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install() {}
+
+// Only does what is safe in a signal handler. The next Ctrl-C kills the process as usual,
+// for a program stuck in a blocking op like tcp-accept that never reaches the next op.
+#[cfg(unix)]
+extern "C" fn on_interrupt(_signum: std::os::raw::c_int) {
+    REQUESTED.store(true, Ordering::Relaxed);
+    unsafe {
+        signal::signal(signal::SIGINT, signal::SIG_DFL);
+    }
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
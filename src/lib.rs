@@ -0,0 +1,28 @@
+pub mod bench;
+mod bigint;
+pub mod compiler;
+mod condition;
+mod coverage;
+mod diagnostics;
+mod dump;
+pub mod error_code;
+mod ffi;
+mod heap;
+mod include;
+pub mod interrupt;
+pub mod log;
+pub mod manifest;
+mod net;
+mod optimizer;
+mod random;
+pub mod scaffold;
+mod scanner;
+pub mod snapshot;
+mod source_map;
+mod stack;
+pub mod span;
+pub mod trace;
+pub mod vendor;
+pub mod vm;
+
+pub use heap::DEFAULT_HEAP_SIZE;
@@ -0,0 +1,39 @@
+use std::fmt::Display;
+
+// How much px2 reports about compiling and running a program, raised one level by
+// each -v. Every level includes the output of the levels below it. Logs go to stderr
+// so they never mix with the program's own output.
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    // only diagnostics
+    #[default]
+    Quiet,
+    // how long compiling and running took
+    Timing,
+    // the compiled ops
+    Ops,
+    // every token as it is scanned
+    Tokens,
+}
+
+impl Verbosity {
+    // Any count past the highest level is treated as the highest level.
+    pub fn from_count(count: usize) -> Self {
+        match count {
+            0 => Verbosity::Quiet,
+            1 => Verbosity::Timing,
+            2 => Verbosity::Ops,
+            _ => Verbosity::Tokens,
+        }
+    }
+
+    pub fn enabled(self, level: Verbosity) -> bool {
+        self >= level
+    }
+
+    pub fn log(self, level: Verbosity, message: impl Display) {
+        if self.enabled(level) {
+            eprintln!("{}", message);
+        }
+    }
+}
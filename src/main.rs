@@ -1,25 +1,364 @@
-mod compiler;
-mod scanner;
-mod vm;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use px2::bench;
+use px2::compiler;
+use px2::error_code;
+use px2::interrupt;
+use px2::log::Verbosity;
+use px2::manifest::Manifest;
+use px2::scaffold;
+use px2::vendor;
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    match args.len() {
-        2 => compiler::compile(&args[1], false),
-        3 => {
-            if args[2] == "--verbose" || args[2] == "-v" {
-                compiler::compile(&args[1], true);
-            } else {
+
+    if args.get(1).is_some_and(|arg| arg == "explain") {
+        match args.get(2) {
+            Some(code) if args.len() == 3 => {
+                if !error_code::explain(code) {
+                    std::process::exit(1);
+                }
+            }
+            _ => usage(),
+        }
+        return;
+    }
+
+    if args.get(1).is_some_and(|arg| arg == "new") {
+        match args.get(2) {
+            Some(name) if args.len() == 3 => match scaffold::new_project(name) {
+                Ok(()) => println!("Created project '{}', run it with 'cd {} && px2 run'", name, name),
+                Err(message) => {
+                    eprintln!("Error creating project: {}", message);
+                    std::process::exit(1);
+                }
+            },
+            _ => usage(),
+        }
+        return;
+    }
+
+    // px2 check <file_path>... compiles every file without running any of them
+    let checking = args.get(1).is_some_and(|arg| arg == "check");
+    // px2 build and px2 run take the main file and their settings from the project's px2.toml
+    let building = args.get(1).is_some_and(|arg| arg == "build");
+    let running = args.get(1).is_some_and(|arg| arg == "run");
+    // px2 bench <file_path> times repeated runs of the program
+    let benching = args.get(1).is_some_and(|arg| arg == "bench");
+    let mut bench_settings = bench::Settings::default();
+    let mut file_paths = Vec::new();
+    let mut verbose_count = 0;
+    let mut options = compiler::Options::default();
+
+    let mut manifest = None;
+    if building || running {
+        let found = std::env::current_dir()
+            .map_err(|error| format!("cannot read the current directory: {}", error))
+            .and_then(|directory| Manifest::find(&directory));
+        match found {
+            Ok(project) => {
+                project.configure(&mut options);
+                match vendor::fetch(&project) {
+                    Ok(include_paths) => options.include_paths.extend(include_paths),
+                    Err(message) => {
+                        eprintln!("Error fetching dependencies: {}", message);
+                        std::process::exit(1);
+                    }
+                }
+                file_paths.push(project.main.clone());
+                manifest = Some(project);
+            }
+            Err(message) => {
+                eprintln!("Error reading manifest: {}", message);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut args_iter = args.iter().skip(if checking || benching || manifest.is_some() { 2 } else { 1 });
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "--verbose" | "-v" => verbose_count += 1,
+            "-vv" => verbose_count += 2,
+            "-vvv" => verbose_count += 3,
+            "--unsafe" => options.allow_unsafe = true,
+            "--allow-env" => options.permissions.env = true,
+            "--allow-net" => options.permissions.net = true,
+            "--allow-run" => options.permissions.run = true,
+            "--no-buffer" => options.buffer_output = false,
+            "--no-fuse" => options.fuse_ops = false,
+            "-O2" => options.fold_program = true,
+            "--coverage" => options.coverage = true,
+            "--stats" => options.stats = true,
+            "--lcov" => {
+                match args_iter.next() {
+                    Some(path) => {
+                        options.coverage = true;
+                        options.lcov = Some(path.to_string());
+                    }
+                    None => {
+                        usage();
+                        return;
+                    }
+                }
+            }
+            "--heap-size" => {
+                match args_iter.next().and_then(|size| size.parse::<usize>().ok()) {
+                    Some(size) => options.heap_size = size,
+                    None => {
+                        usage();
+                        return;
+                    }
+                }
+            }
+            "--max-ops" => {
+                match args_iter.next().and_then(|max_ops| max_ops.parse::<usize>().ok()) {
+                    Some(max_ops) => options.max_ops = Some(max_ops),
+                    None => {
+                        usage();
+                        return;
+                    }
+                }
+            }
+            "--jobs" | "-j" => {
+                match args_iter.next().and_then(|jobs| jobs.parse::<usize>().ok()).filter(|jobs| *jobs > 0) {
+                    Some(jobs) => options.jobs = jobs,
+                    None => {
+                        usage();
+                        return;
+                    }
+                }
+            }
+            "--runs" | "--warmup" if benching => {
+                let count = match args_iter.next().and_then(|count| count.parse::<usize>().ok()) {
+                    Some(count) => count,
+                    None => {
+                        usage();
+                        return;
+                    }
+                };
+                if arg == "--runs" {
+                    bench_settings.runs = count;
+                } else {
+                    bench_settings.warmup = count;
+                }
+            }
+            "--baseline" | "--save-baseline" if benching => {
+                let path = match args_iter.next() {
+                    Some(path) => path.to_string(),
+                    None => {
+                        usage();
+                        return;
+                    }
+                };
+                if arg == "--baseline" {
+                    bench_settings.baseline = Some(path);
+                } else {
+                    bench_settings.save = Some(path);
+                }
+            }
+            "--timeout" => {
+                match args_iter.next().and_then(|ms| ms.parse::<u64>().ok()) {
+                    Some(ms) => options.timeout = Some(Duration::from_millis(ms)),
+                    None => {
+                        usage();
+                        return;
+                    }
+                }
+            }
+            "--define" | "-D" => {
+                match args_iter.next() {
+                    Some(name) => options.defines.push(name.to_string()),
+                    None => {
+                        usage();
+                        return;
+                    }
+                }
+            }
+            "--include" | "-I" => {
+                match args_iter.next() {
+                    Some(directory) => options.include_paths.push(directory.to_string()),
+                    None => {
+                        usage();
+                        return;
+                    }
+                }
+            }
+            "--record" | "--replay" => {
+                let path = match args_iter.next() {
+                    Some(path) => path.to_string(),
+                    None => {
+                        usage();
+                        return;
+                    }
+                };
+                if arg == "--record" {
+                    options.record = Some(path);
+                } else {
+                    options.replay = Some(path);
+                }
+            }
+            "--snapshot" | "--resume" => {
+                let path = match args_iter.next() {
+                    Some(path) => path.to_string(),
+                    None => {
+                        usage();
+                        return;
+                    }
+                };
+                if arg == "--snapshot" {
+                    options.snapshot = Some(path);
+                } else {
+                    options.resume = Some(path);
+                }
+            }
+            "--dump-ir" if !checking && !building && !benching => {
+                match args_iter.next().and_then(|format| compiler::DumpFormat::from_name(format)) {
+                    Some(format) => options.dump_ir = Some(format),
+                    None => {
+                        usage();
+                        return;
+                    }
+                }
+            }
+            "--stop-after" if !checking && !benching => {
+                match args_iter.next().and_then(|phase| compiler::Phase::from_name(phase)) {
+                    Some(phase) => options.stop_after = Some(phase),
+                    None => {
+                        usage();
+                        return;
+                    }
+                }
+            }
+            "--seed" => {
+                match args_iter.next().and_then(|seed| seed.parse::<u64>().ok()) {
+                    Some(seed) => options.seed = Some(seed),
+                    None => {
+                        usage();
+                        return;
+                    }
+                }
+            }
+            _ if manifest.is_none() && (checking || file_paths.is_empty()) && !arg.starts_with('-') => file_paths.push(arg.to_string()),
+            _ => {
                 usage();
+                return;
+            }
+        }
+    }
+
+    options.verbosity = Verbosity::from_count(verbose_count);
+    // searched after the include paths from px2.toml and the command line
+    if let Some(paths) = std::env::var_os("PX2_PATH") {
+        let paths = std::env::split_paths(&paths).filter(|path| !path.as_os_str().is_empty());
+        options.include_paths.extend(paths.map(|path| path.to_string_lossy().into_owned()));
+    }
+
+    if options.record.is_some() && options.replay.is_some() {
+        usage();
+        return;
+    }
+
+    if benching {
+        match file_paths.first() {
+            Some(file_path) if bench_settings.runs > 0 => {
+                if !bench::run(file_path, &options, &bench_settings) {
+                    std::process::exit(1);
+                }
             }
-        } 
-        _ => usage(),
+            _ => usage(),
+        }
+        return;
+    }
+
+    if checking && !file_paths.is_empty() {
+        check(&file_paths, &options);
+        return;
+    }
+
+    if let Some(project) = manifest.filter(|_| building) {
+        if !compiler::check(&project.main, &options) {
+            std::process::exit(1);
+        }
+        println!("Built {}", project.name);
+        return;
+    }
+
+    match file_paths.first() {
+        Some(file_path) => {
+            interrupt::install();
+            std::process::exit(compiler::compile(file_path, &options));
+        }
+        None => usage(),
+    }
+}
+
+fn check(patterns: &[String], options: &compiler::Options) {
+    let file_paths: Vec<String> = patterns.iter().flat_map(|pattern| expand_pattern(pattern)).collect();
+
+    let mut failed = 0;
+    for file_path in &file_paths {
+        if !compiler::check(file_path, options) {
+            failed += 1;
+        }
+    }
+
+    let noun = if file_paths.len() == 1 { "file" } else { "files" };
+    println!("Checked {} {}, {} failed", file_paths.len(), noun, failed);
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+// Expands '*' and '?' in the file name of a path, for shells that leave them alone. A
+// pattern that matches nothing is kept as it is, so it is reported as a missing file.
+fn expand_pattern(pattern: &str) -> Vec<String> {
+    let path = Path::new(pattern);
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) if name.contains(['*', '?']) => name.chars().collect::<Vec<char>>(),
+        _ => return vec![pattern.to_string()],
     };
+
+    let directory = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let mut matches: Vec<String> = match fs::read_dir(directory) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_str().is_some_and(|file_name| wildcard_match(&name, &file_name.chars().collect::<Vec<char>>())))
+            .map(|entry| path.with_file_name(entry.file_name()).to_string_lossy().into_owned())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+
+    if matches.is_empty() {
+        return vec![pattern.to_string()];
+    }
+    matches.sort();
+    matches
+}
+
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| wildcard_match(rest, &name[skip..])),
+        Some(('?', rest)) => !name.is_empty() && wildcard_match(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && wildcard_match(rest, &name[1..]),
+    }
 }
 
 fn usage() {
     println!("px2
 
 Usage:
-    px2 <file_path> [--verbose/-v]");
+    px2 <file_path> [--verbose/-v/-vv/-vvv] [--heap-size <cells>] [--seed <n>] [--unsafe]
+        [--max-ops <n>] [--timeout <ms>] [--no-buffer] [--no-fuse] [-O2] [--jobs/-j <n>] [--include/-I <directory>]
+        [--define/-D <name>] [--allow-env] [--allow-net] [--allow-run] [--coverage] [--lcov <path>] [--stats]
+        [--record <trace_path> | --replay <trace_path>]
+        [--snapshot <path>] [--resume <path>] [--dump-ir json|sexp] [--stop-after scan|check|opt|codegen]
+    px2 new <project_name>
+    px2 build|run [<options>]   (in a directory with a px2.toml, or below one)
+    px2 check <file_path>... [--verbose/-v/-vv/-vvv] [--unsafe] [--jobs/-j <n>] [--include/-I <directory>] [--define/-D <name>]
+    px2 bench <file_path> [--runs <n>] [--warmup <n>] [--baseline <json_path>] [--save-baseline <json_path>] [<options>]
+    px2 explain <error_code>");
 }
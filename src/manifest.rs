@@ -0,0 +1,281 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use crate::compiler::Options;
+
+pub const MANIFEST_NAME: &str = "px2.toml";
+
+// The settings of a px2 project, read from the px2.toml at its root, e.g.
+//
+//     [package]
+//     name = "asteroids"
+//     main = "src/main.px2"
+//     include = ["lib"]
+//
+//     [dependencies]
+//     vectors = { path = "../vectors" }
+//     sprites = { git = "https://example.com/sprites.git", rev = "v1.2" }
+//
+//     [build]
+//     heap-size = 65536
+//     allow-net = true
+//
+// Paths are relative to the directory the manifest is in. The build settings are the
+// same as the command line flags of the same name, which override them.
+pub struct Manifest {
+    pub root: PathBuf,
+    pub name: String,
+    pub main: String,
+    pub include: Vec<String>,
+    pub dependencies: Vec<Dependency>,
+    build: Vec<Entry>,
+}
+
+pub struct Dependency {
+    pub name: String,
+    pub source: Source,
+}
+
+pub enum Source {
+    Path(PathBuf),
+    // rev is a branch, tag or commit, the default branch when None
+    Git { url: String, rev: Option<String> },
+}
+
+// A key = value line, with the line number to report mistakes at.
+struct Entry {
+    section: String,
+    key: String,
+    setting: Setting,
+    line: usize,
+}
+
+enum Setting {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    List(Vec<String>),
+    // an inline table of strings, e.g. { path = "../vectors" }
+    Table(Vec<(String, String)>),
+}
+
+impl Manifest {
+    // Finds the manifest in the directory or the closest of its parents.
+    pub fn find(directory: &Path) -> Result<Manifest, String> {
+        let root = directory
+            .ancestors()
+            .find(|ancestor| ancestor.join(MANIFEST_NAME).is_file())
+            .ok_or_else(|| format!("could not find {} in {} or any of its parents", MANIFEST_NAME, directory.display()))?;
+        Manifest::load(root)
+    }
+
+    pub fn load(root: &Path) -> Result<Manifest, String> {
+        let path = root.join(MANIFEST_NAME);
+        let text = fs::read_to_string(&path).map_err(|error| format!("cannot read '{}': {}", path.display(), error))?;
+        let error = |line: usize, message: String| format!("{}:{}: {}", path.display(), line, message);
+
+        let mut name = None;
+        let mut main = None;
+        let mut include = Vec::new();
+        let mut dependencies = Vec::new();
+        let mut build = Vec::new();
+
+        for entry in parse(&text).map_err(|(line, message)| error(line, message))? {
+            let (key, line) = (entry.key.clone(), entry.line);
+            match (entry.section.as_str(), key.as_str(), &entry.setting) {
+                ("package", "name", Setting::Str(value)) => name = Some(value.clone()),
+                ("package", "main", Setting::Str(value)) => main = Some(root.join(value).to_string_lossy().into_owned()),
+                ("package", "include", Setting::List(values)) => {
+                    include = values.iter().map(|value| root.join(value).to_string_lossy().into_owned()).collect();
+                }
+                ("package", "name" | "main", _) => return Err(error(line, format!("'{}' must be a string", key))),
+                ("package", "include", _) => return Err(error(line, "'include' must be a list of strings".to_string())),
+                ("dependencies", _, Setting::Table(fields)) => {
+                    check_dependency_name(&key).map_err(|message| error(line, message))?;
+                    let source = dependency_source(root, fields).map_err(|message| error(line, message))?;
+                    dependencies.push(Dependency { name: key.clone(), source });
+                }
+                ("dependencies", _, _) => {
+                    return Err(error(line, format!("dependency '{}' must be a table such as {{ path = \"../{}\" }}", key, key)));
+                }
+                ("build", _, _) => build.push(entry),
+                (section, _, _) => return Err(error(line, format!("unknown key '{}' in [{}]", key, section))),
+            }
+        }
+
+        let manifest = Manifest {
+            root: root.to_path_buf(),
+            name: name.ok_or_else(|| format!("{}: [package] is missing 'name'", path.display()))?,
+            main: main.ok_or_else(|| format!("{}: [package] is missing 'main'", path.display()))?,
+            include,
+            dependencies,
+            build,
+        };
+
+        // checked up front so a mistake is reported before anything is compiled
+        manifest.apply(&mut Options::default()).map_err(|(line, message)| error(line, message))?;
+        Ok(manifest)
+    }
+
+    // Sets the options the manifest's build settings give, leaving the rest as they are.
+    pub fn configure(&self, options: &mut Options) {
+        // load has already checked every setting
+        let _ = self.apply(options);
+        options.include_paths.extend(self.include.iter().cloned());
+    }
+
+    fn apply(&self, options: &mut Options) -> Result<(), (usize, String)> {
+        for Entry { key, setting, line, .. } in &self.build {
+            let line = *line;
+            let flag = |value: &Setting| match value {
+                Setting::Bool(value) => Ok(*value),
+                _ => Err((line, format!("'{}' must be true or false", key))),
+            };
+            let count = |value: &Setting| match value {
+                Setting::Int(value) if *value >= 0 => Ok(*value as u64),
+                _ => Err((line, format!("'{}' must be a whole number", key))),
+            };
+
+            match key.as_str() {
+                "heap-size" => options.heap_size = count(setting)? as usize,
+                "seed" => options.seed = Some(count(setting)?),
+                "max-ops" => options.max_ops = Some(count(setting)? as usize),
+                "timeout" => options.timeout = Some(Duration::from_millis(count(setting)?)),
+                "unsafe" => options.allow_unsafe = flag(setting)?,
+                "allow-env" => options.permissions.env = flag(setting)?,
+                "allow-net" => options.permissions.net = flag(setting)?,
+                "allow-run" => options.permissions.run = flag(setting)?,
+                "buffer" => options.buffer_output = flag(setting)?,
+                "fuse" => options.fuse_ops = flag(setting)?,
+                "fold" => options.fold_program = flag(setting)?,
+                "jobs" => match count(setting)? {
+                    0 => return Err((line, "'jobs' must be at least 1".to_string())),
+                    jobs => options.jobs = jobs as usize,
+                },
+                _ => return Err((line, format!("unknown key '{}' in [build]", key))),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// A dependency is fetched into vendor/<name>, replacing what is there, so the name must
+// not reach outside of vendor/.
+fn check_dependency_name(name: &str) -> Result<(), String> {
+    let mut components = Path::new(name).components();
+    let plain = matches!(components.next(), Some(Component::Normal(_))) && components.next().is_none();
+    if !plain || name.contains(['/', '\\']) {
+        return Err(format!("dependency name '{}' must be a plain name, without '/', '\\', '.' or '..'", name));
+    }
+    Ok(())
+}
+
+fn dependency_source(root: &Path, fields: &[(String, String)]) -> Result<Source, String> {
+    let field = |name: &str| fields.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone());
+    if let Some((key, _)) = fields.iter().find(|(key, _)| !["path", "git", "rev"].contains(&key.as_str())) {
+        return Err(format!("unknown dependency key '{}', expected 'path', 'git' or 'rev'", key));
+    }
+
+    match (field("path"), field("git")) {
+        (Some(path), None) if field("rev").is_none() => Ok(Source::Path(root.join(path))),
+        (Some(_), None) => Err("'rev' can only be used with 'git'".to_string()),
+        // git would read either as one of its options
+        (None, Some(url)) if url.starts_with('-') => Err(format!("git url '{}' cannot start with '-'", url)),
+        (None, Some(_)) if field("rev").is_some_and(|rev| rev.starts_with('-')) => Err("'rev' cannot start with '-'".to_string()),
+        (None, Some(url)) => Ok(Source::Git { url, rev: field("rev") }),
+        _ => Err("a dependency needs exactly one of 'path' or 'git'".to_string()),
+    }
+}
+
+// Reads the part of TOML a manifest needs: [sections] and key = value lines, where a
+// value is a string, an integer, true or false, or a list or inline table of strings on
+// one line.
+fn parse(text: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut entries = Vec::new();
+    let mut section = String::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+            section = name.trim().to_string();
+            continue;
+        }
+
+        let (key, value) = line.split_once('=').ok_or((number, format!("expected 'key = value', found '{}'", line)))?;
+        if section.is_empty() {
+            return Err((number, format!("'{}' must be inside a section such as [package]", key.trim())));
+        }
+        let setting = parse_value(value.trim()).ok_or((number, format!("invalid value '{}'", value.trim())))?;
+        entries.push(Entry {
+            section: section.clone(),
+            key: key.trim().to_string(),
+            setting,
+            line: number,
+        });
+    }
+
+    Ok(entries)
+}
+
+fn parse_value(value: &str) -> Option<Setting> {
+    match value {
+        "true" => Some(Setting::Bool(true)),
+        "false" => Some(Setting::Bool(false)),
+        _ if value.starts_with('"') => parse_string(value).map(Setting::Str),
+        _ if value.starts_with('[') => {
+            let items = value.strip_prefix('[')?.strip_suffix(']')?.trim();
+            if items.is_empty() {
+                return Some(Setting::List(Vec::new()));
+            }
+            items
+                .trim_end_matches(',')
+                .split(',')
+                .map(|item| parse_string(item.trim()))
+                .collect::<Option<Vec<String>>>()
+                .map(Setting::List)
+        }
+        _ if value.starts_with('{') => {
+            let fields = value.strip_prefix('{')?.strip_suffix('}')?.trim();
+            if fields.is_empty() {
+                return Some(Setting::Table(Vec::new()));
+            }
+            fields
+                .split(',')
+                .map(|field| {
+                    let (key, value) = field.split_once('=')?;
+                    Some((key.trim().to_string(), parse_string(value.trim())?))
+                })
+                .collect::<Option<Vec<(String, String)>>>()
+                .map(Setting::Table)
+        }
+        _ => value.replace('_', "").parse::<i64>().ok().map(Setting::Int),
+    }
+}
+
+fn parse_string(value: &str) -> Option<String> {
+    let contents = value.strip_prefix('"')?.strip_suffix('"')?;
+    if contents.contains('"') || contents.contains('\\') {
+        return None;
+    }
+    Some(contents.to_string())
+}
+
+// A '#' starts a comment unless it is inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
@@ -0,0 +1,83 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// most bytes a single tcp-read returns
+const READ_SIZE: usize = 4096;
+
+enum Socket {
+    Listener(TcpListener),
+    Stream(TcpStream),
+}
+
+// Sockets are handed to px2 programs as int handles, an index into this table.
+// Closed handles are left empty rather than reused, so a stale handle is caught.
+pub struct Sockets {
+    handles: Vec<Option<Socket>>,
+}
+
+impl Sockets {
+    pub fn new() -> Self {
+        Sockets {
+            handles: Vec::new(),
+        }
+    }
+
+    pub fn connect(&mut self, address: &str) -> Result<i64, String> {
+        let stream = TcpStream::connect(address).map_err(|error| format!("cannot connect to '{}': {}", address, error))?;
+        Ok(self.add(Socket::Stream(stream)))
+    }
+
+    pub fn listen(&mut self, address: &str) -> Result<i64, String> {
+        let listener = TcpListener::bind(address).map_err(|error| format!("cannot listen on '{}': {}", address, error))?;
+        Ok(self.add(Socket::Listener(listener)))
+    }
+
+    pub fn accept(&mut self, handle: i64) -> Result<i64, String> {
+        let stream = match self.get(handle)? {
+            Socket::Listener(listener) => listener.accept().map_err(|error| format!("cannot accept a connection: {}", error))?.0,
+            Socket::Stream(_) => return Err(format!("handle {} is a connection, not a listener", handle)),
+        };
+        Ok(self.add(Socket::Stream(stream)))
+    }
+
+    // Returns "" once the other side has closed the connection.
+    pub fn read(&mut self, handle: i64) -> Result<String, String> {
+        let stream = self.stream(handle)?;
+        let mut buffer = [0u8; READ_SIZE];
+        let count = stream.read(&mut buffer).map_err(|error| format!("cannot read from handle {}: {}", handle, error))?;
+        Ok(String::from_utf8_lossy(&buffer[..count]).into_owned())
+    }
+
+    pub fn write(&mut self, handle: i64, data: &str) -> Result<(), String> {
+        let stream = self.stream(handle)?;
+        stream.write_all(data.as_bytes()).map_err(|error| format!("cannot write to handle {}: {}", handle, error))
+    }
+
+    pub fn close(&mut self, handle: i64) -> Result<(), String> {
+        self.get(handle)?;
+        // dropping the socket closes it
+        self.handles[handle as usize] = None;
+        Ok(())
+    }
+
+    fn add(&mut self, socket: Socket) -> i64 {
+        self.handles.push(Some(socket));
+        (self.handles.len() - 1) as i64
+    }
+
+    fn get(&mut self, handle: i64) -> Result<&mut Socket, String> {
+        let socket = usize::try_from(handle).ok().and_then(|index| self.handles.get_mut(index));
+        match socket {
+            Some(Some(socket)) => Ok(socket),
+            Some(None) => Err(format!("handle {} has already been closed", handle)),
+            None => Err(format!("{} is not a socket handle", handle)),
+        }
+    }
+
+    fn stream(&mut self, handle: i64) -> Result<&mut TcpStream, String> {
+        match self.get(handle)? {
+            Socket::Stream(stream) => Ok(stream),
+            Socket::Listener(_) => Err(format!("handle {} is a listener, not a connection", handle)),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use crate::span::Span;
+use crate::vm::{DataType, Op, Value};
+
+// Merges pairs of ops that often follow each other into one op that does the work of
+// both, so the interpreter dispatches once instead of twice. The fused op keeps the span
+// of the second op of the pair, the one whose word did the arithmetic or printing. Fused
+// ops carry their constant rather than its index in the constant pool, saving a lookup.
+//
+// Nothing jumps into the middle of a pair since there are no jumps, so any two adjacent
+// ops can be merged.
+pub fn fuse(ops: Vec<Op>, spans: Vec<Option<Span>>, constants: &[Value]) -> (Vec<Op>, Vec<Option<Span>>) {
+    let mut fused_ops = Vec::with_capacity(ops.len());
+    let mut fused_spans = Vec::with_capacity(spans.len());
+    let mut pairs = ops.into_iter().zip(spans).peekable();
+
+    while let Some((op, span)) = pairs.next() {
+        let fused = match (&op, pairs.peek().map(|(next, _)| next)) {
+            (Op::PushConst(index), Some(Op::Add)) => Some(Op::AddConst(constants[*index])),
+            (Op::PushConst(index), Some(Op::Subtract)) => Some(Op::SubtractConst(constants[*index])),
+            (Op::PushConst(index), Some(Op::Multiply)) => Some(Op::MultiplyConst(constants[*index])),
+            // dividing by a constant 0 is left to div, which reports it
+            (Op::PushConst(index), Some(Op::Divide)) if !constants[*index].is_zero() => Some(Op::DivideConst(constants[*index])),
+            // the fused min and max only compare as Int
+            (Op::PushConst(index), Some(Op::Min)) if constants[*index].data_type() == DataType::Int => Some(Op::MinConst(constants[*index].as_int())),
+            (Op::PushConst(index), Some(Op::Max)) if constants[*index].data_type() == DataType::Int => Some(Op::MaxConst(constants[*index].as_int())),
+            (Op::PushConst(index), Some(Op::PrintLn)) => Some(Op::PrintConst(constants[*index])),
+            (Op::Dup, Some(Op::Multiply)) => Some(Op::Square),
+            _ => None,
+        };
+
+        match fused {
+            Some(fused) => {
+                let (_, next_span) = pairs.next().unwrap();
+                fused_ops.push(fused);
+                fused_spans.push(next_span.or(span));
+            }
+            None => {
+                fused_ops.push(op);
+                fused_spans.push(span);
+            }
+        }
+    }
+
+    (fused_ops, fused_spans)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    use crate::bigint::BigInt;
+    use crate::span::{FileId, Span};
+    use crate::vm::{ErrorKind, Op, Value, VM};
+
+    #[derive(Clone, Default)]
+    struct Printed(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Printed {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // What a run printed, and the kind, message and op span start of the error that stopped it.
+    type Outcome = (String, Option<(ErrorKind, String, Option<usize>)>);
+
+    // Runs the ops made by program, fused or not, each op's span starting at its index.
+    fn run(program: &dyn Fn(&mut VM) -> Vec<Op>, fuse: bool, max_ops: Option<usize>) -> (Outcome, usize) {
+        let printed = Printed::default();
+        let mut vm = VM::new().with_output(printed.clone()).with_error_output(io::sink());
+        for (index, op) in program(&mut vm).into_iter().enumerate() {
+            vm.push_op(op, Some(Span { file: FileId(0), start: index, end: index + 1, line: 1, column: index + 1 }));
+        }
+        if fuse {
+            vm.fuse_ops();
+        }
+
+        let result = match max_ops {
+            Some(max_ops) => vm.run_with_limit(max_ops),
+            None => vm.run(),
+        };
+        let error = result.err().map(|error| (error.kind, error.message, error.span.map(|span| span.start)));
+        let printed = String::from_utf8(printed.0.take()).unwrap();
+        ((printed, error), vm.op_count())
+    }
+
+    // Runs the program with and without fusing, checking both end the same way, and
+    // returns how it ended and whether anything was fused.
+    fn same_either_way(program: &dyn Fn(&mut VM) -> Vec<Op>) -> (Outcome, bool) {
+        let (unfused, unfused_count) = run(program, false, None);
+        let (fused, fused_count) = run(program, true, None);
+        assert_eq!(fused, unfused, "fusing changed the program {:?}", program(&mut VM::new()).iter().map(Op::to_string).collect::<Vec<_>>());
+        (fused, fused_count < unfused_count)
+    }
+
+    fn constant(vm: &mut VM, value: &Constant) -> usize {
+        let value = match value {
+            Constant::Int(value) => Value::from_int(*value),
+            Constant::UInt(value) => Value::UInt(*value),
+            Constant::Big(digits) => Value::Big(vm.add_big(BigInt::parse(digits).unwrap())),
+        };
+        vm.add_constant(value)
+    }
+
+    #[derive(Clone, Copy)]
+    enum Constant {
+        Int(i64),
+        UInt(u64),
+        Big(&'static str),
+    }
+
+    const INTS: [i64; 10] = [i64::MIN, i64::MIN + 1, -7, -1, 0, 1, 2, 7, 3037000500, i64::MAX];
+    const UINTS: [u64; 4] = [0, 1, 7, u64::MAX];
+    const BIGS: [&str; 3] = ["0", "-7", "123456789012345678901234567890"];
+
+    // The pairs of operands an arithmetic op accepts: ints, uints, and bigs with ints or bigs.
+    fn operand_pairs() -> Vec<(Constant, Constant)> {
+        let ints = INTS.map(Constant::Int);
+        let uints = UINTS.map(Constant::UInt);
+        let bigs = BIGS.map(Constant::Big);
+        let mut pairs = Vec::new();
+        for group in [&ints[..], &uints[..]] {
+            pairs.extend(group.iter().flat_map(|a| group.iter().map(|b| (*a, *b))));
+        }
+        let with_bigs: Vec<Constant> = ints.iter().chain(&bigs).copied().collect();
+        pairs.extend(bigs.iter().flat_map(|a| with_bigs.iter().map(|b| (*a, *b))));
+        pairs.extend(ints.iter().flat_map(|a| bigs.iter().map(|b| (*a, *b))));
+        pairs
+    }
+
+    #[test]
+    fn fused_arithmetic_matches_unfused() {
+        let mut fused_any = [false; 6];
+        for (a, b) in operand_pairs() {
+            let ops: [fn() -> Op; 6] = [|| Op::Add, || Op::Subtract, || Op::Multiply, || Op::Divide, || Op::Min, || Op::Max];
+            for (index, op) in ops.into_iter().enumerate() {
+                let program = |vm: &mut VM| vec![Op::PushConst(constant(vm, &a)), Op::PushConst(constant(vm, &b)), op(), Op::PrintLn];
+                let (_, fused) = same_either_way(&program);
+                fused_any[index] |= fused;
+            }
+            let square = |vm: &mut VM| vec![Op::PushConst(constant(vm, &a)), Op::Dup, Op::Multiply, Op::PrintLn];
+            assert!(same_either_way(&square).1);
+        }
+        assert_eq!(fused_any, [true; 6]);
+    }
+
+    #[test]
+    fn fused_print_matches_unfused() {
+        let program = |vm: &mut VM| {
+            let string = vm.add_string("héllo\n".to_string());
+            let values = [Value::from_string_index(string), Value::from_int(-3), Value::UInt(u64::MAX), Value::from_bool(true)];
+            let mut ops: Vec<Op> = values.iter().flat_map(|value| [Op::PushConst(vm.add_constant(*value)), Op::PrintLn]).collect();
+            let big = constant(vm, &Constant::Big("-123456789012345678901234567890"));
+            ops.extend([Op::PushConst(big), Op::PrintLn]);
+            ops
+        };
+        let ((printed, error), fused) = same_either_way(&program);
+        assert!(fused);
+        assert!(error.is_none());
+        assert_eq!(printed, "héllo\n\n-3\n18446744073709551615\ntrue\n-123456789012345678901234567890\n");
+    }
+
+    #[test]
+    fn fused_ops_keep_their_error_kinds() {
+        let overflow = |vm: &mut VM| vec![Op::PushConst(constant(vm, &Constant::Int(i64::MAX))), Op::PushConst(constant(vm, &Constant::Int(1))), Op::Add];
+        let ((_, error), fused) = same_either_way(&overflow);
+        assert!(fused);
+        // the span of the add, the word that overflowed
+        assert_eq!(error, Some((ErrorKind::Overflow, "9223372036854775807 + 1 overflows".to_string(), Some(2))));
+
+        // a constant 0 divisor is left to div
+        for zero in [Constant::Int(0), Constant::UInt(0), Constant::Big("0")] {
+            let divide = |vm: &mut VM| {
+                let dividend = if matches!(zero, Constant::UInt(_)) { Constant::UInt(7) } else { Constant::Int(7) };
+                vec![Op::PushConst(constant(vm, &dividend)), Op::PushConst(constant(vm, &zero)), Op::Divide]
+            };
+            let ((_, error), fused) = same_either_way(&divide);
+            assert!(!fused);
+            assert_eq!(error, Some((ErrorKind::DivisionByZero, "division by zero".to_string(), Some(2))));
+        }
+
+        // and a host pushing a div_const of 0 itself gets the same error
+        for zero in [Value::from_int(0), Value::UInt(0), Value::Big(0)] {
+            let dividend = if zero == Value::UInt(0) { Value::UInt(7) } else { Value::from_int(7) };
+            let divide = |vm: &mut VM| vec![Op::PushConst(vm.add_constant(dividend)), Op::DivideConst(zero)];
+            let ((_, error), _) = run(&divide, false, None);
+            assert_eq!(error, Some((ErrorKind::DivisionByZero, "division by zero".to_string(), Some(1))));
+        }
+    }
+
+    #[test]
+    fn fused_programs_stop_at_the_same_step_limit() {
+        let program = |vm: &mut VM| {
+            let one = constant(vm, &Constant::Int(1));
+            let two = constant(vm, &Constant::Int(2));
+            vec![Op::PushConst(one), Op::PushConst(two), Op::Add, Op::Dup, Op::PrintLn, Op::Dup, Op::Multiply, Op::PushConst(two), Op::PrintLn, Op::PrintLn]
+        };
+        for max_ops in 0..=10 {
+            let ((unfused_printed, unfused_error), _) = run(&program, false, Some(max_ops));
+            let ((fused_printed, fused_error), _) = run(&program, true, Some(max_ops));
+            assert_eq!(fused_printed, unfused_printed, "with a limit of {}", max_ops);
+            assert_eq!(fused_error.map(|error| error.0), unfused_error.map(|error| error.0), "with a limit of {}", max_ops);
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+// splitmix64, small and good enough for games and simulations. Not for anything
+// that needs to be unpredictable.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: Option<u64>) -> Self {
+        // RandomState is keyed from the OS, so hashing nothing still gives a fresh seed each run
+        let seed = seed.unwrap_or_else(|| RandomState::new().build_hasher().finish());
+        Rng { state: seed }
+    }
+
+    // Carries on the sequence of the generator state was taken from.
+    pub fn from_state(state: u64) -> Self {
+        Rng { state }
+    }
+
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [low, high), high must be greater than low.
+    pub fn range(&mut self, low: i64, high: i64) -> i64 {
+        let span = high.wrapping_sub(low) as u64;
+        // reject the top partial span so every result is equally likely
+        let limit = u64::MAX - u64::MAX % span;
+        loop {
+            let n = self.next();
+            if n < limit {
+                return low.wrapping_add((n % span) as i64);
+            }
+        }
+    }
+}
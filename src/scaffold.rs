@@ -0,0 +1,38 @@
+use std::fs;
+use std::path::Path;
+
+// The files of a new project, relative to its directory. '{name}' is replaced with the
+// project's name.
+const TEMPLATE: [(&str, &str); 6] = [
+    ("px2.toml", include_str!("../templates/new/px2.toml")),
+    ("README.md", include_str!("../templates/new/README.md")),
+    (".gitignore", include_str!("../templates/new/gitignore")),
+    ("src/main.px2", include_str!("../templates/new/main.px2")),
+    ("src/greeting.px2", include_str!("../templates/new/greeting.px2")),
+    ("tests/greeting.px2", include_str!("../templates/new/test.px2")),
+];
+
+// Creates a starter project in a new directory called name.
+pub fn new_project(name: &str) -> Result<(), String> {
+    // the name is used in strings in the manifest and the code, so it is kept simple
+    let valid = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(format!("'{}' is not a valid project name, use letters, digits, '-' and '_', starting with a letter", name));
+    }
+
+    let root = Path::new(name);
+    if root.exists() {
+        return Err(format!("'{}' already exists", name));
+    }
+
+    for (path, contents) in TEMPLATE {
+        let path = root.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|error| format!("cannot create '{}': {}", parent.display(), error))?;
+        }
+        fs::write(&path, contents.replace("{name}", name)).map_err(|error| format!("cannot write '{}': {}", path.display(), error))?;
+    }
+
+    Ok(())
+}
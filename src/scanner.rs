@@ -1,77 +1,377 @@
 use std::fmt;
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::vec;
 
 use phf::phf_map;
 
+use crate::span::{FileId, Span};
+
 static KEYWORDS: phf::Map<&'static str, TokenType> = phf_map! {
+    "abs" => TokenType::Abs,
+    "alloc" => TokenType::Alloc,
+    "array" => TokenType::Array,
+    "as" => TokenType::As,
+    "big->int" => TokenType::BigToInt,
+    "bool->int" => TokenType::BoolToInt,
+    "char-at" => TokenType::CharAt,
+    "clock-ns" => TokenType::ClockNs,
+    "compare" => TokenType::Compare,
+    "comptime" => TokenType::Comptime,
+    "concat" => TokenType::Concat,
+    "data" => TokenType::Data,
+    "divmod" => TokenType::DivMod,
     "dup" => TokenType::Dup,
     "drop" => TokenType::Drop,
+    "else" => TokenType::Else,
+    "end" => TokenType::End,
+    "eq" => TokenType::Equal,
+    "eprintln" => TokenType::EPrintLn,
+    "enum" => TokenType::Enum,
+    "exec" => TokenType::Exec,
+    "extern" => TokenType::Extern,
     "false" => TokenType::False,
+    "format" => TokenType::Format,
+    "flush" => TokenType::Flush,
+    "free" => TokenType::Free,
+    "getenv" => TokenType::GetEnv,
+    "include" => TokenType::Include,
+    "int->big" => TokenType::IntToBig,
+    "int->ptr" => TokenType::IntToPtr,
+    "int->str" => TokenType::IntToStr,
+    "int->uint" => TokenType::IntToUInt,
+    "length" => TokenType::Length,
+    "max" => TokenType::Max,
+    "min" => TokenType::Min,
+    "module" => TokenType::Module,
+    "now-ms" => TokenType::NowMs,
     "over" => TokenType::Over,
+    "pow" => TokenType::Pow,
     "println" => TokenType::PrintLn,
+    "ptr->int" => TokenType::PtrToInt,
+    "pub" => TokenType::Pub,
+    "random" => TokenType::Random,
+    "random-range" => TokenType::RandomRange,
+    "realloc" => TokenType::Realloc,
     "rot" => TokenType::Rot,
+    "shl" => TokenType::ShiftLeft,
+    "shr" => TokenType::ShiftRight,
+    "sizeof" => TokenType::SizeOf,
+    "sleep" => TokenType::Sleep,
+    "str->int" => TokenType::StrToInt,
+    "struct" => TokenType::Struct,
+    "substring" => TokenType::Substring,
     "swap" => TokenType::Swap,
+    "syscall0" => TokenType::Syscall,
+    "syscall1" => TokenType::Syscall,
+    "syscall2" => TokenType::Syscall,
+    "syscall3" => TokenType::Syscall,
+    "syscall4" => TokenType::Syscall,
+    "syscall5" => TokenType::Syscall,
+    "syscall6" => TokenType::Syscall,
+    "tcp-accept" => TokenType::TcpAccept,
+    "tcp-close" => TokenType::TcpClose,
+    "tcp-connect" => TokenType::TcpConnect,
+    "tcp-listen" => TokenType::TcpListen,
+    "tcp-read" => TokenType::TcpRead,
+    "tcp-write" => TokenType::TcpWrite,
     "true" => TokenType::True,
+    "uint->int" => TokenType::UIntToInt,
+    "var" => TokenType::Var,
+    "when" => TokenType::When,
 };
 
 pub struct Scanner<'a> {
+    file: FileId,
     code_string: &'a String,
     code_bytes: &'a [u8],
     start: usize,
     current: usize,
     line: usize,
+    // counted in characters rather than bytes, as editors count them
     column: usize,
+    // where the token being scanned starts, a string can run onto later lines
+    start_line: usize,
+    start_column: usize,
+    // a token scanned ahead by peek, handed out by the next scan_token
+    peeked: Option<Token<'a>>,
+    // set once the iterator has yielded EndOfFile
+    finished: bool,
+    // set when the tokens are lexed on another thread, see Scanner::streamed
+    stream: Option<TokenStream<'a>>,
+    // how much of a 'data name' header has been scanned, its body is scanned next
+    data_header: DataHeader,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DataHeader {
+    None,
+    Keyword,
+    Name,
 }
 
+// Tokens are sent between threads in chunks, since sending each one costs more than lexing it.
+const TOKEN_CHUNK_SIZE: usize = 512;
+
+struct TokenStream<'a> {
+    chunks: Receiver<Vec<Token<'a>>>,
+    chunk: vec::IntoIter<Token<'a>>,
+}
+
+#[derive(Clone, Copy)]
 pub struct Token<'a> {
     pub token_type: TokenType,
-    start: usize,
-    pub length: usize,
-    pub line: usize,
-    pub column: usize,
+    pub span: Span,
     pub text: &'a str,
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum TokenType {
+    Abs,
+    AddressOf,
+    Alloc,
+    Array,
+    As,
+    Attribute,
+    Big,
+    BigToInt,
+    BoolToInt,
+    CharAt,
+    CheckedMinus,
+    CheckedPlus,
+    CheckedStar,
+    ClockNs,
+    Compare,
+    Comptime,
+    Concat,
+    Data,
+    DataBody,
+    DivMod,
+    DoubleDash,
     Dup,
     Drop,
+    End,
     EndOfFile,
+    Else,
+    Enum,
+    Equal,
+    EPrintLn,
     Error,
+    Exec,
+    Extern,
     False,
+    Flush,
+    Format,
+    Free,
+    GetEnv,
     Identifier,
+    Include,
     Int,
+    InvalidNumber,
+    IntToBig,
+    IntToPtr,
+    IntToStr,
+    IntToUInt,
+    Length,
+    Load,
+    Max,
+    Min,
     Minus,
+    Module,
+    NowMs,
     Over,
     Plus,
+    Pow,
     PrintLn,
+    PtrToInt,
+    Pub,
+    QueryStack,
+    Random,
+    RandomRange,
+    Realloc,
     Rot,
+    SaturatingMinus,
+    SaturatingPlus,
+    SaturatingStar,
+    ShiftLeft,
+    ShiftRight,
+    SizeOf,
     Slash,
+    Sleep,
     Star,
+    Store,
+    StrToInt,
+    String,
+    Struct,
+    Substring,
     Swap,
+    Syscall,
+    TcpAccept,
+    TcpClose,
+    TcpConnect,
+    TcpListen,
+    TcpRead,
+    TcpWrite,
     True,
+    UInt,
+    UIntToInt,
+    UnterminatedData,
+    UnterminatedString,
+    Var,
+    When,
+}
+
+fn is_identifier_char(c: char) -> bool {
+    // '.' is allowed so that struct fields can be referred to as 'Struct.field',
+    // ':' for names qualified by their module like 'math::counter'
+    // and '-' and '>' so that conversion words can be written as 'int->str'
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == ':' || c == '-' || c == '>'
+}
+
+// Splits a string literal's token text into how many bytes come before its contents, the
+// contents and whether it is a raw string, whose escapes are left as they are written.
+pub fn string_contents(text: &str) -> (usize, &str, bool) {
+    let raw = text.starts_with('r');
+    let quotes = if text[raw as usize..].starts_with("\"\"\"") { 3 } else { 1 };
+    let start = raw as usize + quotes;
+    (start, &text[start..text.len() - quotes], raw)
 }
 
 impl<'a> fmt::Display for Token<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Token [ type: {:?}, start: {}, length: {}, line: {}, column: {}, text: '{}' ]", self.token_type, self.start, self.length, self.line, self.column, self.text)
+        write!(f, "Token [ type: {:?}, span: {}..{}, line: {}, column: {}, text: '{}' ]", self.token_type, self.span.start, self.span.end, self.span.line, self.span.column, self.text)
+    }
+}
+
+// Yields every token up to and including EndOfFile.
+impl<'a> Iterator for Scanner<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        if self.finished {
+            return None;
+        }
+
+        let token = self.scan_token();
+        self.finished = token.token_type == TokenType::EndOfFile;
+        Some(token)
     }
 }
 
 impl<'a> Scanner<'a> {
-    pub fn new(code_string: &'a String) -> Self {
+    pub fn new(code_string: &'a String, file: FileId) -> Self {
+        // a '#!/usr/bin/env px2' first line lets a file run as a script on Unix, and is
+        // skipped up to its newline so the lines after it keep their numbers
+        let current = if code_string.starts_with("#!") {
+            code_string.find('\n').unwrap_or(code_string.len())
+        } else {
+            0
+        };
+
         Scanner {
+            file,
             code_string,
             code_bytes: code_string.as_bytes(),
-            start: 0,
-            current: 0,
+            start: current,
+            current,
             line: 1,
             column: 1,
+            start_line: 1,
+            start_column: 1,
+            peeked: None,
+            finished: false,
+            stream: None,
+            data_header: DataHeader::None,
+        }
+    }
+
+    // A scanner handing out the tokens another thread lexes from the same code with
+    // send_tokens.
+    pub fn streamed(code_string: &'a String, file: FileId, chunks: Receiver<Vec<Token<'a>>>) -> Self {
+        Scanner {
+            stream: Some(TokenStream { chunks, chunk: Vec::new().into_iter() }),
+            ..Scanner::new(code_string, file)
+        }
+    }
+
+    // Lexes every token into chunks for a streamed scanner, stopping early if the
+    // streamed scanner is dropped.
+    pub fn send_tokens(self, chunks: &SyncSender<Vec<Token<'a>>>) {
+        let mut chunk = Vec::with_capacity(TOKEN_CHUNK_SIZE);
+        for token in self {
+            chunk.push(token);
+            if chunk.len() == TOKEN_CHUNK_SIZE || token.token_type == TokenType::EndOfFile {
+                if chunks.send(chunk).is_err() {
+                    return;
+                }
+                chunk = Vec::with_capacity(TOKEN_CHUNK_SIZE);
+            }
+        }
+    }
+
+    pub fn scan_token(&mut self) -> Token<'a> {
+        match self.peeked.take() {
+            Some(token) => token,
+            None => self.next_token(),
+        }
+    }
+
+    // Returns the next token without consuming it.
+    pub fn peek(&mut self) -> Token<'a> {
+        match self.peeked {
+            Some(token) => token,
+            None => {
+                let token = self.next_token();
+                self.peeked = Some(token);
+                token
+            }
+        }
+    }
+
+    // Consumes the next token, returning it as an error if it is not of the given type.
+    pub fn expect(&mut self, token_type: TokenType) -> Result<Token<'a>, Token<'a>> {
+        let token = self.scan_token();
+        if token.token_type == token_type {
+            Ok(token)
+        } else {
+            Err(token)
         }
     }
 
-    pub fn scan_token(&mut self) -> Token {
+    fn next_token(&mut self) -> Token<'a> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return self.lex_token(),
+        };
+
+        loop {
+            if let Some(token) = stream.chunk.next() {
+                return token;
+            }
+            // the sending thread only stops early if this scanner is dropped
+            stream.chunk = stream.chunks.recv().expect("token stream ended before the end of the file").into_iter();
+        }
+    }
+
+    fn lex_token(&mut self) -> Token<'a> {
+        let body = match self.data_header {
+            DataHeader::Name => self.make_data_body(),
+            _ => None,
+        };
+        let token = body.unwrap_or_else(|| self.lex_word());
+
+        self.data_header = match (self.data_header, token.token_type) {
+            (_, TokenType::Data) => DataHeader::Keyword,
+            (DataHeader::Keyword, TokenType::Identifier) => DataHeader::Name,
+            _ => DataHeader::None,
+        };
+        token
+    }
+
+    fn lex_word(&mut self) -> Token<'a> {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
 
         if self.is_at_end() {
             return self.make_token(TokenType::EndOfFile);
@@ -83,27 +383,68 @@ impl<'a> Scanner<'a> {
             return self.make_number();
         }
 
-        if current_char.is_alphabetic() {
+        if current_char == 'r' && self.peek_char() == Some('"') {
+            self.advance();
+            return self.make_string(true);
+        }
+
+        if current_char.is_ascii_alphabetic() {
             return self.make_identifier();
         }
 
         match current_char {
+            // a sign written against the digits is part of the number, '5 -3 +' is 2
+            '+'|'-' if self.peek_char().is_some_and(|c| c.is_ascii_digit()) => self.make_number(),
+            // '+?' also pushes whether the addition overflowed and '+|' clamps it instead,
+            // and likewise for '-' and '*'
+            '+'|'-'|'*' if matches!(self.peek_char(), Some('?'|'|')) => {
+                let checked = self.advance() == Some('?');
+                self.make_token(match (current_char, checked) {
+                    ('+', true) => TokenType::CheckedPlus,
+                    ('-', true) => TokenType::CheckedMinus,
+                    ('*', true) => TokenType::CheckedStar,
+                    ('+', false) => TokenType::SaturatingPlus,
+                    ('-', false) => TokenType::SaturatingMinus,
+                    _ => TokenType::SaturatingStar,
+                })
+            }
             '+' => self.make_token(TokenType::Plus),
+            // '--' separates arguments from results in a stack effect
+            '-' if self.peek_char() == Some('-') => {
+                self.advance();
+                self.make_token(TokenType::DoubleDash)
+            }
             '-' => self.make_token(TokenType::Minus),
             '*' => self.make_token(TokenType::Star),
             '/' => self.make_token(TokenType::Slash),
+            '@' => self.make_access(TokenType::Load),
+            '!' => self.make_access(TokenType::Store),
+            '&' => self.make_access(TokenType::AddressOf),
+            '"' => self.make_string(false),
+            '?' => self.make_directive(),
+            '#' => self.make_attribute(),
             _ => self.error_token(),
         }
     }
 
     fn advance(&mut self) -> Option<char> {
-        if self.is_at_end() {
-            return None;
-        }
-
-        self.current += 1;
+        let c = self.peek_char()?;
+        self.current += c.len_utf8();
         self.column += 1;
-        Some(self.code_bytes[self.current - 1] as char)
+        Some(c)
+    }
+
+    fn peek_char(&self) -> Option<char> {
+        self.code_string[self.current..].chars().next()
+    }
+
+    // At a '\n' or a Windows '\r\n' line ending.
+    fn at_line_end(&self) -> bool {
+        match self.code_bytes.get(self.current) {
+            Some(b'\n') => true,
+            Some(b'\r') => self.code_bytes.get(self.current + 1) == Some(&b'\n'),
+            _ => false,
+        }
     }
 
     fn is_at_end(&self) -> bool {
@@ -122,7 +463,7 @@ impl<'a> Scanner<'a> {
                     self.line += 1;
                     self.column = 1;
                 },
-                ' '|'\r' => {
+                ' '|'\t'|'\r' => {
                     self.advance();
                 },
                 _ => { 
@@ -132,16 +473,38 @@ impl<'a> Scanner<'a> {
         }
     }
 
-    fn make_number(&mut self) -> Token {
+    // Any sign has been consumed along with the first digit.
+    fn make_number(&mut self) -> Token<'a> {
         while !self.is_at_end() && self.code_bytes[self.current].is_ascii_digit() {
             self.advance();
         }
 
-        self.make_token(TokenType::Int) 
+        // a 'u' suffix makes a uint, which cannot be negative, and an 'n' suffix a big
+        let token_type = match self.code_bytes.get(self.current) {
+            Some(b'u') => TokenType::UInt,
+            Some(b'n') => TokenType::Big,
+            _ => TokenType::Int,
+        };
+        if token_type != TokenType::Int {
+            self.advance();
+        }
+
+        // '3abc' or '1.5' is one bad literal rather than a number followed by a name
+        let mut malformed = false;
+        while !self.is_at_end() && matches!(self.code_bytes[self.current], b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'.') {
+            self.advance();
+            malformed = true;
+        }
+
+        if malformed || (token_type == TokenType::UInt && self.code_bytes[self.start] == b'-') {
+            self.make_token(TokenType::InvalidNumber)
+        } else {
+            self.make_token(token_type)
+        }
     }
 
-    fn make_identifier(&mut self) -> Token {
-        while !self.is_at_end() && (self.code_bytes[self.current].is_ascii_alphanumeric() || self.code_bytes[self.current] as char == '_') {
+    fn make_identifier(&mut self) -> Token<'a> {
+        while !self.is_at_end() && is_identifier_char(self.code_bytes[self.current] as char) {
             self.advance();
         }
 
@@ -154,26 +517,234 @@ impl<'a> Scanner<'a> {
         }        
     }
 
-    fn make_token(&self, token_type: TokenType) -> Token {
-        let length = self.current - self.start;
+    // The opening quote and any 'r' have been consumed.
+    fn make_string(&mut self, raw: bool) -> Token<'a> {
+        if self.code_string[self.current..].starts_with("\"\"") {
+            self.advance();
+            self.advance();
+            return self.make_long_string(raw);
+        }
+
+        // escapes are processed by the compiler, the scanner only needs to not stop at an escaped quote
+        while !self.is_at_end() {
+            match self.code_bytes[self.current] as char {
+                '"' => {
+                    self.advance();
+                    return self.make_token(TokenType::String);
+                }
+                '\\' if !raw => {
+                    self.advance();
+                    if !self.is_at_end() && !self.at_line_end() {
+                        self.advance();
+                    }
+                }
+                _ if self.at_line_end() => break,
+                _ => {
+                    self.advance();
+                }
+            }
+        }
+
+        self.make_token(TokenType::UnterminatedString)
+    }
+
+    // A string between three quotes, which runs onto as many lines as it needs.
+    fn make_long_string(&mut self, raw: bool) -> Token<'a> {
+        while !self.is_at_end() {
+            if self.code_string[self.current..].starts_with("\"\"\"") {
+                for _ in 0..3 {
+                    self.advance();
+                }
+                return self.make_token(TokenType::String);
+            }
+
+            let c = self.advance().unwrap();
+            if c == '\\' && !raw && !self.is_at_end() && !self.at_line_end() {
+                self.advance();
+            } else if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            }
+        }
+
+        self.make_token(TokenType::UnterminatedString)
+    }
+
+    // The lines after a 'data name' header up to one that is only 'end', kept as they are
+    // written. None if the header line goes on past the name, which is then scanned as usual.
+    fn make_data_body(&mut self) -> Option<Token<'a>> {
+        while matches!(self.code_bytes.get(self.current), Some(b' ' | b'\t')) {
+            self.advance();
+        }
+        if !self.is_at_end() && !self.at_line_end() {
+            return None;
+        }
+
+        self.skip_line();
+        self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
+
+        while !self.is_at_end() {
+            let line = self.code_string[self.current..].split('\n').next().unwrap();
+            if line.trim() == "end" {
+                return Some(self.make_token(TokenType::DataBody));
+            }
+            self.skip_line();
+        }
+
+        Some(self.make_token(TokenType::UnterminatedData))
+    }
+
+    // Moves to the start of the next line.
+    fn skip_line(&mut self) {
+        while !self.is_at_end() && self.code_bytes[self.current] != b'\n' {
+            self.advance();
+        }
+        if !self.is_at_end() {
+            self.advance();
+            self.line += 1;
+            self.column = 1;
+        }
+    }
+
+    fn make_access(&mut self, token_type: TokenType) -> Token<'a> {
+        // '@name', '!name' and '&name' are scanned as a single token, the name must follow immediately
+        if !self.peek_char().is_some_and(|c| c.is_ascii_alphabetic()) {
+            return self.error_token();
+        }
+
+        while !self.is_at_end() && is_identifier_char(self.code_bytes[self.current] as char) {
+            self.advance();
+        }
+
+        self.make_token(token_type)
+    }
+
+    fn make_directive(&mut self) -> Token<'a> {
+        // '?stack' asks the compiler about the program rather than being compiled into it
+        while !self.is_at_end() && is_identifier_char(self.code_bytes[self.current] as char) {
+            self.advance();
+        }
+
+        match &self.code_string[self.start..self.current] {
+            "?stack" => self.make_token(TokenType::QueryStack),
+            _ => self.make_token(TokenType::Error),
+        }
+    }
+
+    fn make_attribute(&mut self) -> Token<'a> {
+        // '#deprecated' and the like describe the declaration that follows, the compiler
+        // checks the name so the scanner need not know every attribute
+        if !self.peek_char().is_some_and(|c| c.is_ascii_alphabetic()) {
+            return self.error_token();
+        }
+
+        while !self.is_at_end() && is_identifier_char(self.code_bytes[self.current] as char) {
+            self.advance();
+        }
+
+        self.make_token(TokenType::Attribute)
+    }
+
+    fn make_token(&self, token_type: TokenType) -> Token<'a> {
         Token {
             token_type,
-            start: self.start,
-            length,
-            line: self.line,
-            column: self.column - length,
+            span: Span {
+                file: self.file,
+                start: self.start,
+                end: self.current,
+                line: self.start_line,
+                column: self.start_column,
+            },
             text: &self.code_string.as_str()[self.start..self.current],
         }
     }
 
-    fn error_token(&self) -> Token {
+    fn error_token(&self) -> Token<'a> {
         Token {
             token_type: TokenType::Error,
-            start: self.start,
-            length: 1,
-            line: self.line,
-            column: self.column,
+            span: Span {
+                file: self.file,
+                start: self.start,
+                // the whole character, which may take up more than one byte
+                end: self.start + self.code_string[self.start..].chars().next().map_or(1, char::len_utf8),
+                line: self.start_line,
+                column: self.start_column,
+            },
             text: "Error",
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{string_contents, Scanner, TokenType};
+    use crate::span::FileId;
+
+    // Every token before EndOfFile, with its text, line and column.
+    fn tokens(code: &str) -> Vec<(TokenType, String, usize, usize)> {
+        let code = code.to_string();
+        Scanner::new(&code, FileId(0))
+            .take_while(|token| token.token_type != TokenType::EndOfFile)
+            .map(|token| (token.token_type, token.text.to_string(), token.span.line, token.span.column))
+            .collect()
+    }
+
+    #[test]
+    fn raw_strings_keep_backslashes() {
+        // a backslash does not escape the quote, so the string ends at the first one
+        let scanned = tokens(r#"r"C:\dir\" r"\n\u{" println"#);
+        assert_eq!(scanned, [
+            (TokenType::String, r#"r"C:\dir\""#.to_string(), 1, 1),
+            (TokenType::String, r#"r"\n\u{""#.to_string(), 1, 12),
+            (TokenType::PrintLn, "println".to_string(), 1, 21),
+        ]);
+        assert_eq!(string_contents(&scanned[0].1), (2, r"C:\dir\", true));
+        assert_eq!(string_contents(&scanned[1].1), (2, r"\n\u{", true));
+
+        // 'r' only starts a raw string directly before a quote
+        assert_eq!(tokens(r#"r "a""#)[0].0, TokenType::Identifier);
+    }
+
+    #[test]
+    fn long_strings_run_over_lines() {
+        let code = "1 \"\"\"first \"quoted\"\n  second \\\"\"\"\nthird\"\"\" println\nr\"\"\"raw \\\nlines\"\"\" 2";
+        let scanned = tokens(code);
+        assert_eq!(scanned.iter().map(|(token_type, _, line, column)| (*token_type, *line, *column)).collect::<Vec<_>>(), [
+            (TokenType::Int, 1, 1),
+            (TokenType::String, 1, 3),
+            (TokenType::PrintLn, 3, 10),
+            (TokenType::String, 4, 1),
+            (TokenType::Int, 5, 10),
+        ]);
+        // an escaped quote does not end the string, and the quotes inside are kept
+        assert_eq!(string_contents(&scanned[1].1), (3, "first \"quoted\"\n  second \\\"\"\"\nthird", false));
+        assert_eq!(string_contents(&scanned[3].1), (4, "raw \\\nlines", true));
+
+        // empty strings, short and long
+        assert_eq!(tokens("\"\" \"\"\"\"\"\""), [
+            (TokenType::String, "\"\"".to_string(), 1, 1),
+            (TokenType::String, "\"\"\"\"\"\"".to_string(), 1, 4),
+        ]);
+        assert_eq!(string_contents("\"\"\"\"\"\"").1, "");
+    }
+
+    #[test]
+    fn unterminated_strings() {
+        // a short string stops at the end of its line, and scanning carries on on the next
+        assert_eq!(tokens("\"open\n1"), [
+            (TokenType::UnterminatedString, "\"open".to_string(), 1, 1),
+            (TokenType::Int, "1".to_string(), 2, 1),
+        ]);
+        assert_eq!(tokens("r\"C:\\\n1")[0], (TokenType::UnterminatedString, "r\"C:\\".to_string(), 1, 1));
+        // an escaped line break does not continue it
+        assert_eq!(tokens("\"a\\\n1")[0], (TokenType::UnterminatedString, "\"a\\".to_string(), 1, 1));
+        assert_eq!(tokens("\"a\\")[0], (TokenType::UnterminatedString, "\"a\\".to_string(), 1, 1));
+
+        // a long string runs to the end of the file
+        assert_eq!(tokens("\"\"\"open\n1 \"\" 2"), [(TokenType::UnterminatedString, "\"\"\"open\n1 \"\" 2".to_string(), 1, 1)]);
+        assert_eq!(tokens("r\"\"\"open\\\"\"\"")[0].0, TokenType::String);
+        assert_eq!(tokens("\"\"\"open\\\"\"\"")[0].0, TokenType::UnterminatedString);
+    }
+}
@@ -0,0 +1,251 @@
+use std::fs;
+
+use crate::vm::Value;
+
+// identifies a snapshot file, followed by a format version
+const MAGIC: &[u8] = b"PX2S";
+const VERSION: u8 = 2;
+
+const TAG_INT: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_PTR: u8 = 2;
+const TAG_STR: u8 = 3;
+const TAG_UINT: u8 = 4;
+const TAG_BIG: u8 = 5;
+
+// The state of a run that stopped at --max-ops, --timeout or Ctrl-C, saved so it can
+// carry on from the same op later. Sockets are not saved, a resumed program finds them
+// closed, and clock_ns counts from the resume.
+pub struct Snapshot {
+    // of the ops and constants, so a snapshot is only resumed by the program that made it
+    pub program: u64,
+    pub op_index: usize,
+    pub stack: Vec<Value>,
+    pub memory: Vec<Value>,
+    // address and length of every live allocation
+    pub heap_blocks: Vec<(usize, usize)>,
+    pub heap_freed: Vec<usize>,
+    pub rng_state: u64,
+    // the whole table, strings made at runtime included
+    pub strings: Vec<String>,
+    // the digits of every big in the VM's table, freed slots included so indices stay the same
+    pub bigs: Vec<String>,
+}
+
+impl Snapshot {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|error| format!("cannot read snapshot '{}': {}", path, error))?;
+        Self::decode(&bytes, path)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        fs::write(path, self.encode()).map_err(|error| format!("cannot write snapshot '{}': {}", path, error))
+    }
+
+    // Every field is little-endian with a fixed width, and addresses, lengths and counts
+    // are written as u64 however wide usize is, so a snapshot made on one machine loads
+    // on another. One that does not fit in this machine's usize is rejected. path is only
+    // for errors.
+    fn decode(bytes: &[u8], path: &str) -> Result<Self, String> {
+        let invalid = || format!("'{}' is not a valid px2 snapshot", path);
+
+        if bytes.len() < MAGIC.len() + 1 || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(invalid());
+        }
+        if bytes[MAGIC.len()] != VERSION {
+            return Err(format!("snapshot '{}' has version {}, expected {}", path, bytes[MAGIC.len()], VERSION));
+        }
+
+        let mut reader = Reader { rest: &bytes[MAGIC.len() + 1..] };
+        let snapshot = (|| {
+            let program = reader.u64()?;
+            let op_index = reader.usize()?;
+            let stack = reader.values()?;
+            let memory = reader.values()?;
+            let heap_blocks = (0..reader.u64()?).map(|_| Some((reader.usize()?, reader.usize()?))).collect::<Option<_>>()?;
+            let heap_freed = (0..reader.u64()?).map(|_| reader.usize()).collect::<Option<_>>()?;
+            let rng_state = reader.u64()?;
+            let strings = (0..reader.u64()?).map(|_| reader.string()).collect::<Option<_>>()?;
+            let bigs = (0..reader.u64()?).map(|_| reader.string()).collect::<Option<_>>()?;
+            Some(Snapshot { program, op_index, stack, memory, heap_blocks, heap_freed, rng_state, strings, bigs })
+        })();
+
+        match snapshot {
+            Some(snapshot) if reader.rest.is_empty() => Ok(snapshot),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.extend(self.program.to_le_bytes());
+        bytes.extend((self.op_index as u64).to_le_bytes());
+        for values in [&self.stack, &self.memory] {
+            bytes.extend((values.len() as u64).to_le_bytes());
+            for value in values {
+                let (tag, bits) = match *value {
+                    Value::Int(value) => (TAG_INT, value),
+                    Value::Bool(value) => (TAG_BOOL, value as i64),
+                    Value::Ptr(value) => (TAG_PTR, value),
+                    Value::Str(index) => (TAG_STR, index as i64),
+                    Value::UInt(value) => (TAG_UINT, value as i64),
+                    Value::Big(index) => (TAG_BIG, index as i64),
+                };
+                bytes.push(tag);
+                bytes.extend(bits.to_le_bytes());
+            }
+        }
+        bytes.extend((self.heap_blocks.len() as u64).to_le_bytes());
+        for (address, length) in &self.heap_blocks {
+            bytes.extend((*address as u64).to_le_bytes());
+            bytes.extend((*length as u64).to_le_bytes());
+        }
+        bytes.extend((self.heap_freed.len() as u64).to_le_bytes());
+        for address in &self.heap_freed {
+            bytes.extend((*address as u64).to_le_bytes());
+        }
+        bytes.extend(self.rng_state.to_le_bytes());
+        for strings in [&self.strings, &self.bigs] {
+            bytes.extend((strings.len() as u64).to_le_bytes());
+            for string in strings {
+                bytes.extend((string.len() as u64).to_le_bytes());
+                bytes.extend(string.as_bytes());
+            }
+        }
+        bytes
+    }
+}
+
+// FNV-1a, which unlike the standard library's hasher gives the same hash in every build.
+pub fn fingerprint<'a>(parts: impl IntoIterator<Item = &'a [u8]>) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        // a separator after each part, so moving bytes between parts changes the hash
+        for byte in part.iter().chain([&0xff]) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+struct Reader<'a> {
+    rest: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn u64(&mut self) -> Option<u64> {
+        let (value, rest) = self.rest.split_first_chunk::<8>()?;
+        self.rest = rest;
+        Some(u64::from_le_bytes(*value))
+    }
+
+    // A u64 that has to fit in a usize, which is narrower on 32-bit machines.
+    fn usize(&mut self) -> Option<usize> {
+        usize::try_from(self.u64()?).ok()
+    }
+
+    fn values(&mut self) -> Option<Vec<Value>> {
+        (0..self.u64()?).map(|_| {
+            let (&tag, rest) = self.rest.split_first()?;
+            self.rest = rest;
+            let bits = self.u64()? as i64;
+            match tag {
+                TAG_INT => Some(Value::Int(bits)),
+                TAG_BOOL => Some(Value::Bool(bits != 0)),
+                TAG_PTR => Some(Value::Ptr(bits)),
+                TAG_STR => Some(Value::Str(usize::try_from(bits).ok()?)),
+                TAG_UINT => Some(Value::UInt(bits as u64)),
+                TAG_BIG => Some(Value::Big(usize::try_from(bits).ok()?)),
+                _ => None,
+            }
+        }).collect()
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let length = self.usize()?;
+        if self.rest.len() < length {
+            return None;
+        }
+        let (string, rest) = self.rest.split_at(length);
+        self.rest = rest;
+        String::from_utf8(string.to_vec()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Snapshot, MAGIC, VERSION};
+    use crate::vm::Value;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            program: 0x0102030405060708,
+            op_index: 42,
+            stack: vec![Value::Int(-1), Value::Bool(true), Value::Ptr(7), Value::Str(1), Value::UInt(u64::MAX), Value::Big(0)],
+            memory: vec![Value::Int(i64::MIN), Value::Int(i64::MAX)],
+            heap_blocks: vec![(100, 3), (110, 1)],
+            heap_freed: vec![103],
+            rng_state: 99,
+            strings: vec![String::new(), "héllo\n".to_string()],
+            bigs: vec!["0".to_string(), "-123456789012345678901234567890".to_string()],
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let original = snapshot();
+        let loaded = Snapshot::decode(&original.encode(), "test").unwrap();
+        assert_eq!(loaded.program, original.program);
+        assert_eq!(loaded.op_index, original.op_index);
+        assert_eq!(loaded.stack, original.stack);
+        assert_eq!(loaded.memory, original.memory);
+        assert_eq!(loaded.heap_blocks, original.heap_blocks);
+        assert_eq!(loaded.heap_freed, original.heap_freed);
+        assert_eq!(loaded.rng_state, original.rng_state);
+        assert_eq!(loaded.strings, original.strings);
+        assert_eq!(loaded.bigs, original.bigs);
+    }
+
+    #[test]
+    fn writes_fixed_width_little_endian() {
+        let bytes = snapshot().encode();
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(bytes[4], VERSION);
+        assert_eq!(bytes[5..13], [8, 7, 6, 5, 4, 3, 2, 1]);
+        // op_index is a usize, written as 8 bytes on every machine
+        assert_eq!(bytes[13..21], [42, 0, 0, 0, 0, 0, 0, 0]);
+        // the stack's length, then the first value's tag and bits
+        assert_eq!(bytes[21..29], [6, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(bytes[29..38], [0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let bytes = snapshot().encode();
+        for length in 0..bytes.len() {
+            assert!(Snapshot::decode(&bytes[..length], "test").is_err(), "accepted {} of {} bytes", length, bytes.len());
+        }
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(Snapshot::decode(&longer, "test").is_err());
+    }
+
+    #[test]
+    fn rejects_usize_fields_too_wide_for_this_machine() {
+        let mut bytes = snapshot().encode();
+        bytes[13..21].copy_from_slice(&u64::MAX.to_le_bytes());
+        let result = Snapshot::decode(&bytes, "test");
+        if usize::BITS < 64 {
+            assert!(result.is_err());
+        } else {
+            assert_eq!(result.unwrap().op_index, usize::MAX);
+        }
+
+        // a 32-bit machine reads every op index it could have written
+        let mut original = snapshot();
+        original.op_index = u32::MAX as usize;
+        assert_eq!(Snapshot::decode(&original.encode(), "test").unwrap().op_index, u32::MAX as usize);
+    }
+}
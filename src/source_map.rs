@@ -0,0 +1,47 @@
+use crate::span::{FileId, Span};
+
+struct SourceFile {
+    path: String,
+    code: String,
+    // byte offset of the start of each line
+    line_starts: Vec<usize>,
+}
+
+// Owns the code of every loaded file, so a span from any of them can be shown.
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap {
+            files: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, path: String, code: String) -> FileId {
+        let line_starts = std::iter::once(0).chain(code.match_indices('\n').map(|(index, _)| index + 1)).collect();
+        self.files.push(SourceFile { path, code, line_starts });
+        FileId(self.files.len() - 1)
+    }
+
+    pub fn path(&self, file: FileId) -> &str {
+        &self.files[file.0].path
+    }
+
+    pub fn code(&self, file: FileId) -> &String {
+        &self.files[file.0].code
+    }
+
+    pub fn text(&self, span: &Span) -> &str {
+        &self.code(span.file)[span.start..span.end]
+    }
+
+    // The text of a line, without its line ending. Lines are counted from 1.
+    pub fn line(&self, file: FileId, line: usize) -> &str {
+        let source = &self.files[file.0];
+        let start = source.line_starts[line - 1];
+        let end = source.line_starts.get(line).map_or(source.code.len(), |next| next - 1);
+        source.code[start..end].trim_end_matches('\r')
+    }
+}
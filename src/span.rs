@@ -0,0 +1,20 @@
+// Identifies a source file loaded into the SourceMap.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct FileId(pub usize);
+
+// A range of bytes in a source file. The line and column of the start are kept
+// alongside so diagnostics do not have to count them again.
+#[derive(Clone, Copy, Debug)]
+pub struct Span {
+    pub file: FileId,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn length(&self) -> usize {
+        self.end - self.start
+    }
+}
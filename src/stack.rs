@@ -0,0 +1,171 @@
+use crate::vm::Value;
+
+// The VM's value stack with the top value cached outside the Vec, so that an op that
+// replaces the top, like add or dup followed by mul, works on a local instead of going
+// through memory.
+//
+// The Vec starts out holding nothing, and the cached top starts out as a placeholder.
+// The first push moves the placeholder to the bottom of the Vec where it stays, so push
+// and pop never need to check whether the stack is empty: the values on the stack are
+// below[1..] followed by top, and the length is below.len(). The compiler checks that
+// nothing is popped from an empty stack.
+pub struct Stack {
+    top: Value,
+    below: Vec<Value>,
+}
+
+impl Stack {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Stack {
+            top: Value::from_int(0),
+            // one extra for the placeholder
+            below: Vec::with_capacity(capacity + 1),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.below.len()
+    }
+
+    pub fn push(&mut self, value: Value) {
+        self.below.push(self.top);
+        self.top = value;
+    }
+
+    pub fn pop(&mut self) -> Value {
+        let value = self.top;
+        self.top = self.below.pop().unwrap();
+        value
+    }
+
+    pub fn top(&self) -> Value {
+        self.top
+    }
+
+    pub fn top_mut(&mut self) -> &mut Value {
+        &mut self.top
+    }
+
+    // The value depth places below the top, which has to be on the stack.
+    pub fn peek(&self, depth: usize) -> Value {
+        if depth == 0 {
+            self.top
+        } else {
+            self.below[self.below.len() - depth]
+        }
+    }
+
+    // Removes the top count values, returned bottom to top.
+    pub fn pop_n(&mut self, count: usize) -> Vec<Value> {
+        self.below.push(self.top);
+        let values = self.below.split_off(self.below.len() - count);
+        self.top = self.below.pop().unwrap();
+        values
+    }
+
+    pub fn extend(&mut self, values: impl IntoIterator<Item = Value>) {
+        for value in values {
+            self.push(value);
+        }
+    }
+
+    // a b => a b a
+    pub fn over(&mut self) {
+        let value = *self.below.last().unwrap();
+        self.push(value);
+    }
+
+    // a b => b a
+    pub fn swap(&mut self) {
+        std::mem::swap(&mut self.top, self.below.last_mut().unwrap());
+    }
+
+    // a b c => b c a
+    pub fn rot(&mut self) {
+        let value = self.below.remove(self.below.len() - 2);
+        self.push(value);
+    }
+
+    // The values bottom to top.
+    pub fn iter(&self) -> impl Iterator<Item = Value> + '_ {
+        let top = (!self.below.is_empty()).then_some(self.top);
+        self.below.iter().skip(1).copied().chain(top)
+    }
+
+    // The values bottom to top.
+    pub fn into_vec(mut self) -> Vec<Value> {
+        if self.below.is_empty() {
+            return self.below;
+        }
+        self.below.push(self.top);
+        self.below.remove(0);
+        self.below
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stack;
+    use crate::random::Rng;
+    use crate::vm::Value;
+
+    // Runs the same ops on a Stack and on a plain Vec, the stack without a cached top,
+    // and checks they hold the same values after every op.
+    #[test]
+    fn matches_uncached_stack() {
+        for seed in 0..64 {
+            let mut rng = Rng::new(Some(seed));
+            let mut stack = Stack::with_capacity(0);
+            let mut naive = Vec::<Value>::new();
+
+            for step in 0..2000 {
+                let len = naive.len();
+                let value = Value::from_int(step);
+                match rng.range(0, 10) {
+                    0 if len >= 1 => assert_eq!(stack.pop(), naive.pop().unwrap()),
+                    1 if len >= 1 => {
+                        stack.push(stack.top());
+                        naive.push(naive[len - 1]);
+                    }
+                    2 if len >= 2 => {
+                        stack.over();
+                        naive.push(naive[len - 2]);
+                    }
+                    3 if len >= 2 => {
+                        stack.swap();
+                        naive.swap(len - 1, len - 2);
+                    }
+                    4 if len >= 3 => {
+                        stack.rot();
+                        let value = naive.remove(len - 3);
+                        naive.push(value);
+                    }
+                    5 if len >= 1 => {
+                        *stack.top_mut() = value;
+                        naive[len - 1] = value;
+                    }
+                    6 => {
+                        let count = rng.range(0, len as i64 + 1) as usize;
+                        assert_eq!(stack.pop_n(count), naive.split_off(len - count));
+                    }
+                    7 => {
+                        stack.extend([value, Value::from_bool(true)]);
+                        naive.extend([value, Value::from_bool(true)]);
+                    }
+                    _ => {
+                        stack.push(value);
+                        naive.push(value);
+                    }
+                }
+
+                assert_eq!(stack.len(), naive.len());
+                assert!(stack.iter().eq(naive.iter().copied()));
+                for depth in 0..naive.len() {
+                    assert_eq!(stack.peek(depth), naive[naive.len() - 1 - depth]);
+                }
+            }
+
+            assert_eq!(stack.into_vec(), naive);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::VecDeque;
+use std::fs;
+
+// identifies a trace file, followed by a format version
+const MAGIC: &[u8] = b"PX2T";
+const VERSION: u8 = 1;
+
+const TAG_INT: u8 = 0;
+const TAG_MISSING: u8 = 1;
+const TAG_STRING: u8 = 2;
+
+enum Entry {
+    Int(i64),
+    // an environment variable, None when it was not set, or what a command printed or a
+    // socket read
+    Str(Option<String>),
+}
+
+// Records the result of every op whose result comes from outside the program
+// (the clock, the random number generator, the environment, commands and sockets) so
+// a run can be replayed exactly, e.g. from a bug report. A replay runs no commands and
+// uses no network, it gets what they gave from the trace.
+pub struct Trace {
+    mode: Mode,
+}
+
+enum Mode {
+    Off,
+    Record(Vec<Entry>),
+    Replay(VecDeque<Entry>),
+}
+
+impl Trace {
+    pub fn off() -> Self {
+        Trace { mode: Mode::Off }
+    }
+
+    pub fn record() -> Self {
+        Trace { mode: Mode::Record(Vec::new()) }
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|error| format!("cannot read trace '{}': {}", path, error))?;
+        Self::decode(&bytes, path)
+    }
+
+    // The format is the same on every machine: integers are little-endian with fixed
+    // widths, and string lengths are u32 however wide usize is. path is only for errors.
+    fn decode(bytes: &[u8], path: &str) -> Result<Self, String> {
+        let invalid = || format!("'{}' is not a valid px2 trace", path);
+
+        if bytes.len() < MAGIC.len() + 1 || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(invalid());
+        }
+        if bytes[MAGIC.len()] != VERSION {
+            return Err(format!("trace '{}' has version {}, expected {}", path, bytes[MAGIC.len()], VERSION));
+        }
+
+        let mut entries = VecDeque::new();
+        let mut rest = &bytes[MAGIC.len() + 1..];
+        while let Some((&tag, tail)) = rest.split_first() {
+            let entry = match tag {
+                TAG_INT => {
+                    let (value, tail) = tail.split_first_chunk::<8>().ok_or_else(invalid)?;
+                    rest = tail;
+                    Entry::Int(i64::from_le_bytes(*value))
+                }
+                TAG_MISSING => {
+                    rest = tail;
+                    Entry::Str(None)
+                }
+                TAG_STRING => {
+                    let (length, tail) = tail.split_first_chunk::<4>().ok_or_else(invalid)?;
+                    let length = u32::from_le_bytes(*length) as usize;
+                    if tail.len() < length {
+                        return Err(invalid());
+                    }
+                    let string = String::from_utf8(tail[..length].to_vec()).map_err(|_| invalid())?;
+                    rest = &tail[length..];
+                    Entry::Str(Some(string))
+                }
+                _ => return Err(invalid()),
+            };
+            entries.push_back(entry);
+        }
+
+        Ok(Trace { mode: Mode::Replay(entries) })
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let bytes = match &self.mode {
+            Mode::Record(entries) => Self::encode(entries),
+            _ => return Ok(()),
+        };
+        fs::write(path, bytes).map_err(|error| format!("cannot write trace '{}': {}", path, error))
+    }
+
+    fn encode(entries: &[Entry]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        for entry in entries {
+            match entry {
+                Entry::Int(value) => {
+                    bytes.push(TAG_INT);
+                    bytes.extend(value.to_le_bytes());
+                }
+                Entry::Str(None) => bytes.push(TAG_MISSING),
+                Entry::Str(Some(string)) => {
+                    bytes.push(TAG_STRING);
+                    bytes.extend((string.len() as u32).to_le_bytes());
+                    bytes.extend(string.as_bytes());
+                }
+            }
+        }
+        bytes
+    }
+
+    pub fn replaying(&self) -> bool {
+        matches!(self.mode, Mode::Replay(_))
+    }
+
+    // live produces the value when the program is not being replayed
+    pub fn int(&mut self, live: impl FnOnce() -> i64) -> Result<i64, String> {
+        self.try_int(|| Ok(live()))
+    }
+
+    pub fn str(&mut self, live: impl FnOnce() -> Option<String>) -> Result<Option<String>, String> {
+        self.try_str(|| Ok(live()))
+    }
+
+    // For ops that can fail. A failure is not recorded, the run stops there, so a replay
+    // stops at the same op when it runs out of recorded values.
+    pub fn try_int(&mut self, live: impl FnOnce() -> Result<i64, String>) -> Result<i64, String> {
+        match &mut self.mode {
+            Mode::Off => live(),
+            Mode::Record(entries) => {
+                let value = live()?;
+                entries.push(Entry::Int(value));
+                Ok(value)
+            }
+            Mode::Replay(entries) => match entries.pop_front() {
+                Some(Entry::Int(value)) => Ok(value),
+                Some(Entry::Str(_)) => Err("replay trace recorded a string here, not an int".to_string()),
+                None => Err("replay trace has no more recorded values".to_string()),
+            },
+        }
+    }
+
+    pub fn try_str(&mut self, live: impl FnOnce() -> Result<Option<String>, String>) -> Result<Option<String>, String> {
+        match &mut self.mode {
+            Mode::Off => live(),
+            Mode::Record(entries) => {
+                let value = live()?;
+                entries.push(Entry::Str(value.clone()));
+                Ok(value)
+            }
+            Mode::Replay(entries) => match entries.pop_front() {
+                Some(Entry::Str(value)) => Ok(value),
+                Some(Entry::Int(_)) => Err("replay trace recorded an int here, not a string".to_string()),
+                None => Err("replay trace has no more recorded values".to_string()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mode, Trace, MAGIC, VERSION};
+
+    fn recorded() -> Vec<u8> {
+        let mut trace = Trace::record();
+        trace.int(|| -2).unwrap();
+        trace.str(|| Some("PATH=/bin".to_string())).unwrap();
+        trace.str(|| None).unwrap();
+        trace.int(|| i64::MAX).unwrap();
+        match &trace.mode {
+            Mode::Record(entries) => Trace::encode(entries),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut trace = Trace::decode(&recorded(), "test").unwrap();
+        assert_eq!(trace.int(|| unreachable!()), Ok(-2));
+        assert_eq!(trace.str(|| unreachable!()), Ok(Some("PATH=/bin".to_string())));
+        assert_eq!(trace.str(|| unreachable!()), Ok(None));
+        assert_eq!(trace.int(|| unreachable!()), Ok(i64::MAX));
+        assert!(trace.int(|| unreachable!()).is_err());
+    }
+
+    #[test]
+    fn writes_fixed_width_little_endian() {
+        let bytes = recorded();
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(bytes[4], VERSION);
+        assert_eq!(bytes[5..14], [0, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        // a string's length is a u32 however wide usize is
+        assert_eq!(bytes[14..19], [2, 9, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let bytes = recorded();
+        // cutting between entries leaves a valid trace of fewer entries
+        let boundaries = [5, 14, 28, 29, 38];
+        for length in 0..bytes.len() {
+            let result = Trace::decode(&bytes[..length], "test");
+            assert_eq!(result.is_ok(), boundaries.contains(&length), "{} of {} bytes", length, bytes.len());
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::manifest::{Manifest, Source, MANIFEST_NAME};
+
+pub const VENDOR_DIRECTORY: &str = "vendor";
+
+// Fetches the project's dependencies, and theirs, into <root>/vendor/<name> and returns
+// the include paths to compile the project with: the vendor directory itself, so files
+// are included as "<name>/<file>", followed by the include paths of the dependencies.
+//
+// Path dependencies are copied again on every build so edits to them are picked up. Git
+// dependencies are cloned once and checked out at their rev on every build, delete the
+// dependency's vendor directory to fetch it again.
+pub fn fetch(manifest: &Manifest) -> Result<Vec<String>, String> {
+    let vendor = manifest.root.join(VENDOR_DIRECTORY);
+    let mut include_paths = vec![vendor.to_string_lossy().into_owned()];
+    let mut fetched = HashSet::new();
+    fetch_dependencies(manifest, &vendor, &mut fetched, &mut include_paths)?;
+    Ok(include_paths)
+}
+
+fn fetch_dependencies(manifest: &Manifest, vendor: &Path, fetched: &mut HashSet<String>, include_paths: &mut Vec<String>) -> Result<(), String> {
+    for dependency in &manifest.dependencies {
+        // the first project to ask for a name decides where it comes from
+        if !fetched.insert(dependency.name.clone()) {
+            continue;
+        }
+
+        let destination = vendor.join(&dependency.name);
+        match &dependency.source {
+            Source::Path(path) => copy_path(path, &destination),
+            Source::Git { url, rev } => fetch_git(url, rev.as_deref(), &destination),
+        }
+        .map_err(|message| format!("cannot fetch dependency '{}': {}", dependency.name, message))?;
+
+        // a dependency that is a project itself brings its own includes and dependencies
+        if destination.join(MANIFEST_NAME).is_file() {
+            let nested = Manifest::load(&destination)?;
+            include_paths.extend(nested.include.iter().cloned());
+            fetch_dependencies(&nested, vendor, fetched, include_paths)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn copy_path(source: &Path, destination: &Path) -> Result<(), String> {
+    if !source.is_dir() {
+        return Err(format!("'{}' is not a directory", source.display()));
+    }
+    if destination.exists() {
+        fs::remove_dir_all(destination).map_err(|error| format!("cannot remove '{}': {}", destination.display(), error))?;
+    }
+    copy_directory(source, destination).map_err(|error| format!("cannot copy '{}': {}", source.display(), error))
+}
+
+fn copy_directory(source: &Path, destination: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(destination)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        // a dependency's own vendored code is fetched into the top level vendor directory instead
+        if name == VENDOR_DIRECTORY || name == ".git" {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            copy_directory(&entry.path(), &destination.join(&name))?;
+        } else {
+            fs::copy(entry.path(), destination.join(&name))?;
+        }
+    }
+    Ok(())
+}
+
+fn fetch_git(url: &str, rev: Option<&str>, destination: &Path) -> Result<(), String> {
+    let destination_arg = destination.to_string_lossy();
+    if !destination.exists() {
+        git(&["clone", "--quiet", "--", url, &destination_arg])?;
+    }
+
+    if let Some(rev) = rev {
+        // the rev may be newer than the clone, so fetch once before giving up on it. The
+        // '--' after it keeps git from taking it as an option or a file to check out
+        if git(&["-C", &destination_arg, "checkout", "--quiet", rev, "--"]).is_err() {
+            git(&["-C", &destination_arg, "fetch", "--quiet", "origin"])?;
+            git(&["-C", &destination_arg, "checkout", "--quiet", rev, "--"])?;
+        }
+    }
+    Ok(())
+}
+
+fn git(args: &[&str]) -> Result<(), String> {
+    let output = Command::new("git").args(args).output().map_err(|error| format!("cannot run git: {}", error))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!("'git {}' failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
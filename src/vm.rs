@@ -1,135 +1,577 @@
+use std::cell::{RefCell, RefMut};
+use std::collections::HashMap;
+use std::env;
+use std::ffi::{CStr, CString};
 use std::fmt;
-use std::ops::{Add, Sub, Mul, Div};
+use std::io::{self, BufWriter, Write};
+use std::os::raw::c_char;
+use std::rc::Rc;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::bigint::{BigInt, BigTable};
+use crate::ffi::{self, ExternFunction};
+use crate::heap::{Heap, DEFAULT_HEAP_SIZE};
+use crate::interrupt;
+use crate::net::Sockets;
+use crate::optimizer;
+use crate::random::Rng;
+use crate::snapshot::{self, Snapshot};
+use crate::span::Span;
+use crate::stack::Stack;
+use crate::trace::Trace;
 
 pub struct VM {
     op_list: Vec<Op>,
+    // the word each op was compiled from, for runtime errors and coverage
+    op_spans: Vec<Option<Span>>,
+    // Op::PushConst indexes into this, each distinct value is stored once
+    constants: Vec<Value>,
+    constant_indices: HashMap<Value, usize>,
+    data_segment: Vec<Value>,
+    // Str values are indices into this table, index 0 is always the empty string
+    strings: Vec<String>,
+    // string literals, so a literal used many times is stored once
+    string_indices: HashMap<String, usize>,
+    // the big constants, which Big values index like Str values index strings, index 0
+    // is always 0
+    bigs: Vec<BigInt>,
+    big_indices: HashMap<BigInt, usize>,
+    heap_size: usize,
+    // fixed seed for reproducible runs, seeded from the OS when None
+    seed: Option<u64>,
+    // Op::CallExtern indexes into this
+    externs: Vec<ExternFunction>,
+    // native functions registered by the host, Op::CallBuiltin indexes into this
+    builtins: Vec<Builtin>,
+    timeout: Option<Duration>,
+    permissions: Permissions,
+    // where println writes, and where eprintln and diagnostics for the program write
+    output: RefCell<Box<dyn Write>>,
+    error_output: RefCell<Box<dyn Write>>,
+    buffered: bool,
+    // computed by the compiler so the stack is allocated once, None if the ops were not compiled
+    max_stack_depth: Option<usize>,
+    // how many times each op has run, counted across runs while coverage is on
+    coverage: Option<RefCell<Vec<u64>>>,
+    // what the last run used, kept when it failed too
+    stats: RefCell<Stats>,
+    // where runs start from instead of the first op, see resume_from
+    resume: Option<Snapshot>,
+    // whether a run stopped by a limit keeps its state, and the state it kept
+    snapshots: bool,
+    paused: RefCell<Option<Snapshot>>,
+    // whether each op checks the values it takes before it runs, see set_checked
+    checked: bool,
+}
+
+// What a run used, reported by --stats.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    // counted in unfused ops, like the step limit
+    pub ops_executed: usize,
+    pub peak_stack_depth: usize,
+    // alloc and realloc calls
+    pub allocations: usize,
+    pub peak_heap_cells: usize,
+    pub bytes_printed: usize,
+}
+
+type BuiltinFn = dyn Fn(&mut BuiltinCall) -> Result<(), String>;
+
+struct Builtin {
+    name: String,
+    params: Vec<DataType>,
+    returns: Vec<DataType>,
+    function: Box<BuiltinFn>,
+}
+
+// Passed to a builtin when it is called. Arguments are indexed bottom to top, in the
+// order they were pushed, and results are pushed in the order they should end up in.
+pub struct BuiltinCall<'a> {
+    args: Vec<Value>,
+    results: Vec<Value>,
+    strings: &'a mut Vec<String>,
 }
 
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub enum DataType {
+    Big,
     Bool,
     Int,
+    Ptr,
+    Str,
+    UInt,
 }
 
 impl fmt::Display for DataType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", match self {
+            DataType::Big => "Big",
             DataType::Bool => "Bool",
             DataType::Int => "Int",
+            DataType::Ptr => "Ptr",
+            DataType::Str => "Str",
+            DataType::UInt => "UInt",
         })
     } 
 }
 
+impl DataType {
+    pub fn from_name(name: &str) -> Option<DataType> {
+        match name {
+            "big" => Some(DataType::Big),
+            "bool" => Some(DataType::Bool),
+            "int" => Some(DataType::Int),
+            "ptr" => Some(DataType::Ptr),
+            "str" => Some(DataType::Str),
+            "uint" => Some(DataType::UInt),
+            _ => None,
+        }
+    }
+
+    // The type on its own, as the set of types a checked op accepts.
+    fn alone(self) -> &'static [DataType] {
+        match self {
+            DataType::Int => &[DataType::Int],
+            DataType::Bool => &[DataType::Bool],
+            DataType::Ptr => &[DataType::Ptr],
+            DataType::Str => &[DataType::Str],
+            DataType::UInt => &[DataType::UInt],
+            DataType::Big => &[DataType::Big],
+        }
+    }
+}
+
+const ANY: &[DataType] = &[DataType::Int, DataType::Bool, DataType::Ptr, DataType::Str, DataType::UInt, DataType::Big];
+const INTEGERS: &[DataType] = &[DataType::Int, DataType::UInt, DataType::Big, DataType::Ptr];
+
+#[derive(Clone, Copy)]
+pub struct ArrayRef {
+    pub address: usize,
+    pub length: usize,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+    // an op failed for a reason without a kind of its own, e.g. a builtin returned an error
+    Failed,
+    DivisionByZero,
+    // int arithmetic, abs or pow gave a result too large for an int
+    Overflow,
+    // an op was given a value it cannot work with, e.g. a negative time to sleep for
+    InvalidArgument,
+    // an index past the end of an array or string, or an address outside memory
+    OutOfBounds,
+    // heap memory used after it was freed, or a memory cell read as the wrong type
+    InvalidAccess,
+    // alloc, realloc or free failed, e.g. the heap is full or the pointer was not allocated
+    Allocation,
+    // printing, running a command or using a socket failed
+    Io,
+    // in checked mode, an op did not find the values it takes on the stack
+    InvalidOperand,
+    // the program ran more ops than it was allowed to
+    StepLimit,
+    // the program ran for longer than it was allowed to
+    Timeout,
+    // the program used an op it does not have permission for
+    Denied,
+    // whatever was reading the program's output stopped, e.g. 'px2 main.px2 | head'
+    BrokenPipe,
+    // Ctrl-C was pressed, see interrupt::install
+    Interrupted,
+}
+
+// What a program may do beyond printing to stdout. Everything is denied by default.
+#[derive(Clone, Copy, Default)]
+pub struct Permissions {
+    // reading environment variables with getenv
+    pub env: bool,
+    pub net: bool,
+    pub run: bool,
+}
+
+// Why a run stopped, returned by run and the other ways of running a program. Hosts can
+// match on the kind to tell failures apart without reading the message.
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub op_index: usize,
+    // the span of the op the program stopped at, if it has one
+    pub span: Option<Span>,
+    pub message: String,
+}
+
+impl RuntimeError {
+    pub fn new(op_index: usize, message: String) -> Self {
+        RuntimeError {
+            kind: ErrorKind::Failed,
+            op_index,
+            span: None,
+            message,
+        }
+    }
+
+    pub fn with_kind(mut self, kind: ErrorKind) -> Self {
+        self.kind = kind;
+        self
+    }
+}
+
 pub enum Op {
+    Abs,
     Add,
+    // push followed by add, and likewise for sub, mul, div, min and max, made by the optimizer
+    AddConst(Value),
+    Alloc,
+    // big => int bool, false if it does not fit
+    BigToInt,
+    CallBuiltin(usize),
+    CallExtern(usize),
+    Cast(DataType),
+    CharAt,
+    // a b => result overflowed, the result wrapping around when it overflows
+    CheckedAdd,
+    CheckedMultiply,
+    CheckedSubtract,
+    ClockNs,
+    Compare,
+    Concat,
     Divide,
+    // never 0, that is left unfused so div reports it
+    DivideConst(Value),
+    // a b => a/b a%b, the remainder on top
+    DivMod,
     Drop,
     Dup,
+    // println to the error output, for messages that should not mix with the program's output
+    EPrintLn,
+    // a b => bool, of two values of the same type
+    Equal,
+    Exec,
+    Flush,
+    // number of values taken by the format string on top of the stack
+    Format(usize),
+    Free,
+    GetEnv,
+    IntToStr,
+    Length,
+    Load(usize),
+    LoadIndexed(ArrayRef),
+    LoadPtr(DataType),
+    Max,
+    MaxConst(i64),
+    Min,
+    MinConst(i64),
     Multiply,
+    MultiplyConst(Value),
+    NowMs,
     Over,
-    Push(Value),
+    Pow,
+    // index into the constant pool
+    PushConst(usize),
     PrintLn,
+    // push followed by println, made by the optimizer
+    PrintConst(Value),
+    Random,
+    RandomRange,
+    Realloc,
     Rot,
+    // a b => result, clamped to the smallest or largest value of the type
+    SaturatingAdd,
+    SaturatingMultiply,
+    SaturatingSubtract,
+    // value amount, the amount is from 0 to 63
+    ShiftLeft,
+    // arithmetic for Int, logical for UInt
+    ShiftRight,
+    Sleep,
+    // dup followed by mul, made by the optimizer
+    Square,
+    Store(usize),
+    StoreIndexed(ArrayRef),
+    StorePtr(DataType),
+    StrToInt,
+    Substring,
     Subtract,
+    SubtractConst(Value),
     Swap,
+    // argument count, the syscall number is on top of the arguments
+    Syscall(usize),
+    TcpAccept,
+    TcpClose,
+    TcpConnect,
+    TcpListen,
+    TcpRead,
+    TcpWrite,
 }
 
-#[derive(Clone, Copy)]
-union Data {
-    int_value: i64,
-    bool_value: bool,
+// Strings are indices into the VM's string table, so every value is Copy and no bigger
+// than an i64 and its tag. The compiler checks types, so reading a value as the wrong
+// type is a bug in px2 and panics instead of reinterpreting the bits.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Bool(bool),
+    // an address, kept as an i64 so that pointer arithmetic is integer arithmetic
+    Ptr(i64),
+    Str(usize),
+    // wraps around on overflow, where Int arithmetic overflowing is a bug in the program
+    UInt(u64),
+    // an index into the VM's table of bigs, see BigTable
+    Big(usize),
 }
 
-#[derive(Clone, Copy)]
-pub struct Value {
-    pub data_type: DataType,
-    data: Data,
-}
+// The index of a big 0, the value of big cells until they are stored to.
+const ZERO_BIG: usize = 0;
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        unsafe {
-            match self.data_type {
-                DataType::Int => write!(f, "{}", self.data.int_value),
-                DataType::Bool => write!(f, "{}", self.data.bool_value),
-            }
+        match self {
+            Value::Int(value)|Value::Ptr(value) => write!(f, "{}", value),
+            Value::Bool(value) => write!(f, "{}", value),
+            // the contents live in the VM's string table
+            Value::Str(index) => write!(f, "str#{}", index),
+            Value::UInt(value) => write!(f, "{}", value),
+            Value::Big(index) => write!(f, "big#{}", index),
         }
     }
 }
 
-impl Add for Value {
-    type Output = Value;
+// A value the way println and format show it, which for strs and bigs needs the tables
+// they index. Every type is shown here so the ops that print agree with each other.
+pub struct Shown<'a> {
+    value: Value,
+    strings: &'a [String],
+    bigs: &'a [BigInt],
+}
 
-    fn add(self, other: Self) -> Self {
-        // type checked in compiler
-        unsafe {
-            Value {
-                data_type: DataType::Int,
-                data: Data {
-                    int_value: self.data.int_value + other.data.int_value,
-                }
-            }
+impl fmt::Display for Shown<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value {
+            Value::Str(index) => write!(f, "{}", self.strings[index]),
+            Value::Big(index) => write!(f, "{}", self.bigs[index]),
+            // in hex, so an address is not mistaken for an int
+            Value::Ptr(address) => write!(f, "{:#x}", address),
+            value => write!(f, "{}", value),
         }
     }
 }
 
-impl Sub for Value {
-    type Output = Value;
+impl Value {
+    pub fn shown<'a>(self, strings: &'a [String], bigs: &'a [BigInt]) -> Shown<'a> {
+        Shown { value: self, strings, bigs }
+    }
 
-    fn sub(self, other: Self) -> Self {
-        unsafe {
-            Value {
-                data_type: DataType::Int,
-                data: Data {
-                    int_value: self.data.int_value - other.data.int_value,
-                }
-            }
+    pub fn from_int(value: i64) -> Self {
+        Value::Int(value)
+    }
+
+    pub fn from_bool(value: bool) -> Self {
+        Value::Bool(value)
+    }
+
+    pub fn from_ptr(address: usize) -> Self {
+        Value::Ptr(address as i64)
+    }
+
+    pub fn from_string_index(index: usize) -> Self {
+        Value::Str(index)
+    }
+
+    pub fn default_of(data_type: DataType) -> Self {
+        match data_type {
+            DataType::Bool => Value::from_bool(false),
+            DataType::Int => Value::from_int(0),
+            DataType::Ptr => Value::from_ptr(0),
+            DataType::Str => Value::from_string_index(0),
+            DataType::UInt => Value::UInt(0),
+            DataType::Big => Value::Big(ZERO_BIG),
         }
     }
-}
 
-impl Mul for Value {
-    type Output = Value;
+    pub fn data_type(self) -> DataType {
+        match self {
+            Value::Int(_) => DataType::Int,
+            Value::Bool(_) => DataType::Bool,
+            Value::Ptr(_) => DataType::Ptr,
+            Value::Str(_) => DataType::Str,
+            Value::UInt(_) => DataType::UInt,
+            Value::Big(_) => DataType::Big,
+        }
+    }
 
-    fn mul(self, other: Self) -> Self {
-        unsafe {
-            Value {
-                data_type: DataType::Int,
-                data: Data {
-                    int_value: self.data.int_value * other.data.int_value,
-                }
-            }
+    // Ptr values read as their address.
+    pub fn as_int(self) -> i64 {
+        match self {
+            Value::Int(value)|Value::Ptr(value) => value,
+            _ => panic!("expected an int, found {}", self.data_type()),
+        }
+    }
+
+    pub fn as_uint(self) -> u64 {
+        match self {
+            Value::UInt(value) => value,
+            _ => panic!("expected a uint, found {}", self.data_type()),
         }
     }
-}
 
-impl Div for Value {
-    type Output = Value;
+    pub fn as_bool(self) -> bool {
+        match self {
+            Value::Bool(value) => value,
+            _ => panic!("expected a bool, found {}", self.data_type()),
+        }
+    }
 
-    fn div(self, other: Self) -> Self {
-        unsafe {
-            Value {
-                data_type: DataType::Int,
-                data: Data {
-                    int_value: self.data.int_value / other.data.int_value,
-                }
+    pub fn string_index(self) -> usize {
+        match self {
+            Value::Str(index) => index,
+            _ => panic!("expected a str, found {}", self.data_type()),
+        }
+    }
+
+    pub fn is_zero(self) -> bool {
+        matches!(self, Value::Int(0)|Value::UInt(0)|Value::Big(ZERO_BIG))
+    }
+
+    // Min and max compare UInt values as unsigned.
+    fn min(self, other: Self) -> Self {
+        match (self, other) {
+            (Value::UInt(a), Value::UInt(b)) => Value::UInt(a.min(b)),
+            _ => Value::Int(self.as_int().min(other.as_int())),
+        }
+    }
+
+    fn max(self, other: Self) -> Self {
+        match (self, other) {
+            (Value::UInt(a), Value::UInt(b)) => Value::UInt(a.max(b)),
+            _ => Value::Int(self.as_int().max(other.as_int())),
+        }
+    }
+
+    // Int arithmetic that overflows is an error, None here, while uint wraps around.
+    fn checked_add(self, other: Self) -> Option<Self> {
+        if let (Value::UInt(a), Value::UInt(b)) = (self, other) {
+            return Some(Value::UInt(a.wrapping_add(b)));
+        }
+        // type checked in compiler, ptr + int and int + ptr give a ptr
+        let value = self.as_int().checked_add(other.as_int())?;
+        match (self, other) {
+            (Value::Ptr(_), _)|(_, Value::Ptr(_)) => Some(Value::Ptr(value)),
+            _ => Some(Value::Int(value)),
+        }
+    }
+
+    fn checked_sub(self, other: Self) -> Option<Self> {
+        if let (Value::UInt(a), Value::UInt(b)) = (self, other) {
+            return Some(Value::UInt(a.wrapping_sub(b)));
+        }
+        // ptr - int gives a ptr, ptr - ptr gives the distance between them
+        let value = self.as_int().checked_sub(other.as_int())?;
+        match (self, other) {
+            (Value::Ptr(_), Value::Ptr(_)) => Some(Value::Int(value)),
+            (Value::Ptr(_), _) => Some(Value::Ptr(value)),
+            _ => Some(Value::Int(value)),
+        }
+    }
+
+    fn checked_mul(self, other: Self) -> Option<Self> {
+        if let (Value::UInt(a), Value::UInt(b)) = (self, other) {
+            return Some(Value::UInt(a.wrapping_mul(b)));
+        }
+        self.as_int().checked_mul(other.as_int()).map(Value::Int)
+    }
+
+    // Division by zero is checked before dividing, so only the minimum int divided by -1
+    // gives None.
+    fn checked_div(self, other: Self) -> Option<Self> {
+        if let (Value::UInt(a), Value::UInt(b)) = (self, other) {
+            return Some(Value::UInt(a / b));
+        }
+        self.as_int().checked_div(other.as_int()).map(Value::Int)
+    }
+
+    // For the checked ops, the result wrapped around and whether it did.
+    fn overflowing(self, other: Self, op: &Op) -> (Self, bool) {
+        match (self, other) {
+            (Value::UInt(a), Value::UInt(b)) => {
+                let (value, overflowed) = match op {
+                    Op::CheckedAdd => a.overflowing_add(b),
+                    Op::CheckedSubtract => a.overflowing_sub(b),
+                    _ => a.overflowing_mul(b),
+                };
+                (Value::UInt(value), overflowed)
+            }
+            _ => {
+                let (a, b) = (self.as_int(), other.as_int());
+                let (value, overflowed) = match op {
+                    Op::CheckedAdd => a.overflowing_add(b),
+                    Op::CheckedSubtract => a.overflowing_sub(b),
+                    _ => a.overflowing_mul(b),
+                };
+                (Value::Int(value), overflowed)
             }
         }
     }
-}
 
-impl Value {
-    pub fn from_int(value: i64) -> Self {
-        Value {
-            data_type: DataType::Int,
-            data: Data { int_value: value },
+    fn saturating(self, other: Self, op: &Op) -> Self {
+        match (self, other) {
+            (Value::UInt(a), Value::UInt(b)) => Value::UInt(match op {
+                Op::SaturatingAdd => a.saturating_add(b),
+                Op::SaturatingSubtract => a.saturating_sub(b),
+                _ => a.saturating_mul(b),
+            }),
+            _ => {
+                let (a, b) = (self.as_int(), other.as_int());
+                Value::Int(match op {
+                    Op::SaturatingAdd => a.saturating_add(b),
+                    Op::SaturatingSubtract => a.saturating_sub(b),
+                    _ => a.saturating_mul(b),
+                })
+            }
         }
     }
 
-    pub fn from_bool(value: bool) -> Self {
-        Value {
-            data_type: DataType::Bool,
-            data: Data { bool_value: value },
+    fn shift(self, amount: u32, left: bool) -> Self {
+        match (self, left) {
+            (Value::UInt(value), true) => Value::UInt(value << amount),
+            (Value::UInt(value), false) => Value::UInt(value >> amount),
+            (_, true) => Value::Int(self.as_int() << amount),
+            (_, false) => Value::Int(self.as_int() >> amount),
+        }
+    }
+
+    fn cast(self, data_type: DataType) -> Self {
+        // only int <-> ptr, int <-> uint and bool -> int casts are allowed by the compiler,
+        // int <-> uint keeps the bits
+        match (self, data_type) {
+            (Value::Bool(value), _) => Value::Int(value as i64),
+            (Value::UInt(value), _) => Value::Int(value as i64),
+            (_, DataType::UInt) => Value::UInt(self.as_int() as u64),
+            (_, DataType::Ptr) => Value::Ptr(self.as_int()),
+            _ => Value::Int(self.as_int()),
+        }
+    }
+}
+
+impl Op {
+    // Whether the op does the same thing every run, touching nothing outside the VM other
+    // than the program's output.
+    fn is_pure(&self) -> bool {
+        !matches!(self,
+            Op::CallBuiltin(_)|Op::CallExtern(_)|Op::ClockNs|Op::EPrintLn|Op::Exec|Op::GetEnv|Op::NowMs|
+            Op::Random|Op::RandomRange|Op::Sleep|Op::Syscall(_)|Op::TcpAccept|Op::TcpClose|
+            Op::TcpConnect|Op::TcpListen|Op::TcpRead|Op::TcpWrite)
+    }
+
+    // How many ops the compiler emitted that this op stands for.
+    fn unfused_count(&self) -> usize {
+        match self {
+            Op::AddConst(_)|Op::SubtractConst(_)|Op::MultiplyConst(_)|Op::DivideConst(_)|Op::MinConst(_)|Op::MaxConst(_)
+                |Op::Square|Op::PrintConst(_) => 2,
+            _ => 1,
         }
     }
 }
@@ -137,87 +579,1490 @@ impl Value {
 impl fmt::Display for Op {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            Op::Abs => write!(f, "abs"),
             Op::Add => write!(f, "add"),
             Op::Subtract => write!(f, "sub"),
             Op::Divide => write!(f, "div"),
+            Op::DivMod => write!(f, "divmod"),
             Op::Multiply => write!(f, "mul"),
-            Op::Push(value) => write!(f, "push {}", value),
+            Op::Min => write!(f, "min"),
+            Op::Max => write!(f, "max"),
+            Op::Pow => write!(f, "pow"),
+            Op::BigToInt => write!(f, "big_to_int"),
+            Op::CheckedAdd => write!(f, "checked_add"),
+            Op::CheckedSubtract => write!(f, "checked_sub"),
+            Op::CheckedMultiply => write!(f, "checked_mul"),
+            Op::SaturatingAdd => write!(f, "saturating_add"),
+            Op::SaturatingSubtract => write!(f, "saturating_sub"),
+            Op::SaturatingMultiply => write!(f, "saturating_mul"),
+            Op::ShiftLeft => write!(f, "shl"),
+            Op::ShiftRight => write!(f, "shr"),
+            Op::AddConst(value) => write!(f, "add_const {}", value),
+            Op::SubtractConst(value) => write!(f, "sub_const {}", value),
+            Op::MultiplyConst(value) => write!(f, "mul_const {}", value),
+            Op::DivideConst(value) => write!(f, "div_const {}", value),
+            Op::MinConst(value) => write!(f, "min_const {}", value),
+            Op::MaxConst(value) => write!(f, "max_const {}", value),
+            Op::Square => write!(f, "square"),
+            Op::PushConst(index) => write!(f, "push_const #{}", index),
             Op::Dup => write!(f, "dup"),
             Op::Drop => write!(f, "drop"),
             Op::Swap => write!(f, "swap"),
             Op::Over => write!(f, "over"),
             Op::Rot => write!(f, "rot"),
+            Op::Load(address) => write!(f, "load {}", address),
+            Op::Store(address) => write!(f, "store {}", address),
+            Op::LoadIndexed(array) => write!(f, "load_indexed {} {}", array.address, array.length),
+            Op::StoreIndexed(array) => write!(f, "store_indexed {} {}", array.address, array.length),
+            Op::LoadPtr(data_type) => write!(f, "load_ptr {}", data_type),
+            Op::StorePtr(data_type) => write!(f, "store_ptr {}", data_type),
+            Op::Cast(data_type) => write!(f, "cast {}", data_type),
+            Op::Alloc => write!(f, "alloc"),
+            Op::Free => write!(f, "free"),
+            Op::Realloc => write!(f, "realloc"),
+            Op::Length => write!(f, "length"),
+            Op::Concat => write!(f, "concat"),
+            Op::Substring => write!(f, "substring"),
+            Op::CharAt => write!(f, "char_at"),
+            Op::Compare => write!(f, "compare"),
+            Op::Equal => write!(f, "eq"),
+            Op::Format(count) => write!(f, "format {}", count),
+            Op::IntToStr => write!(f, "int_to_str"),
+            Op::StrToInt => write!(f, "str_to_int"),
+            Op::Random => write!(f, "random"),
+            Op::RandomRange => write!(f, "random_range"),
+            Op::NowMs => write!(f, "now_ms"),
+            Op::ClockNs => write!(f, "clock_ns"),
+            Op::Sleep => write!(f, "sleep"),
+            Op::GetEnv => write!(f, "getenv"),
+            Op::Exec => write!(f, "exec"),
+            Op::TcpConnect => write!(f, "tcp_connect"),
+            Op::TcpListen => write!(f, "tcp_listen"),
+            Op::TcpAccept => write!(f, "tcp_accept"),
+            Op::TcpRead => write!(f, "tcp_read"),
+            Op::TcpWrite => write!(f, "tcp_write"),
+            Op::TcpClose => write!(f, "tcp_close"),
+            Op::CallExtern(index) => write!(f, "call_extern {}", index),
+            Op::CallBuiltin(index) => write!(f, "call_builtin {}", index),
+            Op::Syscall(count) => write!(f, "syscall {}", count),
             Op::PrintLn => write!(f, "println"),
+            Op::PrintConst(value) => write!(f, "print_const {}", value),
+            Op::EPrintLn => write!(f, "eprintln"),
+            Op::Flush => write!(f, "flush"),
         }
     }
 }
 
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VM {
     pub fn new() -> Self {
         VM {
             op_list: Vec::<Op>::new(),
+            op_spans: Vec::new(),
+            constants: Vec::new(),
+            constant_indices: HashMap::new(),
+            data_segment: Vec::<Value>::new(),
+            strings: vec![String::new()],
+            string_indices: HashMap::from([(String::new(), 0)]),
+            bigs: vec![BigInt::from_i64(0)],
+            big_indices: HashMap::from([(BigInt::from_i64(0), ZERO_BIG)]),
+            heap_size: DEFAULT_HEAP_SIZE,
+            seed: None,
+            externs: Vec::new(),
+            builtins: Vec::new(),
+            timeout: None,
+            permissions: Permissions::default(),
+            output: RefCell::new(Box::new(io::stdout())),
+            error_output: RefCell::new(Box::new(io::stderr())),
+            buffered: true,
+            max_stack_depth: None,
+            coverage: None,
+            stats: RefCell::new(Stats::default()),
+            resume: None,
+            snapshots: false,
+            paused: RefCell::new(None),
+            checked: true,
         }
     }
 
-    #[allow(dead_code)]
-    pub fn print_ops(&self) {
-        for op in self.op_list.iter() {
-            println!("{}", op);
+    // Replaces stdout as where the program prints to. To read captured output afterwards,
+    // pass a handle that shares its buffer with the host.
+    pub fn with_output(mut self, output: impl Write + 'static) -> Self {
+        self.output = RefCell::new(Box::new(output));
+        self
+    }
+
+    // Replaces stderr as where compiler and runtime errors for the program are reported.
+    pub fn with_error_output(mut self, error_output: impl Write + 'static) -> Self {
+        self.error_output = RefCell::new(Box::new(error_output));
+        self
+    }
+
+    pub fn error_output(&self) -> RefMut<'_, Box<dyn Write>> {
+        self.error_output.borrow_mut()
+    }
+
+    pub fn set_heap_size(&mut self, heap_size: usize) {
+        self.heap_size = heap_size;
+    }
+
+    pub fn set_seed(&mut self, seed: Option<u64>) {
+        self.seed = seed;
+    }
+
+    pub fn set_permissions(&mut self, permissions: Permissions) {
+        self.permissions = permissions;
+    }
+
+    // Buffered output is flushed before ops that wait and when the program stops, turning
+    // it off writes every line as it is printed.
+    pub fn set_buffered(&mut self, buffered: bool) {
+        self.buffered = buffered;
+    }
+
+    pub fn set_max_stack_depth(&mut self, max_stack_depth: usize) {
+        self.max_stack_depth = Some(max_stack_depth);
+    }
+
+    // Only checked between ops, so a blocking op like sleep or tcp-accept runs to completion.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    // Checked mode makes each op check that the stack holds the values it takes, with the
+    // types it takes, stopping with a runtime error rather than a panic when it does not.
+    // It is on for ops pushed by the host, and the compiler turns it off for the programs
+    // it has type checked.
+    pub fn set_checked(&mut self, checked: bool) {
+        self.checked = checked;
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.op_list
+    }
+
+    pub fn op_spans(&self) -> &[Option<Span>] {
+        &self.op_spans
+    }
+
+    // Starts counting how many times each op runs, call once all the ops are pushed.
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(RefCell::new(vec![0; self.op_list.len()]));
+    }
+
+    pub fn stats(&self) -> Stats {
+        *self.stats.borrow()
+    }
+
+    // Keeps the state of a run that --max-ops or --timeout stops, see take_snapshot.
+    pub fn enable_snapshots(&mut self) {
+        self.snapshots = true;
+    }
+
+    // The state the last run stopped in, if snapshots are on and a limit stopped it.
+    pub fn take_snapshot(&self) -> Option<Snapshot> {
+        self.paused.borrow_mut().take()
+    }
+
+    // Makes runs carry on from where the run that took the snapshot stopped, call once
+    // all the ops are pushed.
+    pub fn resume_from(&mut self, snapshot: Snapshot) -> Result<(), String> {
+        if snapshot.program != self.fingerprint() {
+            return Err("the snapshot was taken of a different program, or of the same program compiled with other options".to_string());
+        }
+        let invalid_index = snapshot.stack.iter().chain(&snapshot.memory).any(|value| match value {
+            Value::Str(index) => *index >= snapshot.strings.len(),
+            Value::Big(index) => *index >= snapshot.bigs.len(),
+            _ => false,
+        });
+        let invalid_big = snapshot.bigs.len() < self.bigs.len() || snapshot.bigs.iter().any(|digits| BigInt::parse(digits).is_none());
+        if snapshot.op_index >= self.op_list.len() || snapshot.memory.len() < self.data_segment.len() || invalid_index || invalid_big {
+            return Err("the snapshot does not fit the program".to_string());
         }
+        self.resume = Some(snapshot);
+        // the stack is read from a file, so nothing has checked what is on it
+        self.checked = true;
+        Ok(())
     }
 
-    pub fn push_op(&mut self, op: Op) {
+    fn fingerprint(&self) -> u64 {
+        let ops = self.op_list.iter().map(|op| op.to_string());
+        let constants = self.constants.iter().map(|value| match value {
+            Value::Str(_) => format!("{:?}", self.string_constant(*value)),
+            Value::Big(index) => format!("Big({})", self.bigs[*index]),
+            _ => format!("{:?}", value),
+        });
+        let parts: Vec<String> = ops.chain(constants).chain([self.data_segment.len().to_string()]).collect();
+        snapshot::fingerprint(parts.iter().map(|part| part.as_bytes()))
+    }
+
+    // How many times each op has run, None if coverage is off.
+    pub fn op_counts(&self) -> Option<Vec<u64>> {
+        self.coverage.as_ref().map(|counts| counts.borrow().clone())
+    }
+
+    pub fn push_op(&mut self, op: Op, span: Option<Span>) {
         self.op_list.push(op);
+        self.op_spans.push(span);
+    }
+
+    // Runs a program made only of pure ops and replaces it with ops printing what it
+    // printed, returning whether it did. A program that fails or runs for more than
+    // max_ops is left as it is, so it fails or runs the same way when it is run.
+    pub fn fold_program(&mut self, max_ops: usize) -> bool {
+        if !self.op_list.iter().all(Op::is_pure) {
+            return false;
+        }
+
+        let printed = SharedBuffer::default();
+        let output = std::mem::replace(self.output.get_mut(), Box::new(printed.clone()));
+        let result = self.execute(Some(max_ops), &mut Trace::off());
+        *self.output.get_mut() = output;
+        *self.stats.get_mut() = Stats::default();
+
+        let printed = match (result, String::from_utf8(printed.0.take())) {
+            (Ok(_), Ok(printed)) => printed,
+            _ => return false,
+        };
+        self.op_list.clear();
+        self.op_spans.clear();
+        for line in printed.split_terminator('\n') {
+            let index = self.add_string(line.to_string());
+            self.push_op(Op::PrintConst(Value::from_string_index(index)), None);
+        }
+        true
+    }
+
+    // Merges common pairs of ops into single ops, see optimizer::fuse. Only run once the
+    // whole program is compiled, the compiler looks at the last op while compiling.
+    pub fn fuse_ops(&mut self) {
+        let ops = std::mem::take(&mut self.op_list);
+        let spans = std::mem::take(&mut self.op_spans);
+        (self.op_list, self.op_spans) = optimizer::fuse(ops, spans, &self.constants);
+    }
+
+    pub fn op_count(&self) -> usize {
+        self.op_list.len()
+    }
+
+    pub fn last_op(&self) -> Option<&Op> {
+        self.op_list.last()
+    }
+
+    pub fn string_constant(&self, value: Value) -> &str {
+        Self::string(&self.strings, value)
+    }
+
+    pub fn big_constant(&self, value: Value) -> &BigInt {
+        match value {
+            Value::Big(index) => &self.bigs[index],
+            _ => panic!("expected a big, found {}", value.data_type()),
+        }
+    }
+
+    pub fn allocate_global(&mut self, data_type: DataType) -> usize {
+        self.data_segment.push(Value::default_of(data_type));
+        self.data_segment.len() - 1
+    }
+
+    pub fn allocate_array(&mut self, data_type: DataType, length: usize) -> ArrayRef {
+        let address = self.data_segment.len();
+        self.data_segment.resize(address + length, Value::default_of(data_type));
+        ArrayRef { address, length }
+    }
+
+    // One integer cell per byte, set before the program runs.
+    pub fn allocate_data(&mut self, bytes: &[u8]) -> ArrayRef {
+        let address = self.data_segment.len();
+        self.data_segment.extend(bytes.iter().map(|byte| Value::from_int(*byte as i64)));
+        ArrayRef { address, length: bytes.len() }
+    }
+
+    // Returns the index of an identical string if one was added before.
+    pub fn add_string(&mut self, string: String) -> usize {
+        if let Some(index) = self.string_indices.get(&string) {
+            return *index;
+        }
+        self.strings.push(string.clone());
+        self.string_indices.insert(string, self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+
+    // Returns the index of an identical big if one was added before.
+    pub fn add_big(&mut self, big: BigInt) -> usize {
+        *self.big_indices.entry(big).or_insert_with_key(|big| {
+            self.bigs.push(big.clone());
+            self.bigs.len() - 1
+        })
+    }
+
+    // Returns the index of an identical constant if one was added before.
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        *self.constant_indices.entry(value).or_insert_with(|| {
+            self.constants.push(value);
+            self.constants.len() - 1
+        })
+    }
+
+    pub fn constant(&self, index: usize) -> Value {
+        self.constants[index]
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    pub fn add_extern(&mut self, function: ExternFunction) -> usize {
+        self.externs.push(function);
+        self.externs.len() - 1
+    }
+
+    pub fn extern_function(&self, index: usize) -> &ExternFunction {
+        &self.externs[index]
+    }
+
+    // Exposes a native function to px2 programs as the word 'name'. The compiler checks
+    // calls against params and returns, listed bottom to top like the stack. Names that
+    // are keywords can never be called, and px2 definitions cannot reuse a builtin's name.
+    pub fn register_builtin<F>(&mut self, name: &str, params: &[DataType], returns: &[DataType], function: F)
+    where
+        F: Fn(&mut BuiltinCall) -> Result<(), String> + 'static,
+    {
+        self.builtins.push(Builtin {
+            name: name.to_string(),
+            params: params.to_vec(),
+            returns: returns.to_vec(),
+            function: Box::new(function),
+        });
+    }
+
+    pub fn builtin_index(&self, name: &str) -> Option<usize> {
+        self.builtins.iter().position(|builtin| builtin.name == name)
+    }
+
+    pub fn builtin_signature(&self, index: usize) -> (&[DataType], &[DataType]) {
+        let builtin = &self.builtins[index];
+        (&builtin.params, &builtin.returns)
+    }
+
+    pub fn global_type(&self, address: usize) -> DataType {
+        self.data_segment[address].data_type()
+    }
+
+
+    pub fn run(&self) -> Result<(), RuntimeError> {
+        self.execute(None, &mut Trace::off()).map(|_| ())
+    }
+
+    // Like run, but stops with ErrorKind::StepLimit once max_ops ops have been executed.
+    pub fn run_with_limit(&self, max_ops: usize) -> Result<(), RuntimeError> {
+        self.execute(Some(max_ops), &mut Trace::off()).map(|_| ())
+    }
+
+    // Runs while recording into, or replaying from, the trace. The caller saves a
+    // recorded trace afterwards, including when the program failed.
+    pub fn run_with_trace(&self, max_ops: Option<usize>, trace: &mut Trace) -> Result<(), RuntimeError> {
+        self.execute(max_ops, trace).map(|_| ())
+    }
+
+    // Runs a comptime block's ops on their own, returning what they leave on the stack.
+    // Strings and bigs the block creates are kept as constants so the results can refer
+    // to them. The ops from start onwards are removed from the program to be evaluated.
+    pub fn evaluate_from(&mut self, start: usize, max_ops: usize) -> Result<Vec<Value>, RuntimeError> {
+        let evaluator = VM {
+            op_list: self.op_list.split_off(start),
+            op_spans: self.op_spans.split_off(start),
+            constants: self.constants.clone(),
+            constant_indices: HashMap::new(),
+            data_segment: Vec::new(),
+            strings: std::mem::take(&mut self.strings),
+            string_indices: HashMap::new(),
+            bigs: self.bigs.clone(),
+            big_indices: HashMap::new(),
+            heap_size: 0,
+            seed: None,
+            externs: Vec::new(),
+            builtins: Vec::new(),
+            timeout: None,
+            permissions: Permissions::default(),
+            // comptime blocks cannot print
+            output: RefCell::new(Box::new(io::sink())),
+            error_output: RefCell::new(Box::new(io::sink())),
+            buffered: false,
+            max_stack_depth: None,
+            coverage: None,
+            stats: RefCell::new(Stats::default()),
+            resume: None,
+            snapshots: false,
+            paused: RefCell::new(None),
+            // the compiler has checked the block by the time it runs
+            checked: false,
+        };
+
+        let result = evaluator.execute(Some(max_ops), &mut Trace::off());
+        match result {
+            Ok((stack, strings, bigs)) => {
+                self.strings = strings;
+                // only the bigs the results refer to, the block's other bigs are dropped
+                let stack = stack.into_iter().map(|value| match value {
+                    Value::Big(index) => Value::Big(self.add_big(bigs.get(index).clone())),
+                    value => value,
+                }).collect();
+                Ok(stack)
+            }
+            Err(runtime_error) => {
+                self.strings = evaluator.strings;
+                Err(runtime_error)
+            }
+        }
     }
 
-    pub fn run(&self) {
-        let mut stack = Vec::<Value>::new();
-        for op in self.op_list.iter() {
-            // unwrap calls here are ok since it is checked in the compiler
+    fn execute(&self, max_ops: Option<usize>, trace: &mut Trace) -> Result<(Vec<Value>, Vec<String>, BigTable), RuntimeError> {
+        let mut stats = Stats::default();
+        let result = self.interpret(max_ops, trace, &mut stats);
+        *self.stats.borrow_mut() = stats;
+        result.map_err(|mut runtime_error| {
+            // limits can stop the program at any op, so the op is not the reason it stopped
+            if !matches!(runtime_error.kind, ErrorKind::StepLimit|ErrorKind::Timeout|ErrorKind::Interrupted) {
+                runtime_error.span = self.op_spans.get(runtime_error.op_index).copied().flatten();
+            }
+            runtime_error
+        })
+    }
+
+    fn interpret(&self, max_ops: Option<usize>, trace: &mut Trace, stats: &mut Stats) -> Result<(Vec<Value>, Vec<String>, BigTable), RuntimeError> {
+        // sized up front from the compiler's simulation of the stack, so pushes never reallocate
+        let mut stack = Stack::with_capacity(self.max_stack_depth.unwrap_or(0));
+        let mut memory = self.data_segment.clone();
+        let mut heap = Heap::new(self.data_segment.len(), self.heap_size);
+        let mut rng = Rng::new(self.seed);
+        let mut start = 0;
+        if let Some(snapshot) = &self.resume {
+            stack.extend(snapshot.stack.iter().copied());
+            memory.clone_from(&snapshot.memory);
+            heap = Heap::restore(self.data_segment.len(), self.heap_size, &snapshot.heap_blocks, &snapshot.heap_freed);
+            rng = Rng::from_state(snapshot.rng_state);
+            start = snapshot.op_index;
+        }
+        let clock = Instant::now();
+        let deadline = self.timeout.map(|timeout| clock + timeout);
+        let mut sockets = Sockets::new();
+        // strings created at runtime are appended to a copy of the constant table
+        let mut strings = self.resume.as_ref().map_or(&self.strings, |snapshot| &snapshot.strings).clone();
+        let mut bigs = match &self.resume {
+            Some(snapshot) => {
+                let values = snapshot.bigs.iter().map(|digits| BigInt::parse(digits).expect("checked by resume_from")).collect();
+                BigTable::new(values, self.bigs.len())
+            }
+            None => BigTable::new(self.bigs.clone(), self.bigs.len()),
+        };
+        let mut output = self.output.borrow_mut();
+        let mut output: Box<dyn Write + '_> = if self.buffered {
+            Box::new(BufWriter::new(&mut *output))
+        } else {
+            Box::new(&mut *output)
+        };
+        // ops are counted in unfused ops, so a step limit stops at the same point with or without fusion
+        let mut coverage = self.coverage.as_ref().map(|counts| counts.borrow_mut());
+        for (op_index, op) in self.op_list.iter().enumerate().skip(start) {
+            let limited = max_ops.is_some_and(|max_ops| stats.ops_executed + op.unfused_count() > max_ops);
+            let timed_out = !limited && deadline.is_some_and(|deadline| Instant::now() >= deadline);
+            let interrupted = !limited && !timed_out && interrupt::requested();
+            if (limited || timed_out || interrupted) && self.snapshots {
+                // the run returns straight after, so its state is moved rather than copied
+                *self.paused.borrow_mut() = Some(Snapshot {
+                    program: self.fingerprint(),
+                    op_index,
+                    stack: std::mem::replace(&mut stack, Stack::with_capacity(0)).into_vec(),
+                    memory: std::mem::take(&mut memory),
+                    heap_blocks: heap.blocks(),
+                    heap_freed: heap.freed(),
+                    rng_state: rng.state(),
+                    strings: std::mem::take(&mut strings),
+                    bigs: bigs.values().iter().map(BigInt::to_string).collect(),
+                });
+            }
+            if limited {
+                return Err(RuntimeError {
+                    kind: ErrorKind::StepLimit,
+                    op_index,
+                    span: None,
+                    message: format!("stopped after executing {} ops", stats.ops_executed),
+                });
+            }
+            if timed_out {
+                return Err(RuntimeError {
+                    kind: ErrorKind::Timeout,
+                    op_index,
+                    span: None,
+                    message: format!("stopped after running for {:?}", clock.elapsed()),
+                });
+            }
+            if interrupted {
+                return Err(RuntimeError {
+                    kind: ErrorKind::Interrupted,
+                    op_index,
+                    span: None,
+                    message: format!("interrupted after executing {} ops", stats.ops_executed),
+                });
+            }
+            stats.ops_executed += op.unfused_count();
+            // between two ops every live value is on the stack or in memory
+            if bigs.wants_collection() {
+                bigs.collect(stack.iter().chain(memory.iter().copied()));
+            }
+
+            self.check_permission(op, op_index)?;
+            if self.checked {
+                self.check_operands(op, &stack, &memory).map_err(|message| RuntimeError::new(op_index, message).with_kind(ErrorKind::InvalidOperand))?;
+            }
+            if matches!(op, Op::Exec|Op::Sleep|Op::TcpAccept|Op::TcpRead|Op::Syscall(_)|Op::CallExtern(_)) {
+                // so everything printed so far is shown while the program waits, and comes
+                // before anything syscalls and C code write to stdout themselves
+                output.flush().map_err(|error| Self::print_error(op_index, error))?;
+            }
+
+            if let Some(counts) = coverage.as_mut() {
+                counts[op_index] += 1;
+            }
+
+            // the compiler, or checked mode, makes sure every op finds the values it pops
             match op {
                 Op::Add => {
-                    let v1 = stack.pop().unwrap();
-                    let v2 = stack.pop().unwrap();
-                    stack.push(v2 + v1);
+                    let v1 = stack.pop();
+                    let v2 = stack.top_mut();
+                    *v2 = Self::arithmetic(&mut bigs, *v2, v1, Value::checked_add, |a, b| a + b, op_index, "+")?;
                 }
                 Op::Subtract => {
-                    let v1 = stack.pop().unwrap();
-                    let v2 = stack.pop().unwrap();
-                    stack.push(v2 - v1);
+                    let v1 = stack.pop();
+                    let v2 = stack.top_mut();
+                    *v2 = Self::arithmetic(&mut bigs, *v2, v1, Value::checked_sub, |a, b| a - b, op_index, "-")?;
+                }
+                Op::AddConst(value) => {
+                    let v = stack.top_mut();
+                    *v = Self::arithmetic(&mut bigs, *v, *value, Value::checked_add, |a, b| a + b, op_index, "+")?;
+                }
+                Op::SubtractConst(value) => {
+                    let v = stack.top_mut();
+                    *v = Self::arithmetic(&mut bigs, *v, *value, Value::checked_sub, |a, b| a - b, op_index, "-")?;
+                }
+                Op::MultiplyConst(value) => {
+                    let v = stack.top_mut();
+                    *v = Self::arithmetic(&mut bigs, *v, *value, Value::checked_mul, |a, b| a * b, op_index, "*")?;
+                }
+                Op::DivideConst(value) => {
+                    // fuse leaves a constant 0 to div, but a host can push one itself
+                    if value.is_zero() {
+                        return Err(RuntimeError::new(op_index, "division by zero".to_string()).with_kind(ErrorKind::DivisionByZero));
+                    }
+                    let v = stack.top_mut();
+                    *v = Self::arithmetic(&mut bigs, *v, *value, Value::checked_div, Self::divide_big, op_index, "/")?;
+                }
+                Op::MinConst(value) => {
+                    let v = stack.top_mut();
+                    *v = Self::arithmetic(&mut bigs, *v, Value::from_int(*value), |a, b| Some(a.min(b)), |a, b| a.clone().min(b.clone()), op_index, "min")?;
+                }
+                Op::MaxConst(value) => {
+                    let v = stack.top_mut();
+                    *v = Self::arithmetic(&mut bigs, *v, Value::from_int(*value), |a, b| Some(a.max(b)), |a, b| a.clone().max(b.clone()), op_index, "max")?;
+                }
+                Op::Square => {
+                    let v = stack.top_mut();
+                    *v = Self::arithmetic(&mut bigs, *v, *v, Value::checked_mul, |a, b| a * b, op_index, "*")?;
                 }
                 Op::Divide => {
-                    let v1 = stack.pop().unwrap();
-                    let v2 = stack.pop().unwrap();
-                    stack.push(v2 / v1);
+                    let v1 = stack.pop();
+                    if v1.is_zero() {
+                        return Err(RuntimeError::new(op_index, "division by zero".to_string()).with_kind(ErrorKind::DivisionByZero));
+                    }
+                    let v2 = stack.top_mut();
+                    *v2 = Self::arithmetic(&mut bigs, *v2, v1, Value::checked_div, Self::divide_big, op_index, "/")?;
+                }
+                Op::CheckedAdd|Op::CheckedSubtract|Op::CheckedMultiply => {
+                    let v1 = stack.pop();
+                    let v2 = stack.pop();
+                    let (result, overflowed) = v2.overflowing(v1, op);
+                    stack.push(result);
+                    stack.push(Value::from_bool(overflowed));
+                }
+                Op::SaturatingAdd|Op::SaturatingSubtract|Op::SaturatingMultiply => {
+                    let v1 = stack.pop();
+                    let v2 = stack.top_mut();
+                    *v2 = v2.saturating(v1, op);
+                }
+                Op::DivMod => {
+                    let v1 = stack.pop();
+                    if v1.is_zero() {
+                        return Err(RuntimeError::new(op_index, "division by zero".to_string()).with_kind(ErrorKind::DivisionByZero));
+                    }
+                    let v2 = stack.pop();
+                    // division truncates, so the remainder has the sign of the dividend
+                    let quotient = Self::arithmetic(&mut bigs, v2, v1, Value::checked_div, Self::divide_big, op_index, "/")?;
+                    let product = Self::arithmetic(&mut bigs, quotient, v1, Value::checked_mul, |a, b| a * b, op_index, "*")?;
+                    let remainder = Self::arithmetic(&mut bigs, v2, product, Value::checked_sub, |a, b| a - b, op_index, "-")?;
+                    stack.push(quotient);
+                    stack.push(remainder);
+                }
+                Op::Abs => {
+                    match stack.pop() {
+                        v @ Value::Big(_) => {
+                            let result = Self::big(&bigs, v).abs();
+                            stack.push(Self::push_big(&mut bigs, result));
+                        }
+                        v => match v.as_int().checked_abs() {
+                            Some(result) => stack.push(Value::from_int(result)),
+                            None => return Err(RuntimeError::new(op_index, format!("abs of {} overflows", v)).with_kind(ErrorKind::Overflow)),
+                        },
+                    }
+                }
+                Op::Min => {
+                    let v1 = stack.pop();
+                    let v2 = stack.top_mut();
+                    *v2 = Self::arithmetic(&mut bigs, *v2, v1, |a, b| Some(a.min(b)), |a, b| a.clone().min(b.clone()), op_index, "min")?;
+                }
+                Op::Max => {
+                    let v1 = stack.pop();
+                    let v2 = stack.top_mut();
+                    *v2 = Self::arithmetic(&mut bigs, *v2, v1, |a, b| Some(a.max(b)), |a, b| a.clone().max(b.clone()), op_index, "max")?;
+                }
+                Op::ShiftLeft|Op::ShiftRight => {
+                    let amount = stack.pop().as_int();
+                    if !(0..64).contains(&amount) {
+                        return Err(RuntimeError::new(op_index, format!("cannot shift by {} bits, the amount must be from 0 to 63", amount)).with_kind(ErrorKind::InvalidArgument));
+                    }
+                    let v = stack.top_mut();
+                    *v = v.shift(amount as u32, matches!(op, Op::ShiftLeft));
+                }
+                Op::Pow => {
+                    let exponent = stack.pop().as_int();
+                    match stack.pop() {
+                        v @ Value::Big(_) => {
+                            // a big result never overflows, only a negative power fails
+                            let base = Self::big(&bigs, v);
+                            let exponent = u32::try_from(exponent).map_err(|_| RuntimeError::new(op_index, format!("cannot raise {} to the power {}", base, exponent)).with_kind(ErrorKind::InvalidArgument))?;
+                            stack.push(Self::push_big(&mut bigs, base.pow(exponent)));
+                        }
+                        v => {
+                            let base = v.as_int();
+                            // overflow is an error rather than silently wrapping
+                            let result = u32::try_from(exponent).ok().and_then(|exponent| base.checked_pow(exponent));
+                            match result {
+                                Some(result) => stack.push(Value::from_int(result)),
+                                None if exponent < 0 => return Err(RuntimeError::new(op_index, format!("cannot raise {} to the negative power {}", base, exponent)).with_kind(ErrorKind::InvalidArgument)),
+                                None => return Err(RuntimeError::new(op_index, format!("{} to the power of {} overflows", base, exponent)).with_kind(ErrorKind::Overflow)),
+                            }
+                        }
+                    }
                 }
                 Op::Multiply => {
-                    let v1 = stack.pop().unwrap();
-                    let v2 = stack.pop().unwrap();
-                    stack.push(v2 * v1);
+                    let v1 = stack.pop();
+                    let v2 = stack.top_mut();
+                    *v2 = Self::arithmetic(&mut bigs, *v2, v1, Value::checked_mul, |a, b| a * b, op_index, "*")?;
                 }
-                Op::Push(value) => stack.push(*value),
-                Op::Dup => stack.push(*stack.last().unwrap()),
+                Op::PushConst(index) => stack.push(self.constants[*index]),
+                Op::Dup => stack.push(stack.top()),
                 Op::Drop => { stack.pop(); },
-                Op::Over => {
-                    // a b => a b a
-                    let v = stack[stack.len() - 2];
-                    stack.push(v);
+                Op::Over => stack.over(),
+                Op::Rot => stack.rot(),
+                Op::Swap => stack.swap(),
+                Op::Load(address) => stack.push(memory[*address]),
+                Op::Store(address) => memory[*address] = stack.pop(),
+                Op::LoadIndexed(array) => {
+                    let index = stack.pop();
+                    let address = Self::element_address(array, index, op_index)?;
+                    stack.push(memory[address]);
+                }
+                Op::StoreIndexed(array) => {
+                    let index = stack.pop();
+                    let value = stack.pop();
+                    let address = Self::element_address(array, index, op_index)?;
+                    memory[address] = value;
+                }
+                Op::LoadPtr(data_type) => {
+                    let ptr = stack.pop();
+                    let address = Self::checked_address(&memory, &heap, ptr, op_index)?;
+                    Self::check_cell_type(&memory, address, *data_type, op_index)?;
+                    stack.push(memory[address]);
+                }
+                Op::StorePtr(data_type) => {
+                    let ptr = stack.pop();
+                    let value = stack.pop();
+                    let address = Self::checked_address(&memory, &heap, ptr, op_index)?;
+                    // heap cells take the type of whatever is stored in them, globals keep their declared type
+                    if !heap.contains(address) {
+                        Self::check_cell_type(&memory, address, *data_type, op_index)?;
+                    }
+                    memory[address] = value;
+                }
+                Op::Alloc => {
+                    let count = stack.pop().as_int();
+                    let address = heap.alloc(&mut memory, count).map_err(|message| RuntimeError::new(op_index, message).with_kind(ErrorKind::Allocation))?;
+                    stack.push(Value::from_ptr(address));
+                    stats.allocations += 1;
+                    stats.peak_heap_cells = stats.peak_heap_cells.max(heap.cells_in_use());
+                }
+                Op::Free => {
+                    let address = stack.pop().as_int();
+                    heap.free(address).map_err(|message| RuntimeError::new(op_index, message).with_kind(ErrorKind::Allocation))?;
+                }
+                Op::Realloc => {
+                    let count = stack.pop().as_int();
+                    let address = stack.pop().as_int();
+                    let address = heap.realloc(&mut memory, address, count).map_err(|message| RuntimeError::new(op_index, message).with_kind(ErrorKind::Allocation))?;
+                    stack.push(Value::from_ptr(address));
+                    stats.allocations += 1;
+                    stats.peak_heap_cells = stats.peak_heap_cells.max(heap.cells_in_use());
+                }
+                Op::Cast(DataType::Big) => {
+                    let v = stack.pop();
+                    stack.push(Self::push_big(&mut bigs, BigInt::from_i64(v.as_int())));
+                }
+                Op::Cast(data_type) => {
+                    let v = stack.pop();
+                    stack.push(v.cast(*data_type));
+                }
+                Op::BigToInt => {
+                    // big => int bool, like str->int a big that does not fit pushes 0 and false
+                    let v = stack.pop();
+                    let value = Self::big(&bigs, v).to_i64();
+                    stack.push(Value::from_int(value.unwrap_or(0)));
+                    stack.push(Value::from_bool(value.is_some()));
+                }
+                Op::Length => {
+                    let v = stack.pop();
+                    let length = Self::string(&strings, v).chars().count();
+                    stack.push(Value::from_int(length as i64));
+                }
+                Op::Concat => {
+                    let v1 = stack.pop();
+                    let v2 = stack.pop();
+                    let concatenated = format!("{}{}", Self::string(&strings, v2), Self::string(&strings, v1));
+                    strings.push(concatenated);
+                    stack.push(Value::from_string_index(strings.len() - 1));
+                }
+                Op::Substring => {
+                    // str start length => str, indices are in characters rather than bytes
+                    let length = stack.pop().as_int();
+                    let start = stack.pop().as_int();
+                    let string = Self::string(&strings, stack.pop());
+                    let char_count = string.chars().count() as i64;
+                    // start is checked first, so char_count - start cannot overflow
+                    if start < 0 || length < 0 || start > char_count || length > char_count - start {
+                        return Err(RuntimeError::new(op_index, format!("substring of {} characters at {} is out of bounds for string of length {}", length, start, char_count)).with_kind(ErrorKind::OutOfBounds));
+                    }
+
+                    let substring = string.chars().skip(start as usize).take(length as usize).collect();
+                    strings.push(substring);
+                    stack.push(Value::from_string_index(strings.len() - 1));
                 }
-                Op::Rot => {
-                    // a b c => b c a
-                    let v = stack.remove(stack.len() - 3);
-                    stack.push(v);
+                Op::CharAt => {
+                    let index = stack.pop().as_int();
+                    let string = Self::string(&strings, stack.pop());
+                    let c = usize::try_from(index).ok().and_then(|index| string.chars().nth(index));
+                    match c {
+                        Some(c) => {
+                            strings.push(c.to_string());
+                            stack.push(Value::from_string_index(strings.len() - 1));
+                        }
+                        None => {
+                            return Err(RuntimeError::new(op_index, format!("index {} is out of bounds for string of length {}", index, string.chars().count())).with_kind(ErrorKind::OutOfBounds));
+                        }
+                    }
                 }
-                Op::Swap => {
-                    // a b => b a
-                    let v = stack.remove(stack.len() - 2);
-                    stack.push(v);
+                Op::Compare => {
+                    // pushes -1, 0 or 1 as the second string is less than, equal to or greater than the top
+                    let v1 = stack.pop();
+                    let v2 = stack.pop();
+                    let ordering = Self::string(&strings, v2).cmp(Self::string(&strings, v1));
+                    stack.push(Value::from_int(ordering as i64));
+                }
+                Op::Equal => {
+                    // strings and bigs are equal by their value, not by where they are in their table
+                    let v1 = stack.pop();
+                    let v2 = stack.pop();
+                    let equal = match (v2, v1) {
+                        (Value::Str(a), Value::Str(b)) => strings[a] == strings[b],
+                        (Value::Big(a), Value::Big(b)) => bigs.get(a) == bigs.get(b),
+                        _ => v2 == v1,
+                    };
+                    stack.push(Value::from_bool(equal));
+                }
+                Op::Format(count) => {
+                    let format = stack.pop();
+                    let values = stack.pop_n(*count);
+                    let values: Vec<String> = values.into_iter().map(|v| v.shown(&strings, bigs.values()).to_string()).collect();
+                    let formatted = apply_format(Self::string(&strings, format), &values);
+                    strings.push(formatted);
+                    stack.push(Value::from_string_index(strings.len() - 1));
+                }
+                Op::IntToStr => {
+                    let v = stack.pop();
+                    strings.push(v.to_string());
+                    stack.push(Value::from_string_index(strings.len() - 1));
+                }
+                Op::StrToInt => {
+                    // str => int bool, a failed parse pushes 0 and false rather than stopping the program
+                    let v = stack.pop();
+                    match Self::string(&strings, v).trim().parse::<i64>() {
+                        Ok(value) => {
+                            stack.push(Value::from_int(value));
+                            stack.push(Value::from_bool(true));
+                        }
+                        Err(_) => {
+                            stack.push(Value::from_int(0));
+                            stack.push(Value::from_bool(false));
+                        }
+                    }
+                }
+                Op::GetEnv => {
+                    // str => str bool, a missing variable pushes "" and false
+                    let v = stack.pop();
+                    let value = trace.str(|| env::var(Self::string(&strings, v)).ok()).map_err(|message| RuntimeError::new(op_index, message))?;
+                    match value {
+                        Some(value) => {
+                            strings.push(value);
+                            stack.push(Value::from_string_index(strings.len() - 1));
+                            stack.push(Value::from_bool(true));
+                        }
+                        None => {
+                            stack.push(Value::from_string_index(0));
+                            stack.push(Value::from_bool(false));
+                        }
+                    }
+                }
+                Op::Exec => {
+                    // str => str int, runs the command through the shell and captures stdout
+                    let v = stack.pop();
+                    let command = Self::string(&strings, v);
+                    let mut code = 0;
+                    let printed = trace.try_str(|| {
+                        let output = if cfg!(windows) {
+                            Command::new("cmd").args(["/C", command]).output()
+                        } else {
+                            Command::new("sh").args(["-c", command]).output()
+                        };
+                        let output = output.map_err(|error| format!("failed to run '{}': {}", command, error))?;
+                        // killed by a signal => -1
+                        code = output.status.code().unwrap_or(-1) as i64;
+                        Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+                    }).map_err(|message| RuntimeError::new(op_index, message).with_kind(ErrorKind::Io))?;
+                    let code = trace.int(|| code).map_err(|message| RuntimeError::new(op_index, message).with_kind(ErrorKind::Io))?;
+
+                    strings.push(printed.unwrap_or_default());
+                    stack.push(Value::from_string_index(strings.len() - 1));
+                    stack.push(Value::from_int(code));
+                }
+                Op::TcpConnect|Op::TcpListen => {
+                    // address => handle
+                    let v = stack.pop();
+                    let address = Self::string(&strings, v);
+                    let handle = trace.try_int(|| if matches!(op, Op::TcpConnect) { sockets.connect(address) } else { sockets.listen(address) });
+                    let handle = handle.map_err(|message| RuntimeError::new(op_index, message).with_kind(ErrorKind::Io))?;
+                    stack.push(Value::from_int(handle));
+                }
+                Op::TcpAccept => {
+                    let listener = stack.pop().as_int();
+                    let handle = trace.try_int(|| sockets.accept(listener)).map_err(|message| RuntimeError::new(op_index, message).with_kind(ErrorKind::Io))?;
+                    stack.push(Value::from_int(handle));
+                }
+                Op::TcpRead => {
+                    let handle = stack.pop().as_int();
+                    let data = trace.try_str(|| sockets.read(handle).map(Some)).map_err(|message| RuntimeError::new(op_index, message).with_kind(ErrorKind::Io))?;
+                    strings.push(data.unwrap_or_default());
+                    stack.push(Value::from_string_index(strings.len() - 1));
+                }
+                // a replay has no sockets, what was written went to the recorded run's peers
+                Op::TcpWrite => {
+                    let handle = stack.pop().as_int();
+                    let v = stack.pop();
+                    if !trace.replaying() {
+                        sockets.write(handle, Self::string(&strings, v)).map_err(|message| RuntimeError::new(op_index, message).with_kind(ErrorKind::Io))?;
+                    }
+                }
+                Op::TcpClose => {
+                    let handle = stack.pop().as_int();
+                    if !trace.replaying() {
+                        sockets.close(handle).map_err(|message| RuntimeError::new(op_index, message).with_kind(ErrorKind::Io))?;
+                    }
+                }
+                Op::CallBuiltin(index) => {
+                    let builtin = &self.builtins[*index];
+                    let args = stack.pop_n(builtin.params.len());
+                    let mut call = BuiltinCall {
+                        args,
+                        results: Vec::new(),
+                        strings: &mut strings,
+                    };
+                    (builtin.function)(&mut call).map_err(|message| RuntimeError::new(op_index, message))?;
+
+                    // the compiler trusted the declared results, so they have to match
+                    let results = call.results;
+                    if !results.iter().map(|value| value.data_type()).eq(builtin.returns.iter().copied()) {
+                        let found = type_list(results.iter().map(|value| value.data_type()));
+                        let expected = type_list(builtin.returns.iter().copied());
+                        return Err(RuntimeError::new(op_index, format!("builtin '{}' returned [{}], expected [{}]", builtin.name, found, expected)));
+                    }
+                    stack.extend(results);
+                }
+                Op::Syscall(count) => {
+                    let number = stack.pop().as_int();
+                    let values = stack.pop_n(*count);
+                    let (args, _c_strings) = Self::c_args(&strings, &values).map_err(|message| RuntimeError::new(op_index, message).with_kind(ErrorKind::InvalidArgument))?;
+                    stack.push(Value::from_int(Self::syscall(number, &args)));
+                }
+                Op::CallExtern(index) => {
+                    let function = &self.externs[*index];
+                    let values = stack.pop_n(function.params.len());
+                    let (args, _c_strings) = Self::c_args(&strings, &values).map_err(|message| RuntimeError::new(op_index, format!("cannot call '{}': {}", function.name, message)).with_kind(ErrorKind::InvalidArgument))?;
+
+                    let result = unsafe { ffi::call(function.address, &args) };
+                    match function.returns {
+                        Some(DataType::Int) => stack.push(Value::from_int(result)),
+                        Some(DataType::UInt) => stack.push(Value::UInt(result as u64)),
+                        Some(DataType::Bool) => stack.push(Value::from_bool(result as u8 != 0)),
+                        Some(DataType::Str) => {
+                            if result == 0 {
+                                return Err(RuntimeError::new(op_index, format!("'{}' returned a null string", function.name)));
+                            }
+                            let string = unsafe { CStr::from_ptr(result as *const c_char) };
+                            strings.push(string.to_string_lossy().into_owned());
+                            stack.push(Value::from_string_index(strings.len() - 1));
+                        }
+                        // ptr and big returns are rejected in the compiler
+                        Some(DataType::Ptr)|Some(DataType::Big)|None => {}
+                    }
+                }
+                Op::Random => {
+                    // keep it non-negative so it can be used with a modulo directly
+                    let value = trace.int(|| (rng.next() >> 1) as i64).map_err(|message| RuntimeError::new(op_index, message))?;
+                    stack.push(Value::from_int(value));
+                }
+                Op::RandomRange => {
+                    let high = stack.pop().as_int();
+                    let low = stack.pop().as_int();
+                    if high <= low {
+                        return Err(RuntimeError::new(op_index, format!("cannot pick a random number from the empty range {} to {}", low, high)).with_kind(ErrorKind::InvalidArgument));
+                    }
+                    let value = trace.int(|| rng.range(low, high)).map_err(|message| RuntimeError::new(op_index, message))?;
+                    stack.push(Value::from_int(value));
+                }
+                Op::NowMs => {
+                    // a clock before 1970 reads as 0
+                    let value = trace.int(|| {
+                        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+                    }).map_err(|message| RuntimeError::new(op_index, message))?;
+                    stack.push(Value::from_int(value));
+                }
+                Op::ClockNs => {
+                    // monotonic, counted from the start of the program
+                    let value = trace.int(|| clock.elapsed().as_nanos() as i64).map_err(|message| RuntimeError::new(op_index, message))?;
+                    stack.push(Value::from_int(value));
+                }
+                Op::Sleep => {
+                    let ms = stack.pop().as_int();
+                    if ms < 0 {
+                        return Err(RuntimeError::new(op_index, format!("cannot sleep for {} milliseconds", ms)).with_kind(ErrorKind::InvalidArgument));
+                    }
+                    thread::sleep(Duration::from_millis(ms as u64));
                 }
                 Op::PrintLn => {
-                    let v = stack.pop().unwrap();
-                    println!("{}", v);
+                    let line = stack.pop().shown(&strings, bigs.values()).to_string();
+                    writeln!(output, "{}", line)
+                        .map_err(|error| Self::print_error(op_index, error))?;
+                    stats.bytes_printed += line.len() + 1;
+                }
+                Op::PrintConst(value) => {
+                    let line = value.shown(&strings, bigs.values()).to_string();
+                    writeln!(output, "{}", line)
+                        .map_err(|error| Self::print_error(op_index, error))?;
+                    stats.bytes_printed += line.len() + 1;
                 }
+                Op::EPrintLn => {
+                    // not buffered, so messages show up as soon as they are printed, after
+                    // what was printed to stdout before them
+                    output.flush().map_err(|error| Self::print_error(op_index, error))?;
+                    let line = stack.pop().shown(&strings, bigs.values()).to_string();
+                    writeln!(self.error_output.borrow_mut(), "{}", line)
+                        .map_err(|error| Self::print_error(op_index, error))?;
+                }
+                Op::Flush => {
+                    // shows what was printed so far even when output is buffered
+                    output.flush().map_err(|error| Self::print_error(op_index, error))?;
+                }
+            }
+
+            stats.peak_stack_depth = stats.peak_stack_depth.max(stack.len());
+            debug_assert!(self.max_stack_depth.is_none_or(|max| stack.len() <= max), "stack grew past the depth the compiler computed");
+        }
+
+        output.flush().map_err(|error| Self::print_error(self.op_list.len(), error))?;
+        Ok((stack.into_vec(), strings, bigs))
+    }
+
+    fn print_error(op_index: usize, error: io::Error) -> RuntimeError {
+        let kind = if error.kind() == io::ErrorKind::BrokenPipe { ErrorKind::BrokenPipe } else { ErrorKind::Io };
+        RuntimeError {
+            kind,
+            op_index,
+            span: None,
+            message: format!("failed to print: {}", error),
+        }
+    }
+
+    fn check_permission(&self, op: &Op, op_index: usize) -> Result<(), RuntimeError> {
+        let (allowed, action, flag) = match op {
+            Op::GetEnv => (self.permissions.env, "reading environment variables", "--allow-env"),
+            Op::Exec => (self.permissions.run, "running commands", "--allow-run"),
+            Op::TcpAccept|Op::TcpClose|Op::TcpConnect|Op::TcpListen|Op::TcpRead|Op::TcpWrite => {
+                (self.permissions.net, "using the network", "--allow-net")
+            }
+            _ => return Ok(()),
+        };
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(RuntimeError {
+                kind: ErrorKind::Denied,
+                op_index,
+                span: None,
+                message: format!("{} is not permitted, run with {} to allow it", action, flag),
+            })
+        }
+    }
+
+    // The checks of checked mode, done before the op runs.
+    fn check_operands(&self, op: &Op, stack: &Stack, memory: &[Value]) -> Result<(), String> {
+        let operands = self.operands(op, memory)?;
+        if stack.len() < operands.len() {
+            return Err(format!("'{}' takes {} values from the stack, found {}", op, operands.len(), stack.len()));
+        }
+
+        // bottom to top, like the operands
+        let values: Vec<Value> = (0..operands.len()).rev().map(|depth| stack.peek(depth)).collect();
+        for (position, (value, types)) in values.iter().zip(&operands).enumerate() {
+            if !types.contains(&value.data_type()) {
+                let depth = operands.len() - 1 - position;
+                let expected = types.iter().map(|data_type| data_type.to_string()).collect::<Vec<String>>().join(" or ");
+                return Err(format!("'{}' cannot take {} at depth {}, expected {}", op, value.data_type(), depth, expected));
+            }
+        }
+
+        // uint only mixes with uint, and eq compares values of one type
+        let is_uint = |value: Value| value.data_type() == DataType::UInt;
+        let mixed = match op {
+            Op::Add|Op::Subtract|Op::Multiply|Op::Divide|Op::DivMod|Op::Min|Op::Max|Op::CheckedAdd|Op::CheckedSubtract
+                |Op::CheckedMultiply|Op::SaturatingAdd|Op::SaturatingSubtract|Op::SaturatingMultiply => is_uint(values[0]) != is_uint(values[1]),
+            Op::AddConst(value)|Op::SubtractConst(value)|Op::MultiplyConst(value)|Op::DivideConst(value) => is_uint(values[0]) != is_uint(*value),
+            Op::MinConst(_)|Op::MaxConst(_) => is_uint(values[0]),
+            Op::Equal => values[0].data_type() != values[1].data_type(),
+            _ => false,
+        };
+        if mixed {
+            return Err(format!("'{}' cannot take {} and {}", op, values[0].data_type(), values[1].data_type()));
+        }
+
+        // the value has to have the type of the element it replaces, an index outside the
+        // array is left to the op to report
+        if let Op::StoreIndexed(array) = op {
+            let element = usize::try_from(values[1].as_int()).ok().filter(|offset| *offset < array.length).map(|offset| memory[array.address + offset]);
+            if let Some(element) = element.filter(|element| element.data_type() != values[0].data_type()) {
+                return Err(format!("'{}' cannot take {} at depth 1, expected {}", op, values[0].data_type(), element.data_type()));
             }
         }
+        Ok(())
+    }
+
+    // The types each value an op takes may have, bottom to top. Ops that index into the
+    // program's tables are checked to be in range too.
+    fn operands(&self, op: &Op, memory: &[Value]) -> Result<Vec<&[DataType]>, String> {
+        let outside = || format!("'{}' is outside the program's {} memory cells", op, memory.len());
+        let cell = |address: usize| memory.get(address).map(|value| value.data_type().alone()).ok_or_else(outside);
+        // every element, not only the first, has to be in memory
+        let array_cells = |array: &ArrayRef| match array.address.checked_add(array.length) {
+            Some(end) if end <= memory.len() => Ok(()),
+            _ => Err(outside()),
+        };
+        // a value carried by the op has to be one of the program's strings or bigs
+        let carried = |value: &Value| match *value {
+            Value::Str(index) if index >= self.strings.len() => Err(format!("'{}' is past the end of the {} strings", op, self.strings.len())),
+            Value::Big(index) if index >= self.bigs.len() => Err(format!("'{}' is past the end of the {} bigs", op, self.bigs.len())),
+            _ => Ok(()),
+        };
+        let operands = match op {
+            Op::Add|Op::Subtract|Op::Multiply|Op::Divide|Op::DivMod|Op::Min|Op::Max => vec![INTEGERS, INTEGERS],
+            Op::CheckedAdd|Op::CheckedSubtract|Op::CheckedMultiply|Op::SaturatingAdd|Op::SaturatingSubtract|Op::SaturatingMultiply => {
+                vec![&[DataType::Int, DataType::UInt][..], &[DataType::Int, DataType::UInt]]
+            }
+            Op::AddConst(value)|Op::SubtractConst(value)|Op::MultiplyConst(value)|Op::DivideConst(value) => {
+                carried(value)?;
+                if !INTEGERS.contains(&value.data_type()) {
+                    return Err(format!("'{}' cannot work with a constant {}", op, value.data_type()));
+                }
+                vec![INTEGERS]
+            }
+            Op::MinConst(_)|Op::MaxConst(_)|Op::Square => vec![INTEGERS],
+            Op::Abs => vec![&[DataType::Int, DataType::Big][..]],
+            Op::Pow => vec![&[DataType::Int, DataType::Big][..], DataType::Int.alone()],
+            Op::ShiftLeft|Op::ShiftRight => vec![&[DataType::Int, DataType::UInt][..], DataType::Int.alone()],
+            Op::PushConst(index) if *index >= self.constants.len() => return Err(format!("'{}' is past the end of the {} constants", op, self.constants.len())),
+            Op::PrintConst(value) => {
+                carried(value)?;
+                Vec::new()
+            }
+            Op::PushConst(_)|Op::Random|Op::NowMs|Op::ClockNs|Op::Flush => Vec::new(),
+            Op::Load(address) => {
+                cell(*address)?;
+                Vec::new()
+            }
+            Op::Store(address) => vec![cell(*address)?],
+            Op::Dup|Op::Drop|Op::PrintLn|Op::EPrintLn => vec![ANY],
+            Op::Over|Op::Swap|Op::Equal => vec![ANY, ANY],
+            Op::Rot => vec![ANY, ANY, ANY],
+            Op::LoadIndexed(array) => {
+                array_cells(array)?;
+                vec![DataType::Int.alone()]
+            }
+            // the element's type is checked once the index is known
+            Op::StoreIndexed(array) => {
+                array_cells(array)?;
+                vec![ANY, DataType::Int.alone()]
+            }
+            Op::LoadPtr(_)|Op::Free => vec![DataType::Ptr.alone()],
+            Op::StorePtr(data_type) => vec![data_type.alone(), DataType::Ptr.alone()],
+            Op::Alloc|Op::Sleep|Op::TcpAccept|Op::TcpRead|Op::TcpClose|Op::IntToStr => vec![DataType::Int.alone()],
+            Op::Realloc => vec![DataType::Ptr.alone(), DataType::Int.alone()],
+            Op::Cast(DataType::Big) => vec![DataType::Int.alone()],
+            Op::Cast(_) => vec![&[DataType::Int, DataType::Ptr, DataType::Bool, DataType::UInt][..]],
+            Op::BigToInt => vec![DataType::Big.alone()],
+            Op::Length|Op::StrToInt|Op::GetEnv|Op::Exec|Op::TcpConnect|Op::TcpListen => vec![DataType::Str.alone()],
+            Op::Concat|Op::Compare => vec![DataType::Str.alone(), DataType::Str.alone()],
+            Op::Substring => vec![DataType::Str.alone(), DataType::Int.alone(), DataType::Int.alone()],
+            Op::CharAt|Op::TcpWrite => vec![DataType::Str.alone(), DataType::Int.alone()],
+            Op::RandomRange => vec![DataType::Int.alone(), DataType::Int.alone()],
+            Op::Format(count) => [ANY].repeat(*count).into_iter().chain([DataType::Str.alone()]).collect(),
+            Op::Syscall(count) => [ANY].repeat(*count).into_iter().chain([DataType::Int.alone()]).collect(),
+            Op::CallBuiltin(index) => match self.builtins.get(*index) {
+                Some(builtin) => builtin.params.iter().map(|data_type| data_type.alone()).collect(),
+                None => return Err(format!("'{}' names none of the {} builtins", op, self.builtins.len())),
+            },
+            Op::CallExtern(index) => match self.externs.get(*index) {
+                Some(function) => function.params.iter().map(|data_type| data_type.alone()).collect(),
+                None => return Err(format!("'{}' names none of the {} externs", op, self.externs.len())),
+            },
+        };
+        Ok(operands)
+    }
+
+    // Converts values to C arguments. The returned C strings back the str pointers,
+    // so they have to be kept alive until the call returns.
+    fn c_args(strings: &[String], values: &[Value]) -> Result<(Vec<i64>, Vec<CString>), String> {
+        let mut args = Vec::with_capacity(values.len());
+        let mut c_strings = Vec::new();
+        for value in values {
+            let arg = match *value {
+                Value::Str(_) => {
+                    let c_string = CString::new(Self::string(strings, *value)).map_err(|_| "strings passed to C cannot contain NUL".to_string())?;
+                    let pointer = c_string.as_ptr() as i64;
+                    c_strings.push(c_string);
+                    pointer
+                }
+                Value::Bool(value) => value as i64,
+                Value::Int(value)|Value::Ptr(value) => value,
+                Value::UInt(value) => value as i64,
+                Value::Big(_) => return Err("big integers cannot be passed to C".to_string()),
+            };
+            args.push(arg);
+        }
+        Ok((args, c_strings))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn syscall(number: i64, args: &[i64]) -> i64 {
+        unsafe { ffi::syscall(number, args) }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn syscall(_number: i64, _args: &[i64]) -> i64 {
+        unreachable!("syscalls are rejected by the compiler on this platform")
+    }
+
+    fn string(strings: &[String], value: Value) -> &str {
+        &strings[value.string_index()]
+    }
+
+    // Arithmetic where either value may be a big, the other one then being an int.
+    // symbol is how the error names the op if a small result overflows.
+    fn arithmetic(bigs: &mut BigTable, a: Value, b: Value, small: fn(Value, Value) -> Option<Value>, big: fn(&BigInt, &BigInt) -> BigInt, op_index: usize, symbol: &str) -> Result<Value, RuntimeError> {
+        if !matches!((a, b), (Value::Big(_), _)|(_, Value::Big(_))) {
+            return small(a, b).ok_or_else(|| RuntimeError::new(op_index, format!("{} {} {} overflows", a, symbol, b)).with_kind(ErrorKind::Overflow));
+        }
+        let result = big(&Self::big(bigs, a), &Self::big(bigs, b));
+        Ok(Self::push_big(bigs, result))
+    }
+
+    fn big(bigs: &BigTable, value: Value) -> BigInt {
+        match value {
+            Value::Big(index) => bigs.get(index).clone(),
+            _ => BigInt::from_i64(value.as_int()),
+        }
+    }
+
+    fn push_big(bigs: &mut BigTable, value: BigInt) -> Value {
+        // so Value::is_zero can tell a big 0 by its index
+        if value.is_zero() {
+            return Value::Big(ZERO_BIG);
+        }
+        Value::Big(bigs.add(value))
+    }
+
+    // Division by zero is checked before dividing.
+    fn divide_big(a: &BigInt, b: &BigInt) -> BigInt {
+        a.checked_div(b).expect("division by zero")
+    }
+
+    fn element_address(array: &ArrayRef, index: Value, op_index: usize) -> Result<usize, RuntimeError> {
+        // index is type checked in compiler, bounds are not
+        let index = index.as_int();
+        match usize::try_from(index) {
+            Ok(offset) if offset < array.length => Ok(array.address + offset),
+            _ => Err(RuntimeError::new(op_index, format!("index {} is out of bounds for array of length {}", index, array.length)).with_kind(ErrorKind::OutOfBounds)),
+        }
+    }
+
+    fn checked_address(memory: &[Value], heap: &Heap, ptr: Value, op_index: usize) -> Result<usize, RuntimeError> {
+        let address = match usize::try_from(ptr.as_int()) {
+            Ok(address) if address < memory.len() => address,
+            _ => return Err(RuntimeError::new(op_index, format!("invalid memory access at address {}", ptr.as_int())).with_kind(ErrorKind::OutOfBounds)),
+        };
+
+        // checking every heap access against the live allocations is too slow for release builds
+        if cfg!(debug_assertions) && heap.contains(address) && !heap.is_live(address) {
+            return Err(RuntimeError::new(op_index, format!("use of heap memory at address {} after it was freed", address)).with_kind(ErrorKind::InvalidAccess));
+        }
+
+        Ok(address)
+    }
+
+    fn check_cell_type(memory: &[Value], address: usize, data_type: DataType, op_index: usize) -> Result<(), RuntimeError> {
+        // memory cells keep their type, so accessing one as a different type is an error rather than a reinterpretation
+        let found = memory[address].data_type();
+        if found != data_type {
+            return Err(RuntimeError::new(op_index, format!("expected {} at address {}, found {}", data_type, address, found)).with_kind(ErrorKind::InvalidAccess));
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> BuiltinCall<'a> {
+    // The compiler checks calls against the declared params, so these only fail when a
+    // builtin reads an argument it did not declare. Use '?' to stop the program then.
+    pub fn int(&self, index: usize) -> Result<i64, String> {
+        Ok(self.arg(index, DataType::Int)?.as_int())
+    }
+
+    pub fn uint(&self, index: usize) -> Result<u64, String> {
+        Ok(self.arg(index, DataType::UInt)?.as_uint())
+    }
+
+    pub fn bool(&self, index: usize) -> Result<bool, String> {
+        Ok(self.arg(index, DataType::Bool)?.as_bool())
+    }
+
+    pub fn str(&self, index: usize) -> Result<&str, String> {
+        Ok(VM::string(self.strings, self.arg(index, DataType::Str)?))
+    }
+
+    pub fn push_int(&mut self, value: i64) {
+        self.results.push(Value::from_int(value));
+    }
+
+    pub fn push_uint(&mut self, value: u64) {
+        self.results.push(Value::UInt(value));
+    }
+
+    pub fn push_bool(&mut self, value: bool) {
+        self.results.push(Value::from_bool(value));
+    }
+
+    pub fn push_str(&mut self, value: String) {
+        self.strings.push(value);
+        self.results.push(Value::from_string_index(self.strings.len() - 1));
+    }
+
+    fn arg(&self, index: usize, data_type: DataType) -> Result<Value, String> {
+        match self.args.get(index) {
+            Some(value) if value.data_type() == data_type => Ok(*value),
+            Some(value) => Err(format!("builtin argument {} is {}, not {}", index, value.data_type(), data_type)),
+            None => Err(format!("builtin has {} arguments, there is no argument {}", self.args.len(), index)),
+        }
+    }
+}
+
+fn type_list(types: impl Iterator<Item = DataType>) -> String {
+    types.map(|data_type| data_type.to_string()).collect::<Vec<String>>().join(" ")
+}
+
+// Collects what a program prints while it is folded, see VM::fold_program.
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Format strings use '{}' as a placeholder for the next value and '{{' and '}}' for literal braces.
+pub fn count_placeholders(format: &str) -> Result<usize, String> {
+    let mut count = 0;
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('{', Some('}')) => count += 1,
+            ('{', Some('{')) | ('}', Some('}')) => (),
+            ('{', _) => return Err("unmatched '{' in format string, use '{{' for a literal brace".to_string()),
+            ('}', _) => return Err("unmatched '}' in format string, use '}}' for a literal brace".to_string()),
+            _ => continue,
+        }
+        chars.next();
+    }
+
+    Ok(count)
+}
+
+fn apply_format(format: &str, values: &[String]) -> String {
+    // the format string has already been validated by count_placeholders
+    let mut result = String::with_capacity(format.len());
+    let mut values = values.iter();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' | '}' => {
+                if chars.next() == Some('}') && c == '{' {
+                    result.push_str(values.next().unwrap());
+                } else {
+                    result.push(c);
+                }
+            }
+            _ => result.push(c),
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{ArrayRef, DataType, ErrorKind, Op, Value, VM};
+
+    // A VM for hand-built op lists, which runs in checked mode, printing nowhere.
+    fn vm() -> VM {
+        VM::new().with_output(io::sink()).with_error_output(io::sink())
+    }
+
+    // Runs the ops and returns the kind and message of the error they stopped with.
+    fn error(mut vm: VM, ops: Vec<Op>) -> (ErrorKind, String) {
+        for op in ops {
+            vm.push_op(op, None);
+        }
+        let error = vm.run().expect_err("the ops should have been rejected");
+        (error.kind, error.message)
+    }
+
+    fn rejected(vm: VM, ops: Vec<Op>) -> String {
+        let (kind, message) = error(vm, ops);
+        assert_eq!(kind, ErrorKind::InvalidOperand, "{}", message);
+        message
+    }
+
+    #[test]
+    fn rejects_missing_and_mistyped_operands() {
+        assert_eq!(rejected(vm(), vec![Op::Add]), "'add' takes 2 values from the stack, found 0");
+
+        let mut strings = vm();
+        let text = Value::from_string_index(strings.add_string("text".to_string()));
+        let ops = vec![Op::PushConst(strings.add_constant(text)), Op::PushConst(strings.add_constant(Value::from_int(1))), Op::Add];
+        assert_eq!(rejected(strings, ops), "'add' cannot take Str at depth 1, expected Int or UInt or Big or Ptr");
+
+        let mut mixed = vm();
+        let ops = vec![Op::PushConst(mixed.add_constant(Value::UInt(1))), Op::PushConst(mixed.add_constant(Value::from_int(1))), Op::Subtract];
+        assert_eq!(rejected(mixed, ops), "'sub' cannot take UInt and Int");
+
+        let mut constant = vm();
+        let ops = vec![Op::PushConst(constant.add_constant(Value::from_int(1))), Op::AddConst(Value::from_bool(true))];
+        assert_eq!(rejected(constant, ops), "'add_const true' cannot work with a constant Bool");
+    }
+
+    #[test]
+    fn rejects_storing_the_wrong_type_into_an_element() {
+        // an array made of an int and a str cell, the str element being the second one
+        let mut program = vm();
+        let array = ArrayRef { address: program.allocate_global(DataType::Int), length: 2 };
+        program.allocate_global(DataType::Str);
+        let seven = program.add_constant(Value::from_int(7));
+        let zero = program.add_constant(Value::from_int(0));
+        let one = program.add_constant(Value::from_int(1));
+        let ops = vec![Op::PushConst(seven), Op::PushConst(zero), Op::StoreIndexed(array), Op::PushConst(seven), Op::PushConst(one), Op::StoreIndexed(array)];
+        assert_eq!(rejected(program, ops), "'store_indexed 0 2' cannot take Int at depth 1, expected Str");
+
+        // an index outside the array is reported by the op itself
+        let mut program = vm();
+        let array = program.allocate_array(DataType::Int, 2);
+        let seven = program.add_constant(Value::from_int(7));
+        let two = program.add_constant(Value::from_int(2));
+        let (kind, message) = error(program, vec![Op::PushConst(seven), Op::PushConst(two), Op::StoreIndexed(array)]);
+        assert_eq!((kind, message.as_str()), (ErrorKind::OutOfBounds, "index 2 is out of bounds for array of length 2"));
+    }
+
+    #[test]
+    fn rejects_arrays_reaching_past_memory() {
+        for array in [ArrayRef { address: 1, length: 3 }, ArrayRef { address: 5, length: 0 }, ArrayRef { address: usize::MAX, length: 2 }] {
+            let mut program = vm();
+            program.allocate_array(DataType::Int, 3);
+            let zero = program.add_constant(Value::from_int(0));
+            let load = vec![Op::PushConst(zero), Op::LoadIndexed(array)];
+            assert_eq!(rejected(program, load), format!("'load_indexed {} {}' is outside the program's 3 memory cells", array.address, array.length));
+
+            let mut program = vm();
+            program.allocate_array(DataType::Int, 3);
+            let zero = program.add_constant(Value::from_int(0));
+            let store = vec![Op::PushConst(zero), Op::PushConst(zero), Op::StoreIndexed(array)];
+            assert_eq!(rejected(program, store), format!("'store_indexed {} {}' is outside the program's 3 memory cells", array.address, array.length));
+        }
+
+        // the last element of an array ending at the end of memory is fine
+        let mut program = vm();
+        let array = program.allocate_array(DataType::Int, 3);
+        let two = program.add_constant(Value::from_int(2));
+        for op in [Op::PushConst(two), Op::PushConst(two), Op::StoreIndexed(array), Op::PushConst(two), Op::LoadIndexed(array), Op::Drop] {
+            program.push_op(op, None);
+        }
+        assert!(program.run().is_ok());
+    }
+
+    #[test]
+    fn rejects_indices_past_the_program_tables() {
+        assert_eq!(rejected(vm(), vec![Op::PushConst(0)]), "'push_const #0' is past the end of the 0 constants");
+        assert_eq!(rejected(vm(), vec![Op::Load(0)]), "'load 0' is outside the program's 0 memory cells");
+        assert_eq!(rejected(vm(), vec![Op::PrintConst(Value::from_string_index(1))]), "'print_const str#1' is past the end of the 1 strings");
+        assert_eq!(rejected(vm(), vec![Op::CallBuiltin(0)]), "'call_builtin 0' names none of the 0 builtins");
+        assert_eq!(rejected(vm(), vec![Op::CallExtern(0)]), "'call_extern 0' names none of the 0 externs");
+
+        let mut program = vm();
+        let one = program.add_constant(Value::from_int(1));
+        assert_eq!(rejected(program, vec![Op::PushConst(one), Op::AddConst(Value::Big(1))]), "'add_const big#1' is past the end of the 1 bigs");
     }
 }